@@ -12,7 +12,7 @@ fn main() {
     };
 
     const PT_COUNT: usize = 10;
-    let points = (0..PT_COUNT).into_iter().map(|x| x as f64 - (PT_COUNT as f64  - 0.5)).map(|x| (x, line(x))).collect::<Vec<_>>();
+    let points = (0..PT_COUNT).map(|x| x as f64 - (PT_COUNT as f64  - 0.5)).map(|x| (x, line(x))).collect::<Vec<_>>();
     println!("{:#?}", points);
 
     let mut inputs = Matrix::new(2, points.len());
@@ -36,7 +36,7 @@ fn main() {
 
     // println!("f(3) = {}", outputs);
 
-    let bp_plan = network.plan_backprop(PT_COUNT);
+    let bp_plan = network.plan_backprop(PT_COUNT).compile();
     // println!("{:#?}", bp_plan);
 
     let mut bp_inputs = HashMap::new();
@@ -49,7 +49,7 @@ fn main() {
     for _ in 0..10000 {
         network.fill_plan_weights(&mut bp_inputs);
 
-        let (_, mut bp_outputs) = bp_plan.execute_cpu(&bp_inputs);
+        let (_, mut bp_outputs) = bp_plan.execute(&bp_inputs);
         let mut bp_vec = vec![];
         for i in 0..network.hidden_layers() {
             bp_vec.push(bp_outputs.remove(&*format!("gradient_{}", i)).unwrap());
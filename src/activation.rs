@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 
-use crate::{MatrixPlan, Scalar, Matrix};
+use crate::{MatrixPlan, Scalar, Matrix, plan::PLACEHOLDER};
 
 
+/// An activation applied after a layer's product.
+/// [`Activation::derivative`] and [`Activation::backward_plan`] are both evaluated at the activation's input (the pre-activation), not at its output.
 pub trait Activation: 'static {
     fn forward<I: Scalar>(&self, from: Matrix<I>) -> Matrix<I> {
         let input: HashMap<String, Matrix<I>> = HashMap::new();
@@ -12,7 +14,28 @@ pub trait Activation: 'static {
 
     fn forward_plan<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I>;
 
-    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I>;
+    /// Element-wise derivative at the input `from`, derived from `forward_plan` by default, activations only need to override it for a cheaper closed form.
+    /// `None` for activations mixing components (e.g. [`Softmax`]), which have no element-wise derivative, their gradient is only given by [`Activation::backward_plan`].
+    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+        let (rows, cols) = from.dims();
+        let placeholder = MatrixPlan::input(rows, cols, PLACEHOLDER);
+        let derivative = self.forward_plan(placeholder).gradients(&[PLACEHOLDER]).remove(0);
+        Some(derivative.substitute(PLACEHOLDER, &from))
+    }
+
+    /// Gradient with respect to the activation's input `from`, given the gradient `prior` with respect to its output.
+    /// Scales `prior` by the derivative, or without one differentiates `forward_plan` seeded by `prior`, activations only need to override it for a cheaper closed form.
+    fn backward_plan<I: Scalar>(&self, prior: MatrixPlan<I>, from: MatrixPlan<I>) -> MatrixPlan<I> {
+        match self.derivative(from.clone()) {
+            Some(derivative) => prior.hadamard_mul(derivative),
+            None => {
+                let (rows, cols) = from.dims();
+                let placeholder = MatrixPlan::input(rows, cols, PLACEHOLDER);
+                let gradient = self.forward_plan(placeholder).gradients_seeded(prior, &[PLACEHOLDER]).remove(0);
+                gradient.substitute(PLACEHOLDER, &from)
+            },
+        }
+    }
 }
 
 pub struct Linear;
@@ -22,8 +45,8 @@ impl Activation for Linear {
         from
     }
 
-    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
-        MatrixPlan::constant(Matrix::new(from.rows(), from.cols()).fill(I::ONE))
+    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+        Some(from.fill(I::ONE))
     }
 }
 
@@ -34,8 +57,9 @@ impl Activation for Relu {
         from.max(I::default())
     }
 
-    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
-        from.sign().max(I::default())
+    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+        let zero = from.fill(I::default());
+        Some(from.greater(zero))
     }
 }
 
@@ -46,11 +70,134 @@ impl Activation for Sigmoid {
         from.sigmoid()
     }
 
-    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
-        let one = MatrixPlan::constant(Matrix::new(from.rows(), from.cols()).fill(I::ONE));
+    fn derivative<I: Scalar>(&self, from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+        let one = from.fill(I::ONE);
 
         let sigmoid = from.sigmoid();
         let one_minus_sigmoid = one - &sigmoid;
-        sigmoid.hadamard_mul(one_minus_sigmoid)
+        Some(sigmoid.hadamard_mul(one_minus_sigmoid))
+    }
+}
+
+pub struct Tanh;
+
+impl Activation for Tanh {
+    fn forward_plan<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
+        from.tanh()
+    }
+}
+
+/// `ln(1 + e^x)`, computed as `max(x, 0) + ln(1 + e^-|x|)` so the exponential cannot overflow
+pub struct Softplus;
+
+impl Activation for Softplus {
+    fn forward_plan<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
+        let one = from.fill(I::ONE);
+
+        let tail = (-from.clone().abs()).exp() + one;
+        from.max(I::default()) + tail.ln()
+    }
+}
+
+/// Softmax over each column (one column per sample), for classification outputs
+pub struct Softmax;
+
+impl Activation for Softmax {
+    fn forward_plan<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
+        from.softmax()
+    }
+
+    /// Softmax mixes the components of each column, so it has no element-wise derivative
+    fn derivative<I: Scalar>(&self, _from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+        None
+    }
+
+    /// `y ⊙ (prior - sum(prior ⊙ y))` per column, with `y` the softmax of `from`
+    fn backward_plan<I: Scalar>(&self, prior: MatrixPlan<I>, from: MatrixPlan<I>) -> MatrixPlan<I> {
+        let softmax = from.softmax();
+        let weighted = prior.clone().hadamard_mul(&softmax).sum_rows().broadcast_rows(&softmax.dims().0);
+        softmax.hadamard_mul(prior - weighted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{Matrix, MatrixPlan, Scalar, plan::testing::{assert_close, bind, finite_difference, sample}};
+
+    use super::{Activation, Linear, Relu, Sigmoid, Softmax, Softplus, Tanh};
+
+    /// Pre-activations spread over `[-3, 3)`, away from the kink of [`Relu`]
+    fn inputs() -> HashMap<String, Matrix<f64>> {
+        bind(&[("x", &sample(3, 4, 1).scale(3.0))])
+    }
+
+    fn assert_backward_plan(activation: impl Activation) {
+        let x = MatrixPlan::input(3, 4, "x");
+        let prior = MatrixPlan::constant(sample(3, 4, 2));
+        let backward = activation.backward_plan(prior.clone(), x.clone());
+        let loss = activation.forward_plan(x).hadamard_mul(prior);
+        assert_close(&backward.execute_cpu(&inputs()).0, &finite_difference(&loss, "x", &inputs()), 1e-6);
+    }
+
+    fn assert_derivative(activation: impl Activation) {
+        let x = MatrixPlan::input(3, 4, "x");
+        let derivative = activation.derivative(x.clone()).expect("element-wise activation");
+        assert_close(&derivative.execute_cpu(&inputs()).0, &finite_difference(&activation.forward_plan(x), "x", &inputs()), 1e-6);
+        assert_backward_plan(activation);
+    }
+
+    #[test]
+    fn linear() {
+        assert_derivative(Linear);
+    }
+
+    #[test]
+    fn relu() {
+        assert_derivative(Relu);
+    }
+
+    #[test]
+    fn sigmoid() {
+        assert_derivative(Sigmoid);
+    }
+
+    #[test]
+    fn tanh() {
+        assert_derivative(Tanh);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn softplus() {
+        assert_derivative(Softplus);
+    }
+
+    #[test]
+    fn softmax() {
+        assert_backward_plan(Softmax);
+    }
+
+    #[test]
+    fn softmax_has_no_element_wise_derivative() {
+        assert!(Softmax.derivative(MatrixPlan::<f64>::input(3, 4, "x")).is_none());
+    }
+
+    /// Softmax without its closed form backward plan, differentiated through the trait's default
+    struct DerivedSoftmax;
+
+    impl Activation for DerivedSoftmax {
+        fn forward_plan<I: Scalar>(&self, from: MatrixPlan<I>) -> MatrixPlan<I> {
+            from.softmax()
+        }
+
+        fn derivative<I: Scalar>(&self, _from: MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+            None
+        }
+    }
+
+    #[test]
+    fn activations_without_a_derivative_are_differentiated_through_their_forward_plan() {
+        assert_backward_plan(DerivedSoftmax);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use crate::{Dim, Matrix, Scalar, NonFiniteTrace, plan::Bindings};
+
+/// Failure to build or execute a plan, returned by the `try_` counterparts of the panicking API
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatrixError {
+    /// The operands of `op` have incompatible shapes
+    ShapeMismatch { op: &'static str, left: (Dim, Dim), right: (Dim, Dim) },
+    /// `op` needs a fixed size along an axis that is the symbolic `dim`
+    SymbolicDim { op: &'static str, dim: Dim },
+    /// `op` was given no parts to concatenate
+    EmptyConcat { op: &'static str },
+    /// `op` was given a matrix without rows to reduce
+    EmptyReduce { op: &'static str },
+    /// The custom op `op` does not accept operands of these shapes, see [`CustomOp::output_shape`](crate::CustomOp::output_shape)
+    CustomOperands { op: String, operands: Vec<(Dim, Dim)> },
+    /// No bound input determines the size of the symbolic dimension `name`
+    UnboundDim { name: String },
+    /// No matrix was bound to a named input of the plan
+    MissingInput { name: String },
+    /// A matrix was bound to a name the plan has no input for
+    UnexpectedInput { name: String },
+    /// The matrix bound to a named input does not have the shape the input was declared with
+    InputShape { name: String, expected: (usize, usize), actual: (usize, usize) },
+    /// Execution produced a NaN, in the named output or in the result itself if `output` is `None`
+    NaN { output: Option<String> },
+    /// A node of a checked execution produced a NaN or infinite component
+    NonFinite(Box<NonFiniteTrace>),
+    /// A plan in the text format of [`MatrixPlan::to_text`](crate::MatrixPlan::to_text) could not be parsed, `line` counting from 1
+    Parse { line: usize, message: String },
+}
+
+/// The result and named outputs of a fallible execution
+pub type ExecuteResult<I> = Result<(Matrix<I>, HashMap<String, Matrix<I>>), MatrixError>;
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::ShapeMismatch { op, left, right } => write!(f, "shape mismatch in {}: {}x{} and {}x{}", op, left.0, left.1, right.0, right.1),
+            MatrixError::SymbolicDim { op, dim } => write!(f, "{} needs a fixed size, got the symbolic dimension '{}'", op, dim),
+            MatrixError::EmptyConcat { op } => write!(f, "{} needs at least one part", op),
+            MatrixError::EmptyReduce { op } => write!(f, "cannot {} an empty matrix", op),
+            MatrixError::CustomOperands { op, operands } => {
+                let shapes = operands.iter().map(|(rows, cols)| format!("{}x{}", rows, cols)).collect::<Vec<_>>();
+                write!(f, "custom op '{}' does not accept operands of shapes [{}]", op, shapes.join(", "))
+            },
+            MatrixError::UnboundDim { name } => write!(f, "no input binds the symbolic dimension '{}'", name),
+            MatrixError::MissingInput { name } => write!(f, "missing input for '{}'", name),
+            MatrixError::UnexpectedInput { name } => write!(f, "plan has no input named '{}'", name),
+            MatrixError::InputShape { name, expected, actual } => {
+                write!(f, "bad shape for input '{}': expected {}x{}, got {}x{}", name, expected.0, expected.1, actual.0, actual.1)
+            },
+            MatrixError::NaN { output: Some(output) } => write!(f, "NaN in output '{}'", output),
+            MatrixError::NaN { output: None } => write!(f, "NaN in result"),
+            MatrixError::NonFinite(trace) => write!(f, "non-finite result: {}", trace),
+            MatrixError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for MatrixError {}
+
+/// Unwraps the result of a `try_` builder for its panicking counterpart
+#[track_caller]
+pub(crate) fn or_panic<T>(result: Result<T, MatrixError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => panic!("{}", error),
+    }
+}
+
+/// Checks that every declared input is bound to a matrix of its shape, with symbolic dimensions taken from `bindings`
+pub(crate) fn check_inputs<'a, I: Scalar>(declared: impl IntoIterator<Item=(&'a str, (Dim, Dim))>, bindings: &Bindings, inputs: &HashMap<&str, &Matrix<I>>) -> Result<(), MatrixError> {
+    for (name, (rows, cols)) in declared {
+        let input = inputs.get(name).ok_or_else(|| MatrixError::MissingInput { name: name.to_string() })?;
+        let actual = (input.rows(), input.cols());
+        // a bound input binds its own symbols
+        let expected = (rows.resolve(bindings).expect("bound symbol"), cols.resolve(bindings).expect("bound symbol"));
+        if actual != expected {
+            return Err(MatrixError::InputShape { name: name.to_string(), expected, actual });
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the result or any named output of an execution contains a NaN, outputs being checked in name order
+pub(crate) fn check_nan<I: Scalar>(executed: (Matrix<I>, HashMap<String, Matrix<I>>)) -> ExecuteResult<I> {
+    let (result, outputs) = &executed;
+    let mut names = outputs.iter().filter(|(_, output)| output.has_nan()).map(|(name, _)| name).collect::<Vec<_>>();
+    names.sort_unstable();
+    if let Some(name) = names.first() {
+        return Err(MatrixError::NaN { output: Some(name.to_string()) });
+    }
+    if result.has_nan() {
+        return Err(MatrixError::NaN { output: None });
+    }
+    Ok(executed)
+}
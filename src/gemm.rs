@@ -0,0 +1,280 @@
+//! Packed, cache-blocked general matrix multiplication.
+//!
+//! Operands are described by a slice and a `(row_stride, col_stride)` pair, so transposed views can be multiplied without materializing them.
+//! `B` is packed into `KC x NR` panels and `A` into `MC x KC` blocks of `MR`-row panels, then a fixed-size register tile is computed per panel pair.
+
+use std::sync::Mutex;
+
+use half::f16;
+
+use crate::{Scalar, parallel};
+
+/// rows of `A` packed per block
+const MC: usize = 64;
+/// length of the shared dimension packed per block
+const KC: usize = 256;
+/// columns of `B` packed per block
+const NC: usize = 1024;
+
+/// A strided view of a row-major operand
+#[derive(Clone, Copy)]
+pub struct Operand<'a, T> {
+    data: &'a [T],
+    row_stride: usize,
+    col_stride: usize,
+}
+
+impl<'a, T: Copy> Operand<'a, T> {
+    pub(crate) fn new(data: &'a [T], (row_stride, col_stride): (usize, usize)) -> Self {
+        Self {
+            data,
+            row_stride,
+            col_stride,
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self, row: usize, col: usize) -> T {
+        self.data[row * self.row_stride + col * self.col_stride]
+    }
+}
+
+/// `c = a * b` for an `m x k` by `k x n` product, accumulating in `A`.
+/// `c` is row-major `m x n` and is overwritten.
+fn gemm<T: Copy + Sync, A: Scalar, const MR: usize, const NR: usize>(m: usize, k: usize, n: usize, a: Operand<'_, T>, b: Operand<'_, T>, c: &mut [A], widen: impl Fn(T) -> A + Copy + Sync + Send) {
+    assert_eq!(c.len(), m * n);
+    c.iter_mut().for_each(|x| *x = A::default());
+    if m == 0 || n == 0 || k == 0 {
+        return;
+    }
+
+    let mut packed_b = vec![A::default(); KC * NC.min(n).div_ceil(NR) * NR];
+    // packing buffers of `A`, taken by each task for the duration of a panel so there is one per concurrent task
+    let packed_a_buffers = Mutex::new(vec![]);
+
+    for jc in (0..n).step_by(NC) {
+        let nc = NC.min(n - jc);
+        for pc in (0..k).step_by(KC) {
+            let kc = KC.min(k - pc);
+            pack_b::<T, A, NR>(b, pc, jc, kc, nc, &mut packed_b, widen);
+
+            let packed_b = &packed_b;
+            // each block of MC rows of `c` is owned by exactly one task, sharing the packed panel of `B`
+            parallel::for_each_row_block(c, MC * n, m * kc * nc, |first_block, c_blocks| {
+                let mut packed_a = packed_a_buffers.lock().unwrap().pop().unwrap_or_else(|| vec![A::default(); MC.min(m).div_ceil(MR) * MR * KC]);
+                for (block, c_block) in c_blocks.chunks_mut(MC * n).enumerate() {
+                    let ic = (first_block + block) * MC;
+                    let mc = c_block.len() / n;
+                    pack_a::<T, A, MR>(a, ic, pc, mc, kc, &mut packed_a, widen);
+                    block_kernel::<A, MR, NR>(mc, kc, nc, &packed_a, packed_b, c_block, n, jc);
+                }
+                packed_a_buffers.lock().unwrap().push(packed_a);
+            });
+        }
+    }
+}
+
+/// Packs `b[pc..pc + kc][jc..jc + nc]` into zero padded `kc x NR` panels laid out row by row.
+fn pack_b<T: Copy, A: Scalar, const NR: usize>(b: Operand<'_, T>, pc: usize, jc: usize, kc: usize, nc: usize, packed: &mut [A], widen: impl Fn(T) -> A) {
+    for (panel, jr) in (0..nc).step_by(NR).enumerate() {
+        let width = NR.min(nc - jr);
+        let panel = &mut packed[panel * kc * NR..(panel + 1) * kc * NR];
+        for (p, row) in panel.chunks_exact_mut(NR).enumerate() {
+            for (j, target) in row.iter_mut().enumerate() {
+                *target = if j < width {
+                    widen(b.get(pc + p, jc + jr + j))
+                } else {
+                    A::default()
+                };
+            }
+        }
+    }
+}
+
+/// Packs `a[ic..ic + mc][pc..pc + kc]` into zero padded `kc x MR` panels laid out column by column.
+fn pack_a<T: Copy, A: Scalar, const MR: usize>(a: Operand<'_, T>, ic: usize, pc: usize, mc: usize, kc: usize, packed: &mut [A], widen: impl Fn(T) -> A) {
+    for (panel, ir) in (0..mc).step_by(MR).enumerate() {
+        let height = MR.min(mc - ir);
+        let panel = &mut packed[panel * kc * MR..(panel + 1) * kc * MR];
+        for (p, col) in panel.chunks_exact_mut(MR).enumerate() {
+            for (i, target) in col.iter_mut().enumerate() {
+                *target = if i < height {
+                    widen(a.get(ic + ir + i, pc + p))
+                } else {
+                    A::default()
+                };
+            }
+        }
+    }
+}
+
+/// Multiplies one packed block of `A` with the packed panel of `B`, adding into the rows of `c` owned by the block.
+#[allow(clippy::too_many_arguments)]
+fn block_kernel<A: Scalar, const MR: usize, const NR: usize>(mc: usize, kc: usize, nc: usize, packed_a: &[A], packed_b: &[A], c: &mut [A], ldc: usize, jc: usize) {
+    for jr in (0..nc).step_by(NR) {
+        let b_panel = &packed_b[(jr / NR) * kc * NR..(jr / NR + 1) * kc * NR];
+        let width = NR.min(nc - jr);
+        for ir in (0..mc).step_by(MR) {
+            let a_panel = &packed_a[(ir / MR) * kc * MR..(ir / MR + 1) * kc * MR];
+            let height = MR.min(mc - ir);
+            let tile = micro_kernel::<A, MR, NR>(a_panel, b_panel);
+            for (i, tile_row) in tile.iter().enumerate().take(height) {
+                let c_row = &mut c[(ir + i) * ldc + jc + jr..(ir + i) * ldc + jc + jr + width];
+                for (target, value) in c_row.iter_mut().zip(tile_row.iter()) {
+                    *target = *target + *value;
+                }
+            }
+        }
+    }
+}
+
+/// Register tile: fixed trip counts keep the accumulators in registers and let the inner loop vectorize over `NR`.
+#[inline(always)]
+fn micro_kernel<A: Scalar, const MR: usize, const NR: usize>(a_panel: &[A], b_panel: &[A]) -> [[A; NR]; MR] {
+    let mut acc = [[A::default(); NR]; MR];
+    for (a, b) in a_panel.chunks_exact(MR).zip(b_panel.chunks_exact(NR)) {
+        for (acc_row, a) in acc.iter_mut().zip(a.iter()) {
+            for (acc, b) in acc_row.iter_mut().zip(b.iter()) {
+                *acc = *acc + *a * *b;
+            }
+        }
+    }
+    acc
+}
+
+/// The product kernel of each [`Scalar`] of this crate.
+/// Unnameable outside of it, which also keeps [`Scalar`] from being implemented elsewhere.
+pub trait Gemm: Sized {
+    /// `c = a * b` for an `m x k` by `k x n` product, `c` is row-major and overwritten
+    fn gemm(m: usize, k: usize, n: usize, a: Operand<'_, Self>, b: Operand<'_, Self>, c: &mut [Self]);
+}
+
+impl Gemm for f32 {
+    fn gemm(m: usize, k: usize, n: usize, a: Operand<'_, f32>, b: Operand<'_, f32>, c: &mut [f32]) {
+        gemm::<f32, f32, 4, 16>(m, k, n, a, b, c, |x| x);
+    }
+}
+
+impl Gemm for f64 {
+    fn gemm(m: usize, k: usize, n: usize, a: Operand<'_, f64>, b: Operand<'_, f64>, c: &mut [f64]) {
+        gemm::<f64, f64, 4, 8>(m, k, n, a, b, c, |x| x);
+    }
+}
+
+/// half precision is widened while packing and accumulated in f32, then rounded once on the way out
+impl Gemm for f16 {
+    fn gemm(m: usize, k: usize, n: usize, a: Operand<'_, f16>, b: Operand<'_, f16>, c: &mut [f16]) {
+        let mut accumulator = vec![0f32; m * n];
+        gemm::<f16, f32, 4, 16>(m, k, n, a, b, &mut accumulator, f16::to_f32);
+        c.iter_mut().zip(accumulator).for_each(|(target, value)| *target = f16::from_f32(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    use super::{Gemm, Operand};
+
+    /// row-major components of a deterministic pseudo-random `rows x cols` matrix, in `[-1, 1)`
+    fn sample(rows: usize, cols: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..rows * cols).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+        }).collect()
+    }
+
+    fn naive(m: usize, k: usize, n: usize, a: Operand<'_, f64>, b: Operand<'_, f64>) -> Vec<f64> {
+        let mut c = vec![0.0; m * n];
+        for i in 0..m {
+            for j in 0..n {
+                c[i * n + j] = (0..k).map(|p| a.get(i, p) * b.get(p, j)).sum();
+            }
+        }
+        c
+    }
+
+    fn assert_close(actual: impl IntoIterator<Item = f64>, expected: &[f64], tolerance: f64) {
+        let actual = actual.into_iter().collect::<Vec<_>>();
+        assert_eq!(actual.len(), expected.len());
+        for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+            assert!((actual - expected).abs() <= tolerance * (1.0 + expected.abs()), "component {}: {} != {}", index, actual, expected);
+        }
+    }
+
+    /// shapes around and across the register tile (4 x 8 or 16) and the `MC`, `KC` and `NC` blocks
+    const SHAPES: [(usize, usize, usize); 9] = [
+        (0, 3, 2),
+        (3, 0, 2),
+        (1, 1, 1),
+        (3, 5, 7),
+        (5, 17, 9),
+        (17, 3, 33),
+        (65, 257, 31),
+        (130, 300, 19),
+        (7, 9, 1030),
+    ];
+
+    #[test]
+    fn matches_naive_product() {
+        for (m, k, n) in SHAPES {
+            let (a, b) = (sample(m, k, 1), sample(k, n, 2));
+            let expected = naive(m, k, n, Operand::new(&a, (k, 1)), Operand::new(&b, (n, 1)));
+            let mut c = vec![f64::NAN; m * n];
+            f64::gemm(m, k, n, Operand::new(&a, (k, 1)), Operand::new(&b, (n, 1)), &mut c);
+            assert_close(c, &expected, 1e-12);
+
+            let (a, b) = (a.iter().map(|x| *x as f32).collect::<Vec<_>>(), b.iter().map(|x| *x as f32).collect::<Vec<_>>());
+            let mut c = vec![f32::NAN; m * n];
+            f32::gemm(m, k, n, Operand::new(&a, (k, 1)), Operand::new(&b, (n, 1)), &mut c);
+            assert_close(c.into_iter().map(f64::from), &expected, 1e-4);
+        }
+    }
+
+    #[test]
+    fn matches_naive_product_of_transposed_views() {
+        for (m, k, n) in SHAPES {
+            // `a` stored as `k x m` and `b` as `n x k`, both read transposed
+            let (a, b) = (sample(k, m, 3), sample(n, k, 4));
+            let (a_view, b_view) = (Operand::new(&a, (1, m)), Operand::new(&b, (1, k)));
+            let expected = naive(m, k, n, a_view, b_view);
+            let mut c = vec![f64::NAN; m * n];
+            f64::gemm(m, k, n, a_view, b_view, &mut c);
+            assert_close(c, &expected, 1e-12);
+            // one operand transposed, the other not
+            let b_rows = sample(k, n, 5);
+            let expected = naive(m, k, n, a_view, Operand::new(&b_rows, (n, 1)));
+            let mut c = vec![f64::NAN; m * n];
+            f64::gemm(m, k, n, a_view, Operand::new(&b_rows, (n, 1)), &mut c);
+            assert_close(c, &expected, 1e-12);
+        }
+    }
+
+    #[test]
+    fn f16_matches_naive_product() {
+        for (m, k, n) in SHAPES {
+            let (a, b) = (sample(m, k, 6), sample(k, n, 7));
+            let (a, b) = (a.iter().map(|x| f16::from_f64(*x)).collect::<Vec<_>>(), b.iter().map(|x| f16::from_f64(*x)).collect::<Vec<_>>());
+            let widened = |data: &[f16]| data.iter().map(|x| x.to_f64()).collect::<Vec<_>>();
+            let (a_wide, b_wide) = (widened(&a), widened(&b));
+            let expected = naive(m, k, n, Operand::new(&a_wide, (k, 1)), Operand::new(&b_wide, (n, 1)));
+            let mut c = vec![f16::NAN; m * n];
+            f16::gemm(m, k, n, Operand::new(&a, (k, 1)), Operand::new(&b, (n, 1)), &mut c);
+            // one rounding to half precision of an f32 accumulation
+            assert_close(c.iter().map(|x| x.to_f64()), &expected, 2e-3);
+        }
+    }
+
+    #[test]
+    fn f16_accumulates_in_f32() {
+        // 2048 + 1 is not representable in half precision, so accumulating in it would stop at 2048
+        let k = 3000;
+        let (a, b) = (vec![f16::ONE; k], vec![f16::ONE; k]);
+        let mut c = [f16::ZERO];
+        f16::gemm(1, k, 1, Operand::new(&a, (k, 1)), Operand::new(&b, (1, 1)), &mut c);
+        assert_eq!(c[0], f16::from_f32(3000.0));
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::{Scalar, MatrixPlan, Matrix, Activation};
+use crate::{Scalar, MatrixPlan, Matrix, Activation, plan::PLACEHOLDER};
 
 pub trait Layer<I: Scalar> {
     fn input_shape(&self) -> (usize, usize);
@@ -19,7 +19,21 @@ pub trait Layer<I: Scalar> {
 
     fn forward_plan(&self, from: MatrixPlan<I>) -> MatrixPlan<I>;
 
-    fn backward_plan(&self, prior: MatrixPlan<I>, layer_value: MatrixPlan<I>, lower_layer_value: MatrixPlan<I>) -> (MatrixPlan<I>, MatrixPlan<I>);
+    /// Returns the prior of the layer below and the gradient of this layer's weights.
+    /// Derived from `forward_plan` by default, for layers reading a single weights input.
+    fn backward_plan(&self, prior: MatrixPlan<I>, _layer_value: MatrixPlan<I>, lower_layer_value: MatrixPlan<I>) -> (MatrixPlan<I>, MatrixPlan<I>) {
+        let (rows, cols) = lower_layer_value.dims();
+        let placeholder = MatrixPlan::input(rows, cols, PLACEHOLDER);
+        let forward = self.forward_plan(placeholder);
+        let mut weights = forward.inputs().into_iter().map(|(name, _)| name).filter(|name| *name != PLACEHOLDER).collect::<Vec<_>>();
+        weights.sort_unstable();
+        weights.dedup();
+        assert_eq!(weights.len(), 1, "a derived backward_plan needs exactly one weights input");
+
+        let mut gradients = forward.gradients_seeded(prior, &[PLACEHOLDER, weights[0]]).into_iter()
+            .map(|gradient| gradient.substitute(PLACEHOLDER, &lower_layer_value));
+        (gradients.next().unwrap(), gradients.next().unwrap())
+    }
 }
 
 pub struct DenseLayer<I: Scalar, A: Activation> {
@@ -77,10 +91,59 @@ impl<I: Scalar, A: Activation> Layer<I> for DenseLayer<I, A> {
         self.activation.forward_plan(self.input.clone().unwrap() * from)
     }
 
-    fn backward_plan(&self, prior: MatrixPlan<I>, layer_value: MatrixPlan<I>, lower_layer_value: MatrixPlan<I>) -> (MatrixPlan<I>, MatrixPlan<I>) {
+    fn backward_plan(&self, prior: MatrixPlan<I>, _layer_value: MatrixPlan<I>, lower_layer_value: MatrixPlan<I>) -> (MatrixPlan<I>, MatrixPlan<I>) {
         assert!(self.input.is_some());
 
-        let sigma = prior.hadamard_mul(self.activation.derivative(layer_value));
+        // the activation differentiates at its input, the same product as in `forward_plan`, which CSE merges with it when compiled
+        let pre_activation = self.input.clone().unwrap() * &lower_layer_value;
+        let sigma = self.activation.backward_plan(prior, pre_activation);
         (self.input.clone().unwrap().transpose() * &sigma, sigma * lower_layer_value.transpose())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Activation, MatrixPlan, activation::{Linear, Relu, Sigmoid, Softmax, Softplus, Tanh}, plan::testing::{assert_close, bind, finite_difference, matrix, sample}};
+
+    use super::{DenseLayer, Layer};
+
+    /// Both gradients of a dense layer against finite differences of `sum(prior ⊙ forward)`
+    fn assert_dense_gradients(activation: impl Activation) {
+        let mut layer = DenseLayer::new(sample(2, 3, 1), activation);
+        layer.prepare_input(0);
+        let x = MatrixPlan::input(3, 4, "x");
+        let prior = MatrixPlan::constant(sample(2, 4, 2));
+        let forward = layer.forward_plan(x.clone());
+        let (lower, weights) = layer.backward_plan(prior.clone(), forward.clone(), x);
+        let loss = forward.hadamard_mul(prior);
+
+        let mut inputs = bind(&[("x", &sample(3, 4, 3).scale(3.0))]);
+        layer.assign_input(&mut inputs);
+        assert_close(&lower.execute_cpu(&inputs).0, &finite_difference(&loss, "x", &inputs), 1e-6);
+        assert_close(&weights.execute_cpu(&inputs).0, &finite_difference(&loss, "weights_0", &inputs), 1e-6);
+    }
+
+    #[test]
+    fn dense_layer_gradients_match_finite_differences() {
+        assert_dense_gradients(Linear);
+        assert_dense_gradients(Relu);
+        assert_dense_gradients(Sigmoid);
+        assert_dense_gradients(Tanh);
+        assert_dense_gradients(Softplus);
+        assert_dense_gradients(Softmax);
+    }
+
+    #[test]
+    fn tanh_layer_differentiates_at_the_pre_activation() {
+        let mut layer = DenseLayer::new(matrix(1, 2, &[1.0, 2.0]), Tanh);
+        layer.prepare_input(0);
+        let x = MatrixPlan::input(2, 1, "x");
+        let forward = layer.forward_plan(x.clone());
+        let (_, weights) = layer.backward_plan(forward.fill(1.0), forward, x);
+        let mut inputs = bind(&[("x", &matrix(2, 1, &[0.5, 1.0]))]);
+        layer.assign_input(&mut inputs);
+        // z = 2.5
+        let slope = 1.0 - 2.5f64.tanh().powi(2);
+        assert_close(&weights.execute_cpu(&inputs).0, &matrix(1, 2, &[0.5 * slope, slope]), 1e-12);
+    }
+}
@@ -2,9 +2,16 @@
 mod matrix;
 pub use matrix::*;
 
+mod gemm;
+
+pub mod parallel;
+
 mod scalar;
 pub use scalar::*;
 
+mod error;
+pub use error::{MatrixError, ExecuteResult};
+
 mod plan;
 pub use plan::*;
 
@@ -1,9 +1,27 @@
 use core::fmt;
-use std::{ops::{Index, IndexMut, Mul, Add, Neg, Sub}, fmt::{Display, Debug}};
+use std::{ops::{Index, IndexMut, Mul, Add, Neg, Sub, Range}, fmt::{Display, Debug}};
 
 use half::f16;
 
-use crate::Scalar;
+use crate::{Scalar, gemm::Operand, parallel};
+
+/// Component-wise maximum of [`Matrix::max`], `rhs` unless `x` compares greater (so a NaN `x` and `-0.0` against `0.0` give `rhs`)
+pub(crate) fn max<I: Scalar>(x: I, rhs: I) -> I {
+    if x > rhs {
+        x
+    } else {
+        rhs
+    }
+}
+
+/// Component-wise minimum of [`Matrix::min`], `rhs` unless `x` compares less
+pub(crate) fn min<I: Scalar>(x: I, rhs: I) -> I {
+    if x < rhs {
+        x
+    } else {
+        rhs
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct Matrix<I: Scalar> {
@@ -65,90 +83,383 @@ impl<I: Scalar> Matrix<I> {
         }
     }
 
-    pub fn scale(mut self, rhs: I) -> Self {
-        for component in self.as_mut() {
-            *component = *component * rhs;
-        }
+    /// Applies `f` to every component in place, split into row blocks across threads when large
+    fn map(mut self, f: impl Fn(I) -> I + Sync + Send) -> Self {
+        let work = self.data.len();
+        parallel::for_each_row_block(&mut self.data, self.cols, work, |_, block| {
+            block.iter_mut().for_each(|x| *x = f(*x));
+        });
         self
     }
 
-    pub fn max(mut self, rhs: I) -> Self {
-        for component in self.as_mut() {
-            *component = if *component > rhs {
-                *component
-            } else {
-                rhs
-            };
-        }
+    /// Combines every component with the matching component of `rhs` in place
+    fn zip_map(mut self, rhs: &Matrix<I>, f: impl Fn(I, I) -> I + Sync + Send) -> Self {
+        let work = self.data.len();
+        let cols = self.cols;
+        parallel::for_each_row_block(&mut self.data, cols, work, |first_row, block| {
+            let rhs = &rhs.data[first_row * cols..first_row * cols + block.len()];
+            block.iter_mut().zip(rhs.iter().copied()).for_each(|(x, y)| *x = f(*x, y));
+        });
         self
     }
 
-    pub fn min(mut self, rhs: I) -> Self {
-        for component in self.as_mut() {
-            *component = if *component < rhs {
-                *component
-            } else {
-                rhs
-            };
-        }
-        self
+    pub fn scale(self, rhs: I) -> Self {
+        self.map(|x| x * rhs)
     }
 
-    pub fn sigmoid(mut self) -> Self {
-        for component in self.as_mut() {
-            *component = I::ONE / (I::ONE + I::from_f64(std::f64::consts::E).power(-*component));
-        }
-        self
+    pub fn max(self, rhs: I) -> Self {
+        self.map(|x| max(x, rhs))
+    }
+
+    pub fn min(self, rhs: I) -> Self {
+        self.map(|x| min(x, rhs))
+    }
+
+    /// clamps each component to `low..=high`
+    pub fn clamp(self, low: I, high: I) -> Self {
+        self.max(low).min(high)
+    }
+
+    pub fn sigmoid(self) -> Self {
+        self.map(I::sigmoid)
+    }
+
+    pub fn exp(self) -> Self {
+        self.map(I::exp)
+    }
+
+    /// natural logarithm of each component
+    pub fn ln(self) -> Self {
+        self.map(I::ln)
+    }
+
+    pub fn sqrt(self) -> Self {
+        self.map(I::sqrt)
+    }
+
+    pub fn abs(self) -> Self {
+        self.map(I::abs)
+    }
+
+    pub fn tanh(self) -> Self {
+        self.map(I::tanh)
+    }
+
+    /// raises each component to `exponent`
+    pub fn pow(self, exponent: I) -> Self {
+        self.map(|x| x.power(exponent))
+    }
+
+    pub fn reciprocal(self) -> Self {
+        self.map(I::recip)
     }
 
     /// sets each component to -1, 0, or 1
-    pub fn sign(mut self) -> Self {
-        for component in self.as_mut() {
-            *component = if *component > I::default() {
-                I::ONE
-            } else if *component < I::default() {
-                -I::ONE
+    pub fn sign(self) -> Self {
+        self.map(I::sign)
+    }
+
+    pub fn hadamard_mul<M: AsRef<Matrix<I>>>(self, rhs: M) -> Self {
+        let rhs = rhs.as_ref();
+        assert_eq!(self.cols, rhs.cols);
+        assert_eq!(self.rows, rhs.rows);
+        self.zip_map(rhs, |x, y| x * y)
+    }
+
+    /// 1 where a component is greater than the matching component of `rhs`, 0 elsewhere
+    pub fn greater<M: AsRef<Matrix<I>>>(self, rhs: M) -> Self {
+        self.compare(rhs.as_ref(), |x, y| x > y)
+    }
+
+    /// 1 where a component is less than the matching component of `rhs`, 0 elsewhere
+    pub fn less<M: AsRef<Matrix<I>>>(self, rhs: M) -> Self {
+        self.compare(rhs.as_ref(), |x, y| x < y)
+    }
+
+    /// 1 where a component equals the matching component of `rhs`, 0 elsewhere
+    pub fn equal<M: AsRef<Matrix<I>>>(self, rhs: M) -> Self {
+        self.compare(rhs.as_ref(), |x, y| x == y)
+    }
+
+    fn compare(self, rhs: &Matrix<I>, f: impl Fn(I, I) -> bool + Sync + Send) -> Self {
+        assert_eq!(self.cols, rhs.cols);
+        assert_eq!(self.rows, rhs.rows);
+        self.zip_map(rhs, |x, y| if f(x, y) {
+            I::ONE
+        } else {
+            I::default()
+        })
+    }
+
+    /// Treating `self` as a mask, takes components of `then` where it is non-zero and of `otherwise` elsewhere
+    pub fn select<T: AsRef<Matrix<I>>, E: AsRef<Matrix<I>>>(self, then: T, otherwise: E) -> Self {
+        let (then, otherwise) = (then.as_ref(), otherwise.as_ref());
+        assert_eq!((self.rows, self.cols), (then.rows, then.cols));
+        assert_eq!((self.rows, self.cols), (otherwise.rows, otherwise.cols));
+        let mut out = self;
+        let cols = out.cols;
+        let work = out.data.len();
+        parallel::for_each_row_block(&mut out.data, cols, work, |first_row, block| {
+            let range = first_row * cols..first_row * cols + block.len();
+            let choices = then.data[range.clone()].iter().zip(&otherwise.data[range]);
+            block.iter_mut().zip(choices).for_each(|(x, (then, otherwise))| *x = if *x != I::default() {
+                *then
             } else {
-                I::default()
-            };
-        }
-        self
+                *otherwise
+            });
+        });
+        out
     }
 
-    pub fn hadamard_mul<M: AsRef<Matrix<I>>>(mut self, rhs: M) -> Self {
+    pub fn hadamard_div<M: AsRef<Matrix<I>>>(self, rhs: M) -> Self {
         let rhs = rhs.as_ref();
         assert_eq!(self.cols, rhs.cols);
         assert_eq!(self.rows, rhs.rows);
-        for row in 0..self.rows {
-            let cols = self.cols;
-            let self_left_row = &mut self[row];
-            let rhs_left_row = &rhs[row];
-            
-            for col in 0..cols {
-                self_left_row[col] = self_left_row[col] * rhs_left_row[col];
-            }
+        self.zip_map(rhs, |x, y| x / y)
+    }
+
+    /// Matrix product of `self` and `rhs`, computed by the packed GEMM kernel
+    pub fn matmul(&self, rhs: &Matrix<I>) -> Matrix<I> {
+        let mut output = Matrix::default();
+        self.matmul_into(rhs, &mut output);
+        output
+    }
+
+    /// Same as [`Matrix::matmul`], but writes into `output`, reusing its allocation
+    pub(crate) fn matmul_into(&self, rhs: &Matrix<I>, output: &mut Matrix<I>) {
+        self.matmul_transposed_into(rhs, (false, false), output);
+    }
+
+    /// Same as [`Matrix::matmul_into`], reading `self` and `rhs` transposed as flagged in `transposed` through strides instead of copying them
+    pub(crate) fn matmul_transposed_into(&self, rhs: &Matrix<I>, transposed: (bool, bool), output: &mut Matrix<I>) {
+        // logical shape and (row, col) strides of each operand
+        let view = |matrix: &Matrix<I>, transposed: bool| if transposed {
+            (matrix.cols, matrix.rows, (1, matrix.cols))
+        } else {
+            (matrix.rows, matrix.cols, (matrix.cols, 1))
+        };
+        let (rows, inner, left_strides) = view(self, transposed.0);
+        let (rhs_rows, cols, right_strides) = view(rhs, transposed.1);
+        if inner != rhs_rows {
+            panic!("cannot multiply _x{} by {}x_ matrix", inner, rhs_rows);
         }
-        self
+        output.resize(rows, cols);
+        I::gemm(
+            rows,
+            inner,
+            cols,
+            Operand::new(&self.data, left_strides),
+            Operand::new(&rhs.data, right_strides),
+            &mut output.data,
+        );
     }
 
     pub fn transpose(&self) -> Self {
-        let mut out = Matrix::new(self.cols, self.rows);
-        for row in 0..self.rows {
-            for col in 0..self.cols {
-                out[col][row] = self[row][col];
-            }
-        }
+        let mut out = Matrix::default();
+        self.transpose_into(&mut out);
         out
     }
 
-    pub fn fill(mut self, with: I) -> Self {
-        self.data.iter_mut().for_each(|x| *x = with);
-        self
+    /// Same as [`Matrix::transpose`], but writes into `out`, reusing its allocation
+    pub(crate) fn transpose_into(&self, out: &mut Matrix<I>) {
+        out.resize(self.cols, self.rows);
+        let rows = self.rows;
+        // each block owns whole output rows, i.e. whole input columns
+        parallel::for_each_row_block(&mut out.data, rows, self.data.len(), |first_col, block| {
+            for (col, out_row) in block.chunks_exact_mut(rows.max(1)).enumerate() {
+                for (row, target) in out_row.iter_mut().enumerate() {
+                    *target = self[row][first_col + col];
+                }
+            }
+        });
+    }
+
+    /// Sets the shape, keeping the allocation. Contents are unspecified afterwards.
+    pub(crate) fn resize(&mut self, rows: usize, cols: usize) {
+        self.rows = rows;
+        self.cols = cols;
+        self.data.resize(rows * cols, I::default());
+    }
+
+    /// Copies `from` into `self`, keeping the allocation
+    pub(crate) fn assign(&mut self, from: &Matrix<I>) {
+        self.rows = from.rows;
+        self.cols = from.cols;
+        self.data.clear();
+        self.data.extend_from_slice(&from.data);
+    }
+
+    /// Bytes held by the components
+    pub fn size_bytes(&self) -> usize {
+        self.data.len() * std::mem::size_of::<I>()
+    }
+
+    pub fn fill(self, with: I) -> Self {
+        self.map(|_| with)
     }
 
     pub fn has_nan(&self) -> bool {
         self.data.iter().any(|x| x.is_nan())
     }
+
+    /// Stacks `parts` on top of each other, they must have the same column count
+    pub fn vstack(parts: &[&Matrix<I>]) -> Matrix<I> {
+        assert!(!parts.is_empty(), "nothing to stack");
+        let cols = parts[0].cols;
+        assert!(parts.iter().all(|part| part.cols == cols), "cannot stack matrices with different column counts");
+        Matrix {
+            data: parts.iter().flat_map(|part| part.data.iter().copied()).collect(),
+            rows: parts.iter().map(|part| part.rows).sum(),
+            cols,
+        }
+    }
+
+    /// Places `parts` side by side, they must have the same row count
+    pub fn hstack(parts: &[&Matrix<I>]) -> Matrix<I> {
+        assert!(!parts.is_empty(), "nothing to stack");
+        let rows = parts[0].rows;
+        assert!(parts.iter().all(|part| part.rows == rows), "cannot stack matrices with different row counts");
+        let mut data = Vec::with_capacity(parts.iter().map(|part| part.data.len()).sum());
+        for row in 0..rows {
+            for part in parts {
+                data.extend_from_slice(&part[row]);
+            }
+        }
+        Matrix {
+            data,
+            rows,
+            cols: parts.iter().map(|part| part.cols).sum(),
+        }
+    }
+
+    /// Copy of the components in `rows` and `cols`
+    pub fn submatrix(&self, rows: Range<usize>, cols: Range<usize>) -> Matrix<I> {
+        assert!(rows.start <= rows.end && rows.end <= self.rows, "rows {:?} out of bounds for {} rows", rows, self.rows);
+        assert!(cols.start <= cols.end && cols.end <= self.cols, "cols {:?} out of bounds for {} cols", cols, self.cols);
+        Matrix {
+            data: rows.clone().flat_map(|row| self[row][cols.clone()].iter().copied()).collect(),
+            rows: rows.len(),
+            cols: cols.len(),
+        }
+    }
+
+    /// Reinterprets the components, in row-major order, as a `rows x cols` matrix
+    pub fn reshape(self, rows: usize, cols: usize) -> Matrix<I> {
+        assert_eq!(self.rows * self.cols, rows * cols, "cannot reshape {}x{} into {}x{}", self.rows, self.cols, rows, cols);
+        Matrix {
+            data: self.data,
+            rows,
+            cols,
+        }
+    }
+
+    /// adds the rows together into a `1 x cols` row
+    pub fn sum_rows(&self) -> Matrix<I> {
+        let mut out = Matrix::new(1, self.cols);
+        for row in self.data.chunks(self.cols.max(1)) {
+            out.data.iter_mut().zip(row).for_each(|(total, x)| *total = *total + *x);
+        }
+        out
+    }
+
+    /// adds the columns together into a `rows x 1` column
+    pub fn sum_cols(&self) -> Matrix<I> {
+        Matrix::from_col((0..self.rows).map(|row| self[row].iter().copied().sum()))
+    }
+
+    pub fn mean(&self) -> I {
+        self.data.iter().copied().sum::<I>() / I::from_f64(self.data.len() as f64)
+    }
+
+    /// largest component of each column, as a `1 x cols` row
+    pub fn max_reduce(&self) -> Matrix<I> {
+        assert!(self.rows > 0, "cannot reduce an empty matrix");
+        let mut out = Matrix::new(1, self.cols);
+        out.data.copy_from_slice(&self[0]);
+        for row in 1..self.rows {
+            out.data.iter_mut().zip(&self[row]).for_each(|(max, x)| if *x > *max {
+                *max = *x;
+            });
+        }
+        out
+    }
+
+    /// row of the largest component of each column (the first one on ties), as a `1 x cols` row
+    pub fn argmax(&self) -> Matrix<I> {
+        let max = self.max_reduce();
+        let mut out = Matrix::new(1, self.cols);
+        for col in 0..self.cols {
+            let row = (0..self.rows).find(|row| self[*row][col] == max.data[col]).unwrap_or(0);
+            out.data[col] = I::from_f64(row as f64);
+        }
+        out
+    }
+
+    /// `rows x cols` matrix with a one in each column at the row given by the matching component of a `1 x cols` row of indices (e.g. from [`Matrix::argmax`]), zero elsewhere.
+    /// Columns whose index is not a row are all zero.
+    pub fn one_hot(&self, rows: usize) -> Matrix<I> {
+        assert_eq!(self.rows, 1, "can only one-hot encode a single row");
+        let mut out = Matrix::new(rows, self.cols);
+        for (col, index) in self.data.iter().enumerate() {
+            let row = index.to_f64();
+            if row >= 0.0 && row < rows as f64 && row.fract() == 0.0 {
+                out.data[row as usize * self.cols + col] = I::ONE;
+            }
+        }
+        out
+    }
+
+    /// Components minus the maximum of their column, and the per column sums of their exponentials
+    fn shifted_exp_sums(&self) -> (Matrix<I>, Matrix<I>) {
+        let max = self.max_reduce();
+        let mut shifted = self.clone();
+        for row in 0..self.rows {
+            shifted[row].iter_mut().zip(&max.data).for_each(|(x, max)| *x = *x - *max);
+        }
+        let mut sums = Matrix::new(1, self.cols);
+        for row in 0..self.rows {
+            sums.data.iter_mut().zip(&shifted[row]).for_each(|(total, x)| *total = *total + x.exp());
+        }
+        (shifted, sums)
+    }
+
+    /// softmax of each column, the column maximum is subtracted first so the exponentials cannot overflow
+    pub fn softmax(&self) -> Matrix<I> {
+        let (mut out, sums) = self.shifted_exp_sums();
+        for row in 0..self.rows {
+            out[row].iter_mut().zip(&sums.data).for_each(|(x, total)| *x = x.exp() / *total);
+        }
+        out
+    }
+
+    /// logarithm of the softmax of each column, computed as `x - max - ln(sum(e^(x - max)))`
+    pub fn log_softmax(&self) -> Matrix<I> {
+        let (mut out, sums) = self.shifted_exp_sums();
+        for row in 0..self.rows {
+            out[row].iter_mut().zip(&sums.data).for_each(|(x, total)| *x = *x - total.ln());
+        }
+        out
+    }
+
+    /// repeats a `1 x cols` row `rows` times
+    pub fn broadcast_rows(&self, rows: usize) -> Matrix<I> {
+        assert_eq!(self.rows, 1, "can only broadcast a single row");
+        Matrix {
+            data: self.data.repeat(rows),
+            rows,
+            cols: self.cols,
+        }
+    }
+
+    /// repeats a `rows x 1` column `cols` times
+    pub fn broadcast_cols(&self, cols: usize) -> Matrix<I> {
+        assert_eq!(self.cols, 1, "can only broadcast a single column");
+        Matrix {
+            data: self.data.iter().flat_map(|x| std::iter::repeat_n(*x, cols)).collect(),
+            rows: self.rows,
+            cols,
+        }
+    }
 }
 
 impl<I: Scalar> Display for Matrix<I> {
@@ -226,11 +537,8 @@ macro_rules! mul_impl {
         impl Mul<$scalar> for Matrix<$scalar> {
             type Output = Matrix<$scalar>;
         
-            fn mul(mut self, rhs: $scalar) -> Self::Output {
-                for component in self.as_mut() {
-                    *component = *component * rhs;
-                }
-                self
+            fn mul(self, rhs: $scalar) -> Self::Output {
+                self.scale(rhs)
             }
         }
 
@@ -252,32 +560,18 @@ impl<I: Scalar, M: AsRef<Matrix<I>>> Mul<M> for Matrix<I> {
     type Output = Matrix<I>;
 
     fn mul(self, rhs: M) -> Self::Output {
-        let rhs = rhs.as_ref();
-        if self.cols != rhs.rows {
-            panic!("cannot multiply _x{} by {}x_ matrix", self.cols, rhs.rows);
-        }
-        let mut output = Matrix::<I>::new(self.rows, rhs.cols);
-        for row in 0..self.rows {
-            let left_row = &self[row];
-            
-            for col in 0..rhs.cols {
-                output[row][col] = left_row.iter().copied().zip(rhs.col(col)).map(|(left, right)| left * right).sum::<I>();
-            }
-        }
-        output
+        self.matmul(rhs.as_ref())
     }
 }
 
 impl<I: Scalar, M: AsRef<Matrix<I>>> Add<M> for Matrix<I> {
     type Output = Matrix<I>;
 
-    fn add(mut self, rhs: M) -> Self::Output {
+    fn add(self, rhs: M) -> Self::Output {
         let rhs = rhs.as_ref();
         assert_eq!(self.rows, rhs.rows);
         assert_eq!(self.cols, rhs.cols);
-        let rhs: &[I] = rhs.as_ref();
-        self.as_mut().iter_mut().zip(rhs.iter().copied()).for_each(|(target, source)| *target = *target + source);
-        self
+        self.zip_map(rhs, |x, y| x + y)
     }
 
 }
@@ -285,21 +579,72 @@ impl<I: Scalar, M: AsRef<Matrix<I>>> Add<M> for Matrix<I> {
 impl<I: Scalar> Neg for Matrix<I> {
     type Output = Matrix<I>;
 
-    fn neg(mut self) -> Self::Output {
-        self.as_mut().iter_mut().for_each(|x| *x = -*x);
-        self
+    fn neg(self) -> Self::Output {
+        self.map(|x| -x)
     }
 }
 
 impl<I: Scalar, M: AsRef<Matrix<I>>> Sub<M> for Matrix<I> {
     type Output = Matrix<I>;
 
-    fn sub(mut self, rhs: M) -> Self::Output {
+    fn sub(self, rhs: M) -> Self::Output {
         let rhs = rhs.as_ref();
         assert_eq!(self.rows, rhs.rows);
         assert_eq!(self.cols, rhs.cols);
-        let rhs: &[I] = rhs.as_ref();
-        self.as_mut().iter_mut().zip(rhs.iter().copied()).for_each(|(target, source)| *target = *target - source);
-        self
+        self.zip_map(rhs, |x, y| x - y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Matrix, plan::testing::{assert_bitwise_eq, matrix}};
+
+    #[test]
+    fn stacking_keeps_components_in_place() {
+        let top = matrix(1, 3, &[1.0, 2.0, 3.0]);
+        let bottom = matrix(2, 3, &[4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_bitwise_eq(&Matrix::vstack(&[&top, &bottom]), &matrix(3, 3, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]));
+        let left = matrix(2, 1, &[1.0, 2.0]);
+        let right = matrix(2, 2, &[3.0, 4.0, 5.0, 6.0]);
+        assert_bitwise_eq(&Matrix::hstack(&[&left, &right, &left]), &matrix(2, 4, &[1.0, 3.0, 4.0, 1.0, 2.0, 5.0, 6.0, 2.0]));
+        assert_bitwise_eq(&Matrix::vstack(&[&top]), &top);
+    }
+
+    #[test]
+    #[should_panic(expected = "different column counts")]
+    fn vstack_of_different_widths_panics() {
+        Matrix::vstack(&[&Matrix::<f64>::new(1, 2), &Matrix::new(1, 3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "different row counts")]
+    fn hstack_of_different_heights_panics() {
+        Matrix::hstack(&[&Matrix::<f64>::new(1, 2), &Matrix::new(2, 2)]);
+    }
+
+    #[test]
+    fn submatrix_copies_the_ranges() {
+        let whole = matrix(3, 4, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0]);
+        assert_bitwise_eq(&whole.submatrix(1..3, 1..3), &matrix(2, 2, &[5.0, 6.0, 9.0, 10.0]));
+        assert_bitwise_eq(&whole.submatrix(0..3, 0..4), &whole);
+        let empty = whole.submatrix(2..2, 0..4);
+        assert_eq!((empty.rows(), empty.cols()), (0, 4));
+        // the parts of a split stack back into the whole
+        let (top, bottom) = (whole.submatrix(0..1, 0..4), whole.submatrix(1..3, 0..4));
+        assert_bitwise_eq(&Matrix::vstack(&[&top, &bottom]), &whole);
+        let (left, right) = (whole.submatrix(0..3, 0..3), whole.submatrix(0..3, 3..4));
+        assert_bitwise_eq(&Matrix::hstack(&[&left, &right]), &whole);
+    }
+
+    #[test]
+    #[should_panic(expected = "cols 2..5 out of bounds for 4 cols")]
+    fn submatrix_out_of_bounds_panics() {
+        Matrix::<f64>::new(3, 4).submatrix(0..1, 2..5);
+    }
+
+    #[test]
+    fn reshape_reads_components_in_row_major_order() {
+        let reshaped = matrix(2, 3, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).reshape(3, 2);
+        assert_bitwise_eq(&reshaped, &matrix(3, 2, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
     }
 }
@@ -1,6 +1,19 @@
 use std::collections::HashMap;
 
-use crate::{Scalar, MatrixPlan, Matrix, Layer, Activation, DenseLayer, Optimizer};
+use crate::{Scalar, MatrixPlan, Matrix, Layer, Activation, DenseLayer, Optimizer, Dim};
+
+/// Which layer activations [`NeuralNetworkBuilder::plan_backprop`] keeps for the backward pass.
+/// The others are dropped after the forward pass and recomputed from the nearest kept activation below when the backward pass reaches them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Checkpointing {
+    /// keep every activation
+    #[default]
+    Disabled,
+    /// keep the activation of every `k`-th layer
+    EveryLayers(usize),
+    /// keep the activations of every `k`-th layer for the smallest `k` whose kept and recomputed activations fit in this many bytes per sample of the batch, or for the `k` needing the least memory if none fits
+    MemoryBudget(usize),
+}
 
 #[derive(Default)]
 pub struct NeuralNetworkBuilder<I: Scalar> {
@@ -8,6 +21,7 @@ pub struct NeuralNetworkBuilder<I: Scalar> {
     layers: Vec<Box<dyn Layer<I>>>,
     inputs: usize,
     trained_steps: usize,
+    checkpointing: Checkpointing,
 }
 
 impl<I: Scalar> NeuralNetworkBuilder<I> {
@@ -46,6 +60,37 @@ impl<I: Scalar> NeuralNetworkBuilder<I> {
         self
     }
 
+    /// Trades compute for memory in [`NeuralNetworkBuilder::plan_backprop`], see [`Checkpointing`].
+    /// Panics on `Checkpointing::EveryLayers(0)`.
+    pub fn checkpointing(mut self, checkpointing: Checkpointing) -> Self {
+        assert!(checkpointing != Checkpointing::EveryLayers(0), "checkpoints every 0 layers");
+        self.checkpointing = checkpointing;
+        self
+    }
+
+    /// Layers between kept activations under the checkpointing policy, the network input counting as kept
+    fn checkpoint_interval(&self) -> usize {
+        let layers = self.layers.len();
+        match self.checkpointing {
+            Checkpointing::Disabled => 1,
+            Checkpointing::EveryLayers(interval) => interval,
+            Checkpointing::MemoryBudget(bytes) => {
+                let sizes = self.layers.iter().map(|layer| layer.output_shape().0 * std::mem::size_of::<I>()).collect::<Vec<_>>();
+                // activation `i + 1` is the output of layer `i`, the last one is needed right away
+                let required = |interval: usize| {
+                    let kept = (1..layers).filter(|value| value.is_multiple_of(interval)).map(|value| sizes[value - 1]).sum::<usize>();
+                    let recomputed = (0..layers).step_by(interval)
+                        .map(|checkpoint| (checkpoint + 1..(checkpoint + interval).min(layers)).map(|value| sizes[value - 1]).sum::<usize>())
+                        .max()
+                        .unwrap_or(0);
+                    kept + recomputed
+                };
+                (1..=layers).find(|interval| required(*interval) <= bytes)
+                    .unwrap_or_else(|| (1..=layers).min_by_key(|interval| required(*interval)).unwrap())
+            },
+        }
+    }
+
     // pub fn layers(&self) -> &[Layer<I>] {
     //     &self.layers[..]
     // }
@@ -81,9 +126,21 @@ impl<I: Scalar> NeuralNetworkBuilder<I> {
         outputs.col(0).collect()
     }
 
+    /// Jacobian of the network outputs with respect to its inputs, at `inputs`
+    pub fn jacobian(&self, inputs: &[I]) -> Matrix<I> {
+        assert!(self.plan.is_some());
+
+        let matrix = Matrix::from_col(inputs.iter().copied());
+
+        let mut inputs = HashMap::new();
+        inputs.insert("input".to_string(), matrix);
+        self.fill_plan_weights(&mut inputs);
+        self.plan.as_ref().unwrap().jacobian_cpu("input", &inputs)
+    }
+
     pub fn apply_backprop<O: Optimizer<I>>(&mut self, optimizer: &mut O, gradients: Vec<Matrix<I>>) {
         assert_eq!(self.layers.len(), gradients.len());
-        self.layers.iter_mut().zip(gradients.into_iter()).for_each(|(current, gradient)| {
+        self.layers.iter_mut().zip(gradients).for_each(|(current, gradient)| {
             //TODO: weight-less layers
             let weights = current.get_weights().unwrap().clone();
             current.set_weights(optimizer.optimize(weights, gradient, self.trained_steps));
@@ -91,10 +148,14 @@ impl<I: Scalar> NeuralNetworkBuilder<I> {
         self.trained_steps += 1;
     }
 
-    pub fn plan_backprop(&self, batch_size: usize) -> MatrixPlan<I> {
+    /// A symbolic `batch_size` (e.g. `plan_backprop("batch")`) gives one plan for every batch size, taken from the bound inputs when executed on the CPU.
+    /// Compiling does not accept symbolic dimensions, so compiled training still needs a [`MatrixPlan::resolve`] and a [`MatrixPlan::compile`] per batch size.
+    /// With [`Checkpointing`], activations between checkpoints are recomputed during the backward pass, which lowers the memory of compiled and concurrent executions.
+    pub fn plan_backprop(&self, batch_size: impl Into<Dim>) -> MatrixPlan<I> {
         assert!(!self.layers.is_empty());
 
-        let targets = MatrixPlan::<I>::input(self.plan.as_ref().unwrap().rows(), batch_size, "targets");
+        let batch_size = batch_size.into();
+        let targets = MatrixPlan::<I>::input(self.plan.as_ref().unwrap().rows(), &batch_size, "targets");
         let inputs = MatrixPlan::<I>::input(self.inputs, batch_size, "inputs");
 
         let mut state = inputs;
@@ -109,24 +170,112 @@ impl<I: Scalar> NeuralNetworkBuilder<I> {
 
         let diff = layer_values.last().cloned().unwrap() - targets;
 
+        let interval = self.checkpoint_interval();
+        let kept = |value: usize| value.is_multiple_of(interval) || value == self.layers.len();
         let mut prior = diff;
         let mut output = vec![];
-        for ((layer, layer_value), lower_layer_value) in self.layers.iter().rev()
-            .zip(layer_values.iter().rev())
-            .zip(layer_values.iter().rev().skip(1)) {
-            let (new_prior, out) = layer.backward_plan(prior, layer_value.clone(), lower_layer_value.clone());
+        for (index, layer) in self.layers.iter().enumerate().rev() {
+            // entering a run of dropped activations, recompute all of them from the checkpoint below once the backward pass got here
+            if !kept(index) && kept(index + 1) {
+                let checkpoint = index - index % interval;
+                let mut state = layer_values[checkpoint].clone().recompute_after(&prior);
+                // the layer above the checkpoint reads it through the barrier too, or its backward product would be merged with the forward one, keeping that alive
+                layer_values[checkpoint] = state.clone();
+                for lower in checkpoint..index {
+                    state = self.layers[lower].forward_plan(state);
+                    layer_values[lower + 1] = state.clone();
+                }
+            }
+            let (new_prior, out) = layer.backward_plan(prior, layer_values[index + 1].clone(), layer_values[index].clone());
             prior = new_prior;
-            output.push(out);
+            // merged from the top layer down, so each gradient is evaluated as soon as the backward pass reaches its layer and the activations it reads can be dropped
+            output.push(out.output(format!("gradient_{}", index)));
         }
 
-        output.reverse();
-        output.iter_mut().enumerate().for_each(|(i, sigma)| {
-            let old = std::mem::take(sigma);
-            *sigma = old.output(format!("gradient_{}", i));
-        });
-        println!("output = {:#?}", output);
         output.push(outputs);
 
         MatrixPlan::merge_outputs(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Matrix, activation::{Sigmoid, Tanh}, plan::testing::{assert_bitwise_eq, bind, sample}};
+
+    use super::{Checkpointing, NeuralNetworkBuilder};
+
+    const BATCH: usize = 16;
+
+    /// Layers of the given widths over 4 inputs, alternating activations
+    fn network(widths: &[usize], checkpointing: Checkpointing) -> NeuralNetworkBuilder<f64> {
+        let mut network = NeuralNetworkBuilder::new().input(4).checkpointing(checkpointing);
+        let mut inputs = 4;
+        for (seed, width) in widths.iter().enumerate() {
+            let weights = sample(*width, inputs, seed as u64);
+            network = if seed % 2 == 0 {
+                network.add_dense_layer_weighted(weights, Tanh)
+            } else {
+                network.add_dense_layer_weighted(weights, Sigmoid)
+            };
+            inputs = *width;
+        }
+        network
+    }
+
+    fn inputs(network: &NeuralNetworkBuilder<f64>, targets: usize) -> std::collections::HashMap<String, Matrix<f64>> {
+        let mut inputs = bind(&[("inputs", &sample(4, BATCH, 100)), ("targets", &sample(targets, BATCH, 101))]);
+        network.fill_plan_weights(&mut inputs);
+        inputs
+    }
+
+    #[test]
+    fn checkpointing_keeps_the_gradients() {
+        let widths = [8, 8, 8, 8, 8, 2];
+        let disabled = network(&widths, Checkpointing::Disabled);
+        let inputs = inputs(&disabled, 2);
+        let (_, expected) = disabled.plan_backprop(BATCH).execute_cpu(&inputs);
+        assert_eq!(expected.len(), widths.len() + 1);
+        let policies = (1..=widths.len() + 1).map(Checkpointing::EveryLayers).chain([0, 200, 1000, usize::MAX].map(Checkpointing::MemoryBudget));
+        for checkpointing in policies {
+            let plan = network(&widths, checkpointing).plan_backprop(BATCH);
+            for (_, outputs) in [plan.execute_cpu(&inputs), plan.compile().execute(&inputs)] {
+                assert_eq!(outputs.len(), expected.len());
+                for (name, expected) in &expected {
+                    assert_bitwise_eq(&outputs[name], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn memory_budget_picks_the_smallest_interval_that_fits() {
+        // activations of 4, 8, 8, 8 and 2 values, the first four can be dropped, the last one is needed right away
+        let widths = [4, 8, 8, 8, 2];
+        // with 8 byte values, interval 1 keeps 224 bytes and recomputes none, interval 2 keeps 128 and recomputes at most 64,
+        // interval 3 keeps 64 and recomputes at most 96, interval 4 keeps 64 and recomputes 160, interval 5 recomputes 224
+        for (budget, interval) in [(usize::MAX, 1), (224, 1), (223, 2), (192, 2), (191, 3), (160, 3), (0, 3)] {
+            assert_eq!(network(&widths, Checkpointing::MemoryBudget(budget)).checkpoint_interval(), interval, "budget {}", budget);
+        }
+        assert_eq!(network(&widths, Checkpointing::Disabled).checkpoint_interval(), 1);
+        assert_eq!(network(&widths, Checkpointing::EveryLayers(4)).checkpoint_interval(), 4);
+    }
+
+    #[test]
+    fn checkpointing_lowers_the_peak_memory_of_compiled_plans() {
+        // a large batch of narrow layers, so activations outweigh the gradients, which all live until the end
+        let widths = [8; 8];
+        let peak = |checkpointing| network(&widths, checkpointing).plan_backprop(256).peak_memory();
+        let disabled = peak(Checkpointing::Disabled);
+        // every layer but the last dropped, recomputing them all from the inputs holds as much as keeping them
+        for interval in 2..widths.len() {
+            let checkpointed = peak(Checkpointing::EveryLayers(interval));
+            assert!(checkpointed < disabled, "every {} layers: {} bytes, {} without checkpoints", interval, checkpointed, disabled);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "checkpoints every 0 layers")]
+    fn checkpointing_every_zero_layers_is_rejected() {
+        NeuralNetworkBuilder::<f64>::new().checkpointing(Checkpointing::EveryLayers(0));
+    }
+}
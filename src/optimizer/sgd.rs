@@ -38,7 +38,7 @@ impl<I: Scalar, L: LearningRate<I>> Optimizer<I> for StochasticGradientDescent<I
     fn optimize(&mut self, weights: Matrix<I>, gradient: Matrix<I>, step: usize) -> Matrix<I> {
         let gradient = gradient.scale(self.learning_rate.rate(step));
         if gradient.has_nan() {
-            eprintln!("NaN in gradients, skipping SGD application");
+            eprintln!("NaN in gradients, skipping SGD application (MatrixPlan::try_execute_cpu_checked finds the op producing it)");
             return weights;
         }
         let mut out = weights - gradient;
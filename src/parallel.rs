@@ -0,0 +1,82 @@
+//! Splitting of matrix kernels across a thread pool.
+//!
+//! With the `parallel` feature enabled, kernels whose work exceeds [`threshold`] are split into row blocks and run on a shared pool of [`thread_count`] threads.
+//! Without it, every kernel runs on the calling thread.
+
+#[cfg(feature = "parallel")]
+use std::sync::{Arc, RwLock, atomic::{AtomicUsize, Ordering}};
+
+#[cfg(feature = "parallel")]
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+
+/// Default amount of work (elements touched, or multiply-adds for products) below which kernels stay single-threaded
+pub const DEFAULT_THRESHOLD: usize = 1 << 15;
+
+#[cfg(feature = "parallel")]
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "parallel")]
+static THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_THRESHOLD);
+
+#[cfg(feature = "parallel")]
+static POOL: RwLock<Option<Arc<ThreadPool>>> = RwLock::new(None);
+
+/// Sets the number of worker threads, `0` uses one per available core. Rebuilds the pool on next use.
+#[cfg(feature = "parallel")]
+pub fn set_thread_count(threads: usize) {
+    THREADS.store(threads, Ordering::Relaxed);
+    *POOL.write().unwrap() = None;
+}
+
+/// Number of worker threads kernels are split across
+#[cfg(feature = "parallel")]
+pub fn thread_count() -> usize {
+    pool().current_num_threads()
+}
+
+/// Sets the amount of work below which kernels run on the calling thread
+#[cfg(feature = "parallel")]
+pub fn set_threshold(work: usize) {
+    THRESHOLD.store(work, Ordering::Relaxed);
+}
+
+#[cfg(feature = "parallel")]
+pub fn threshold() -> usize {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "parallel")]
+pub(crate) fn pool() -> Arc<ThreadPool> {
+    if let Some(pool) = &*POOL.read().unwrap() {
+        return pool.clone();
+    }
+    let mut current = POOL.write().unwrap();
+    current.get_or_insert_with(|| {
+        Arc::new(ThreadPoolBuilder::new()
+            .num_threads(THREADS.load(Ordering::Relaxed))
+            .thread_name(|i| format!("matrux-{}", i))
+            .build()
+            .expect("failed to build matrux thread pool"))
+    }).clone()
+}
+
+/// Calls `f(first_row, block)` over disjoint blocks of whole rows of `data`, in parallel when `work` is large enough.
+#[cfg(feature = "parallel")]
+pub(crate) fn for_each_row_block<T: Send>(data: &mut [T], row_len: usize, work: usize, f: impl Fn(usize, &mut [T]) + Sync + Send) {
+    let rows = data.len() / row_len.max(1);
+    if work < threshold() || rows < 2 {
+        f(0, data);
+        return;
+    }
+    let pool = pool();
+    let blocks = pool.current_num_threads() * 4;
+    let rows_per_block = rows.div_ceil(blocks).max(threshold().div_ceil(work.div_ceil(rows)).min(rows));
+    pool.install(|| {
+        data.par_chunks_mut(rows_per_block * row_len).enumerate().for_each(|(i, block)| f(i * rows_per_block, block));
+    });
+}
+
+#[cfg(not(feature = "parallel"))]
+pub(crate) fn for_each_row_block<T: Send>(data: &mut [T], _row_len: usize, _work: usize, f: impl Fn(usize, &mut [T]) + Sync + Send) {
+    f(0, data);
+}
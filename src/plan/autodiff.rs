@@ -0,0 +1,543 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{Dim, Matrix, MatrixPlan, Scalar, plan::{fusion, graph::PlanGraph, op::MatrixOp}};
+
+/// Name of the input standing in for the value a derived `derivative` or `backward_plan` is evaluated at, substituted once the gradient is built
+pub(crate) const PLACEHOLDER: &str = "__autodiff_placeholder";
+
+/// Name of the input carrying the basis tangent when a Jacobian is evaluated column by column
+const TANGENT: &str = "__autodiff_tangent";
+
+/// 1 where `plan` is positive, 0 elsewhere
+fn positive<I: Scalar>(plan: MatrixPlan<I>) -> MatrixPlan<I> {
+    let zero = plan.fill(I::default());
+    plan.greater(zero)
+}
+
+/// 1 at the first maximum of each column of `matrix`, the one [`MatrixPlan::argmax`] picks, 0 elsewhere
+fn max_mask<I: Scalar>(matrix: &MatrixPlan<I>) -> MatrixPlan<I> {
+    matrix.clone().argmax().one_hot(&matrix.rows)
+}
+
+/// `plan` placed at `rows` and `cols` of an otherwise zero plan shaped like `like`, `None` covering the whole axis
+fn pad<I: Scalar>(plan: MatrixPlan<I>, like: &MatrixPlan<I>, rows: &Option<Range<usize>>, cols: &Option<Range<usize>>) -> MatrixPlan<I> {
+    if rows.as_ref().is_some_and(Range::is_empty) || cols.as_ref().is_some_and(Range::is_empty) {
+        return like.fill(I::default());
+    }
+    let zero = |rows: Dim, cols: Dim| MatrixPlan::splat(rows, cols, I::default());
+    let non_empty = |part: &MatrixPlan<I>| part.rows != 0 && part.cols != 0;
+    let band = match cols {
+        Some(cols) => {
+            let (total, height) = (like.cols(), plan.rows.clone());
+            let parts = [zero(height.clone(), Dim::Fixed(cols.start)), plan, zero(height, Dim::Fixed(total - cols.end))];
+            MatrixPlan::concat_cols(parts.into_iter().filter(non_empty))
+        },
+        None => plan,
+    };
+    match rows {
+        Some(rows) => {
+            let total = like.rows();
+            let parts = [zero(Dim::Fixed(rows.start), like.cols.clone()), band, zero(Dim::Fixed(total - rows.end), like.cols.clone())];
+            MatrixPlan::concat_rows(parts.into_iter().filter(non_empty))
+        },
+        None => band,
+    }
+}
+
+/// Range of the part at `position` along the axis it was concatenated on, given the sizes of all parts along it.
+/// Concatenating needs fixed sizes along that axis, see [`MatrixPlan::try_concat_rows`].
+fn band<'a>(sizes: impl Iterator<Item=&'a Dim>, position: usize) -> Range<usize> {
+    let sizes = sizes.map(|size| size.fixed().expect("concatenated along a fixed axis")).collect::<Vec<_>>();
+    let start = sizes[..position].iter().sum::<usize>();
+    start..start + sizes[position]
+}
+
+/// Contribution of `adjoint`, the gradient flowing into `plan`, to the gradient of its operand at `position`.
+/// `None` if the operand receives no gradient through this op.
+fn operand_adjoint<I: Scalar>(plan: &MatrixPlan<I>, adjoint: &MatrixPlan<I>, position: usize) -> Option<MatrixPlan<I>> {
+    let adjoint = adjoint.clone();
+    Some(match &*plan.source {
+        MatrixOp::Input { .. } |
+        MatrixOp::Constant { .. } |
+        MatrixOp::Fill { .. } |
+        MatrixOp::Combine { .. } |
+        MatrixOp::Sign { .. } |
+        MatrixOp::Greater { .. } |
+        MatrixOp::Less { .. } |
+        MatrixOp::Equal { .. } => return None,
+        // the mask itself receives no gradient
+        MatrixOp::Where { cond, .. } => {
+            let zero = plan.fill(I::default());
+            match position {
+                0 => return None,
+                1 => cond.clone().select(adjoint, zero),
+                _ => cond.clone().select(zero, adjoint),
+            }
+        },
+        MatrixOp::Output { .. } => adjoint,
+        // `after` only orders evaluation
+        MatrixOp::Recompute { .. } => match position {
+            0 => adjoint,
+            _ => return None,
+        },
+        MatrixOp::Scale { scalar, .. } => adjoint.scale(*scalar),
+        MatrixOp::Max { matrix, scalar } => {
+            // 1 where the input passed through, 0 where it was clamped
+            let above = if *scalar == I::default() {
+                matrix.clone()
+            } else {
+                matrix.clone() - matrix.fill(*scalar)
+            };
+            adjoint.hadamard_mul(positive(above))
+        },
+        MatrixOp::Min { matrix, scalar } => {
+            let below = matrix.fill(*scalar) - matrix;
+            adjoint.hadamard_mul(positive(below))
+        },
+        MatrixOp::Clamp { matrix, min, max } => {
+            let above = matrix.clone() - matrix.fill(*min);
+            let below = matrix.fill(*max) - matrix;
+            adjoint.hadamard_mul(positive(above).hadamard_mul(positive(below)))
+        },
+        MatrixOp::Neg { .. } => -adjoint,
+        MatrixOp::Transpose { .. } => adjoint.transpose(),
+        MatrixOp::Sigmoid { .. } => {
+            let one_minus_sigmoid = plan.fill(I::ONE) - plan;
+            adjoint.hadamard_mul(plan.clone().hadamard_mul(one_minus_sigmoid))
+        },
+        MatrixOp::Exp { .. } => adjoint.hadamard_mul(plan),
+        MatrixOp::Log { matrix } => adjoint.hadamard_div(matrix),
+        MatrixOp::Sqrt { .. } => adjoint.hadamard_div(plan.clone().scale(I::from_f64(2.0))),
+        MatrixOp::Abs { matrix } => adjoint.hadamard_mul(matrix.clone().sign()),
+        MatrixOp::Tanh { .. } => {
+            let one_minus_square = plan.fill(I::ONE) - plan.clone().hadamard_mul(plan);
+            adjoint.hadamard_mul(one_minus_square)
+        },
+        MatrixOp::Pow { matrix, exponent } => adjoint.hadamard_mul(matrix.clone().pow(*exponent - I::ONE).scale(*exponent)),
+        MatrixOp::Reciprocal { .. } => -adjoint.hadamard_mul(plan.clone().hadamard_mul(plan)),
+        MatrixOp::SumRows { matrix } => adjoint.broadcast_rows(&matrix.rows),
+        MatrixOp::SumCols { matrix } => adjoint.broadcast_cols(&matrix.cols),
+        MatrixOp::Mean { matrix } => {
+            let spread = adjoint.broadcast_rows(&matrix.rows).broadcast_cols(&matrix.cols);
+            match (matrix.rows.fixed(), matrix.cols.fixed()) {
+                (Some(rows), Some(cols)) => spread.scale(I::from_f64(1.0 / (rows * cols) as f64)),
+                // the component count is only known at execution
+                _ => spread.hadamard_div(matrix.fill(I::ONE).sum_rows().sum_cols().broadcast_rows(&matrix.rows).broadcast_cols(&matrix.cols)),
+            }
+        },
+        // only the first maximum of a column receives the column's gradient, so ties do not count it several times
+        MatrixOp::MaxReduce { matrix } => adjoint.broadcast_rows(&matrix.rows).hadamard_mul(max_mask(matrix)),
+        MatrixOp::ArgMax { .. } |
+        MatrixOp::OneHot { .. } => return None,
+        MatrixOp::BroadcastRows { .. } => adjoint.sum_rows(),
+        MatrixOp::BroadcastCols { .. } => adjoint.sum_cols(),
+        // the Jacobian of each column is diag(y) - y yᵀ
+        MatrixOp::Softmax { .. } => {
+            let weighted = adjoint.clone().hadamard_mul(plan).sum_rows().broadcast_rows(&plan.rows);
+            plan.clone().hadamard_mul(adjoint - weighted)
+        },
+        // the Jacobian of each column is I - 1 softmax(x)ᵀ
+        MatrixOp::LogSoftmax { .. } => {
+            let total = adjoint.clone().sum_rows().broadcast_rows(&plan.rows);
+            adjoint - plan.clone().exp().hadamard_mul(total)
+        },
+        MatrixOp::Reshape { matrix } => adjoint.reshape(&matrix.rows, &matrix.cols),
+        MatrixOp::Slice { matrix, rows, cols } => pad(adjoint, matrix, rows, cols),
+        // only the concatenated axis is sliced, the other one may be symbolic
+        MatrixOp::ConcatRows { inner } => adjoint.slice_rows(band(inner.iter().map(|part| &part.rows), position)),
+        MatrixOp::ConcatCols { inner } => adjoint.slice_cols(band(inner.iter().map(|part| &part.cols), position)),
+        // with y = op(l) op(r), op(l) receives a op(r)ᵀ and op(r) receives op(l)ᵀ a, transposed back for flagged operands
+        MatrixOp::Mul { left, right, transposed: (left_transposed, right_transposed) } => {
+            let product = |left: &MatrixPlan<I>, right: &MatrixPlan<I>, transposed| left.clone().try_mul_transposed(right, transposed).expect("adjoint shapes match");
+            match (position, left_transposed, right_transposed) {
+                (0, false, _) => product(&adjoint, right, (false, !right_transposed)),
+                (0, true, _) => product(right, &adjoint, (*right_transposed, true)),
+                (_, _, false) => product(left, &adjoint, (!left_transposed, false)),
+                (_, _, true) => product(&adjoint, left, (true, *left_transposed)),
+            }
+        },
+        MatrixOp::HadamardMul { left, right } => match position {
+            0 => adjoint.hadamard_mul(right),
+            _ => adjoint.hadamard_mul(left),
+        },
+        MatrixOp::Add { .. } => adjoint,
+        MatrixOp::Sub { .. } => match position {
+            0 => adjoint,
+            _ => -adjoint,
+        },
+        MatrixOp::Div { right, .. } => match position {
+            0 => adjoint.hadamard_div(right),
+            _ => -adjoint.hadamard_mul(plan).hadamard_div(right),
+        },
+        MatrixOp::Custom { op, inputs } => {
+            let adjoints = op.backward_plan(inputs, plan, &adjoint).unwrap_or_else(|| panic!("custom op '{}' has no backward plan", op.name()));
+            assert_eq!(adjoints.len(), inputs.len(), "custom op '{}' returned the wrong number of gradients", op.name());
+            return adjoints.into_iter().nth(position).flatten();
+        },
+        MatrixOp::Fused { .. } |
+        MatrixOp::FusedMul { .. } => unreachable!("fused ops are expanded before differentiation"),
+    })
+}
+
+fn accumulate<I: Scalar>(total: &mut Option<MatrixPlan<I>>, contribution: MatrixPlan<I>) {
+    *total = Some(match total.take() {
+        Some(total) => total + contribution,
+        None => contribution,
+    });
+}
+
+/// Builds the gradient plans of `plan` with respect to each named input, for an output gradient of `seed`.
+/// Only nodes depending on one of the named inputs are propagated into.
+pub(crate) fn gradients<I: Scalar>(plan: &MatrixPlan<I>, seed: MatrixPlan<I>, wrt: &[&str]) -> Vec<MatrixPlan<I>> {
+    assert!(!matches!(&*plan.source, MatrixOp::Combine { .. }), "cannot differentiate merged outputs, differentiate one of them");
+    gradients_of_sum(&[(plan.clone(), seed)], wrt, &input_shapes(plan, wrt))
+}
+
+/// Shapes of the named inputs of `plan`, in `names` order
+fn input_shapes<I: Scalar>(plan: &MatrixPlan<I>, names: &[&str]) -> Vec<(Dim, Dim)> {
+    let signature = plan.signature();
+    names.iter().map(|name| signature.input(name).unwrap_or_else(|| panic!("no input named '{}'", name))).collect()
+}
+
+/// Gradients of the sum of several seeded plans, which share one backward pass.
+/// Inputs the roots do not read get zero gradients of their shape in `shapes`.
+fn gradients_of_sum<I: Scalar>(roots: &[(MatrixPlan<I>, MatrixPlan<I>)], wrt: &[&str], shapes: &[(Dim, Dim)]) -> Vec<MatrixPlan<I>> {
+    for (plan, seed) in roots {
+        assert_eq!(plan.dims(), seed.dims(), "seed must have the shape of the differentiated plan");
+    }
+    let plan = fusion::unfuse(&MatrixPlan::merge_outputs(roots.iter().map(|(plan, _)| plan.clone())));
+    let graph = PlanGraph::new(&plan);
+    let nodes = &graph.nodes;
+    let mut relevant = vec![false; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        relevant[index] = match &*node.plan.source {
+            MatrixOp::Input { name } => wrt.contains(&&**name),
+            _ => node.operands.iter().any(|operand| relevant[*operand]),
+        };
+    }
+
+    let mut adjoints: Vec<Option<MatrixPlan<I>>> = vec![None; nodes.len()];
+    for (root, (_, seed)) in nodes[graph.root].operands.iter().zip(roots) {
+        accumulate(&mut adjoints[*root], seed.clone());
+    }
+    let mut out: Vec<Option<MatrixPlan<I>>> = vec![None; wrt.len()];
+    for (index, node) in nodes.iter().enumerate().rev() {
+        let adjoint = match adjoints[index].take() {
+            Some(adjoint) if relevant[index] => adjoint,
+            _ => continue,
+        };
+        if let MatrixOp::Input { name } = &*node.plan.source {
+            let position = wrt.iter().position(|input| input == name).expect("relevant input");
+            accumulate(&mut out[position], adjoint);
+            continue;
+        }
+        for (position, operand) in node.operands.iter().enumerate() {
+            if !relevant[*operand] {
+                continue;
+            }
+            if let Some(contribution) = operand_adjoint(node.plan, &adjoint, position) {
+                accumulate(&mut adjoints[*operand], contribution);
+            }
+        }
+    }
+
+    out.into_iter().zip(shapes).map(|(gradient, (rows, cols))| {
+        gradient.unwrap_or_else(|| MatrixPlan::splat(rows.clone(), cols.clone(), I::default()))
+    }).collect()
+}
+
+/// Hessian of the sum of all components of `plan` times the vector given by `vectors`, one plan per named input.
+/// Reverse over reverse: the gradient plans are differentiated again, seeded by the vector.
+pub(crate) fn hessian_vector_product<I: Scalar>(plan: &MatrixPlan<I>, vectors: &[(&str, MatrixPlan<I>)]) -> Vec<MatrixPlan<I>> {
+    let wrt = vectors.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    // the gradients may no longer read an input the plan is linear in, whose Hessian products are then zero
+    let shapes = input_shapes(plan, &wrt);
+    let seed = plan.fill(I::ONE);
+    let gradients = gradients(plan, seed, &wrt);
+    let roots = gradients.into_iter().zip(vectors).map(|(gradient, (_, vector))| (gradient, vector.clone())).collect::<Vec<_>>();
+    gradients_of_sum(&roots, &wrt, &shapes)
+}
+
+/// Directional derivative of `plan` along `tangents`, a plan per named input (forward mode).
+/// Inputs without a tangent are held constant.
+pub(crate) fn jacobian_vector_product<I: Scalar>(plan: &MatrixPlan<I>, tangents: &[(&str, MatrixPlan<I>)]) -> MatrixPlan<I> {
+    assert!(!matches!(&*plan.source, MatrixOp::Combine { .. }), "cannot differentiate merged outputs, differentiate one of them");
+    let names = tangents.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    for ((name, tangent), (rows, cols)) in tangents.iter().zip(input_shapes(plan, &names)) {
+        assert_eq!((rows, cols), tangent.dims(), "bad tangent shape for input '{}'", name);
+    }
+
+    let plan = fusion::unfuse(plan);
+    let graph = PlanGraph::new(&plan);
+    // `None` for nodes whose tangent is zero
+    let mut derivatives: Vec<Option<MatrixPlan<I>>> = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let y = node.plan;
+        let operand = |position: usize| node.plan.source.operands()[position].clone();
+        let tangent = |position: usize| derivatives[node.operands[position]].clone();
+        let derivative = match &*y.source {
+            MatrixOp::Input { name } => tangents.iter().find(|(tangent, _)| tangent == name).map(|(_, tangent)| tangent.clone()),
+            MatrixOp::Constant { .. } |
+            MatrixOp::Fill { .. } |
+            MatrixOp::Combine { .. } |
+            MatrixOp::Sign { .. } |
+            MatrixOp::Greater { .. } |
+            MatrixOp::Less { .. } |
+            MatrixOp::Equal { .. } => None,
+            MatrixOp::Where { cond, .. } => match (tangent(1), tangent(2)) {
+                (None, None) => None,
+                (then, otherwise) => {
+                    let zero = || y.fill(I::default());
+                    Some(cond.clone().select(then.unwrap_or_else(zero), otherwise.unwrap_or_else(zero)))
+                },
+            },
+            // tangents share the transposition of the operands they stand for
+            MatrixOp::Mul { .. } => match (tangent(0), tangent(1)) {
+                (Some(left), Some(right)) => Some(y.with_operands(vec![left, operand(1)]) + y.with_operands(vec![operand(0), right])),
+                (Some(left), None) => Some(y.with_operands(vec![left, operand(1)])),
+                (None, Some(right)) => Some(y.with_operands(vec![operand(0), right])),
+                (None, None) => None,
+            },
+            MatrixOp::HadamardMul { .. } => match (tangent(0), tangent(1)) {
+                (Some(left), Some(right)) => Some(left.hadamard_mul(operand(1)) + operand(0).hadamard_mul(right)),
+                (Some(left), None) => Some(left.hadamard_mul(operand(1))),
+                (None, Some(right)) => Some(operand(0).hadamard_mul(right)),
+                (None, None) => None,
+            },
+            // (tl - y ⊙ tr) / r
+            MatrixOp::Div { .. } => match (tangent(0), tangent(1)) {
+                (Some(left), Some(right)) => Some((left - y.clone().hadamard_mul(right)).hadamard_div(operand(1))),
+                (Some(left), None) => Some(left.hadamard_div(operand(1))),
+                (None, Some(right)) => Some(-y.clone().hadamard_mul(right).hadamard_div(operand(1))),
+                (None, None) => None,
+            },
+            MatrixOp::Add { .. } | MatrixOp::Sub { .. } => match (tangent(0), tangent(1)) {
+                (Some(left), Some(right)) => Some(y.with_operands(vec![left, right])),
+                (Some(left), None) => Some(left),
+                (None, Some(right)) => Some(match &*y.source {
+                    MatrixOp::Sub { .. } => -right,
+                    _ => right,
+                }),
+                (None, None) => None,
+            },
+            MatrixOp::ArgMax { .. } | MatrixOp::OneHot { .. } => None,
+            MatrixOp::LogSoftmax { .. } => tangent(0).map(|tangent| {
+                let weighted = y.clone().exp().hadamard_mul(&tangent).sum_rows().broadcast_rows(&y.rows);
+                tangent - weighted
+            }),
+            MatrixOp::Custom { op, inputs } => {
+                let tangents = (0..inputs.len()).map(tangent).collect::<Vec<_>>();
+                tangents.iter().any(Option::is_some).then(|| {
+                    op.tangent_plan(inputs, y, &tangents).unwrap_or_else(|| panic!("custom op '{}' has no tangent plan", op.name()))
+                })
+            },
+            MatrixOp::ConcatRows { inner } | MatrixOp::ConcatCols { inner } => {
+                let tangents = (0..inner.len()).map(tangent).collect::<Vec<_>>();
+                tangents.iter().any(Option::is_some).then(|| {
+                    let tangents = tangents.into_iter().zip(inner)
+                        .map(|(tangent, part)| tangent.unwrap_or_else(|| part.fill(I::default())))
+                        .collect();
+                    y.with_operands(tangents)
+                })
+            },
+            MatrixOp::MaxReduce { matrix } => tangent(0).map(|tangent| tangent.hadamard_mul(max_mask(matrix)).sum_rows()),
+            // linear in their operand
+            MatrixOp::SumRows { .. } |
+            MatrixOp::SumCols { .. } |
+            MatrixOp::Mean { .. } |
+            MatrixOp::BroadcastRows { .. } |
+            MatrixOp::BroadcastCols { .. } |
+            MatrixOp::Reshape { .. } |
+            MatrixOp::Slice { .. } => tangent(0).map(|tangent| y.with_operands(vec![tangent])),
+            // other single operand ops (transposes, element-wise maps and softmax) have symmetric Jacobians, the same rule applies to the tangent as to an adjoint
+            _ => tangent(0).map(|tangent| operand_adjoint(y, &tangent, 0).expect("differentiable op")),
+        };
+        derivatives.push(derivative);
+    }
+    derivatives.swap_remove(graph.root).unwrap_or_else(|| plan.fill(I::default()))
+}
+
+/// Jacobian of the components of `plan` (row-major) with respect to the components of the input `wrt`, evaluated at `inputs`.
+/// Built from one forward mode plan, executed once per component of `wrt`.
+pub(crate) fn jacobian_cpu<I: Scalar>(plan: &MatrixPlan<I>, wrt: &str, inputs: &HashMap<&str, &Matrix<I>>) -> Matrix<I> {
+    let (rows, cols) = input_shapes(plan, &[wrt]).remove(0);
+    let tangent = MatrixPlan::input(rows, cols, TANGENT);
+    let (rows, cols) = (tangent.rows(), tangent.cols());
+    let derivative = jacobian_vector_product(plan, &[(wrt, tangent)]).compile();
+
+    let mut jacobian = Matrix::new(plan.rows() * plan.cols(), rows * cols);
+    let mut basis = Matrix::new(rows, cols);
+    for column in 0..rows * cols {
+        basis[column / cols][column % cols] = I::ONE;
+        let bound = derivative.inputs().map(|(name, _)| match name {
+            TANGENT => &basis,
+            name => *inputs.get(name).unwrap_or_else(|| panic!("missing input for '{}'", name)),
+        }).collect::<Vec<_>>();
+        let (derivative, _) = derivative.execute_slots(&bound);
+        let derivative: &[I] = derivative.as_ref();
+        for (row, value) in derivative.iter().enumerate() {
+            jacobian[row][column] = *value;
+        }
+        basis[column / cols][column % cols] = I::default();
+    }
+    jacobian
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{Matrix, MatrixPlan, plan::testing::{Cube, assert_bitwise_eq, assert_close, assert_gradients, bind, matrix, sample}};
+
+    fn x() -> MatrixPlan<f64> {
+        MatrixPlan::input(3, 4, "x")
+    }
+
+    fn y() -> MatrixPlan<f64> {
+        MatrixPlan::input(3, 4, "y")
+    }
+
+    /// `plan` weighted component-wise, so the gradient of its sum does not vanish for ops like softmax whose columns sum to a constant
+    fn weighted(plan: MatrixPlan<f64>) -> MatrixPlan<f64> {
+        let weights = MatrixPlan::constant(sample(plan.rows(), plan.cols(), 99));
+        plan.hadamard_mul(weights)
+    }
+
+    fn assert_matches_finite_differences(plan: MatrixPlan<f64>) {
+        let inputs = bind(&[("x", &sample(3, 4, 1)), ("y", &sample(3, 4, 2))]);
+        assert_gradients(&weighted(plan), &["x", "y"], &inputs);
+    }
+
+    #[test]
+    fn products() {
+        for transposed in [(false, false), (false, true), (true, false), (true, true)] {
+            // read as 3x4 and 4x3 whatever the flags
+            let left = if transposed.0 { x().transpose() } else { x() };
+            let right = if transposed.1 { y() } else { y().transpose() };
+            let product = left.try_mul_transposed(&right, transposed).unwrap();
+            assert_matches_finite_differences(product.sigmoid());
+        }
+        assert_matches_finite_differences((x().transpose() * x()).tanh() * y().transpose());
+    }
+
+    #[test]
+    fn div() {
+        let denominator = || y().abs() + y().fill(1.0);
+        assert_matches_finite_differences(x().hadamard_div(denominator()));
+        assert_matches_finite_differences(denominator().reciprocal().hadamard_mul(x()));
+    }
+
+    #[test]
+    fn pow() {
+        let base = || x().abs() + y().exp();
+        assert_matches_finite_differences(base().pow(2.5));
+        assert_matches_finite_differences(base().pow(-1.5).sqrt().ln());
+    }
+
+    #[test]
+    fn softmax() {
+        assert_matches_finite_differences((x() + y().scale(2.0)).softmax());
+        assert_matches_finite_differences(x().hadamard_mul(y()).log_softmax());
+    }
+
+    #[test]
+    fn reductions() {
+        assert_matches_finite_differences(x().exp().sum_rows().broadcast_rows(3).hadamard_mul(y()));
+        assert_matches_finite_differences(x().hadamard_mul(y()).sum_cols().tanh().broadcast_cols(4));
+        assert_matches_finite_differences(x().pow(2.0).mean().broadcast_rows(3).broadcast_cols(4).hadamard_mul(y()));
+        assert_matches_finite_differences((x() + y()).max_reduce().exp().broadcast_rows(3));
+    }
+
+    #[test]
+    fn broadcasts() {
+        let row = MatrixPlan::input(1, 4, "row");
+        let col = MatrixPlan::input(3, 1, "col");
+        let plan = weighted((row.broadcast_rows(3) + x()).hadamard_mul(col.broadcast_cols(4).tanh()));
+        let inputs = bind(&[("x", &sample(3, 4, 1)), ("row", &sample(1, 4, 2)), ("col", &sample(3, 1, 3))]);
+        assert_gradients(&plan, &["row", "col"], &inputs);
+    }
+
+    #[test]
+    fn mean_of_symbolic_batch() {
+        let batch = MatrixPlan::input(3, "batch", "x");
+        let plan = batch.clone().exp().mean() + batch.sum_cols().sum_rows().scale(0.5);
+        for cols in [2, 5] {
+            assert_gradients(&plan, &["x"], &bind(&[("x", &sample(3, cols, 1))]));
+        }
+    }
+
+    #[test]
+    fn max_reduce_of_ties_flows_into_the_first_maximum() {
+        let columns = MatrixPlan::input(2, 3, "x");
+        let inputs = bind(&[("x", &matrix(2, 3, &[1.0, 2.0, -1.0, 1.0, 0.0, -1.0]))]);
+        let gradient = columns.clone().max_reduce().gradients(&["x"]).remove(0);
+        assert_bitwise_eq(&gradient.execute_cpu(&inputs).0, &matrix(2, 3, &[1.0, 1.0, 1.0, 0.0, 0.0, 0.0]));
+        // the tangent of each column is the one of its first maximum
+        let tangent = columns.max_reduce().jvp(&[("x", MatrixPlan::constant(matrix(2, 3, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0])))]);
+        assert_bitwise_eq(&tangent.execute_cpu(&inputs).0, &matrix(1, 3, &[1.0, 2.0, 3.0]));
+    }
+
+    #[test]
+    fn slice() {
+        assert_matches_finite_differences(x().slice(1..3, 1..3).exp().reshape(1, 4).broadcast_rows(3) + y());
+        assert_matches_finite_differences(x().slice_cols(2..4).hadamard_mul(y().slice_cols(0..2)).reshape(2, 3));
+    }
+
+    #[test]
+    fn concat() {
+        let parts = x().split_rows(&[1, 2]);
+        let rows = MatrixPlan::concat_rows([parts[1].clone(), y().slice_rows(0..1).tanh()]);
+        assert_matches_finite_differences(rows.hadamard_mul(x()));
+        let cols = MatrixPlan::concat_cols([x().slice_cols(0..1), y().slice_cols(1..4).exp()]);
+        assert_matches_finite_differences(cols.hadamard_mul(y()));
+    }
+
+    #[test]
+    fn concat_of_symbolic_batches() {
+        let (a, b) = (MatrixPlan::input(2, "batch", "a"), MatrixPlan::input(3, "batch", "b"));
+        let rows = MatrixPlan::concat_rows([a.clone(), b.clone().tanh()]).exp();
+        let cols = MatrixPlan::concat_cols([a.transpose(), b.transpose()]).sigmoid();
+        for batch in [1, 4] {
+            let inputs = bind(&[("a", &sample(2, batch, 1)), ("b", &sample(3, batch, 2))]);
+            assert_gradients(&rows, &["a", "b"], &inputs);
+            assert_gradients(&cols, &["a", "b"], &inputs);
+        }
+    }
+
+    #[test]
+    fn select() {
+        let mask = x().greater(y());
+        assert_matches_finite_differences(mask.select(x().exp(), y().hadamard_mul(x())));
+        assert_matches_finite_differences(x().less(y()).select(x().max(0.0), y().min(0.25).clamp(-0.5, 0.5)));
+    }
+
+    #[test]
+    fn custom() {
+        let cube = MatrixPlan::custom(Arc::new(Cube), [x().hadamard_mul(y())]);
+        assert_matches_finite_differences(MatrixPlan::custom(Arc::new(Cube), [cube.scale(0.5) + x()]));
+    }
+
+    #[test]
+    fn hessian_vector_product_of_linear_input_is_zero() {
+        // the gradient with respect to `x` is `y`, which no longer reads `x`
+        let hvp = x().hadamard_mul(y()).hvp(&[("x", MatrixPlan::constant(sample(3, 4, 3)))]);
+        assert_eq!(hvp.len(), 1);
+        let inputs = bind(&[("x", &sample(3, 4, 1)), ("y", &sample(3, 4, 2))]);
+        assert_bitwise_eq(&hvp[0].execute_cpu(&inputs).0, &Matrix::new(3, 4));
+    }
+
+    #[test]
+    fn hessian_vector_product_matches_finite_differences_of_gradients() {
+        const STEP: f64 = 1e-6;
+        let plan = weighted(x().tanh().hadamard_mul(y()) + y().exp());
+        let (vx, vy) = (sample(3, 4, 5), sample(3, 4, 6));
+        let hvp = plan.hvp(&[("x", MatrixPlan::constant(vx.clone())), ("y", MatrixPlan::constant(vy.clone()))]);
+        let gradients = plan.gradients(&["x", "y"]);
+        let (x, y) = (sample(3, 4, 1), sample(3, 4, 2));
+        let at = |step: f64| bind(&[("x", &(x.clone() + vx.clone().scale(step))), ("y", &(y.clone() + vy.clone().scale(step)))]);
+        for (hvp, gradient) in hvp.iter().zip(&gradients) {
+            let difference = (gradient.execute_cpu(&at(STEP)).0 - gradient.execute_cpu(&at(-STEP)).0).scale(0.5 / STEP);
+            assert_close(&hvp.execute_cpu(&at(0.0)).0, &difference, 1e-6);
+        }
+    }
+}
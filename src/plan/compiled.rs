@@ -0,0 +1,387 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{MatrixPlan, ExecuteResult, Scalar, Matrix, Dim, error, plan::{Bindings, op::MatrixOp, products, graph::PlanGraph, cpu_eval, memory::{MemoryPlan, Usage}}};
+
+/// Where an instruction operand or a plan result is read from
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    Input(usize),
+    Constant(usize),
+    /// result of the instruction at this index
+    Instruction(usize),
+}
+
+#[derive(Clone, Debug)]
+struct Instruction<I: Scalar> {
+    plan: MatrixPlan<I>,
+    operands: Vec<Value>,
+    /// buffer the result is written into
+    slot: usize,
+    /// the result overwrites the first operand's buffer
+    in_place: bool,
+}
+
+/// Memory use of a compiled plan's intermediate results
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// largest total size of intermediate results alive at the same time
+    pub peak_bytes: usize,
+    /// size of the buffer pool the intermediate results are assigned to
+    pub pool_bytes: usize,
+    /// size of all intermediate results if each had its own buffer
+    pub unplanned_bytes: usize,
+    pub buffers: usize,
+    /// instructions computed in place over a dying operand
+    pub in_place: usize,
+}
+
+/// Passes applied when compiling a [`MatrixPlan`]
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// reorder chains of products to their cheapest association, see [`MatrixPlan::reassociate_products`]
+    pub reassociate: bool,
+    /// merge structurally identical subgraphs, see [`MatrixPlan::eliminate_common_subexpressions`]
+    pub cse: bool,
+    /// let products read transposed operands through strides instead of materializing the transposes
+    pub transposes: bool,
+    /// collapse element-wise runs and product epilogues into fused kernels, see [`MatrixPlan::fuse`]
+    pub fusion: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            reassociate: true,
+            cse: true,
+            transposes: true,
+            fusion: true,
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Turning reassociation off keeps products in the order they were built, and their results bit for bit
+    pub fn reassociate(mut self, reassociate: bool) -> Self {
+        self.reassociate = reassociate;
+        self
+    }
+
+    pub fn cse(mut self, cse: bool) -> Self {
+        self.cse = cse;
+        self
+    }
+
+    pub fn transposes(mut self, transposes: bool) -> Self {
+        self.transposes = transposes;
+        self
+    }
+
+    /// Turning fusion off keeps one instruction per op, which is easier to follow when debugging
+    pub fn fusion(mut self, fusion: bool) -> Self {
+        self.fusion = fusion;
+        self
+    }
+}
+
+/// A [`MatrixPlan`] lowered once into a topologically ordered instruction tape.
+/// Inputs are resolved to slots and operands to buffer indices at compile time, so repeated executions skip walking and hashing the plan.
+/// Intermediate results share a pool of buffers assigned from their liveness, and element-wise ops overwrite operands that die with them.
+#[derive(Debug)]
+pub struct CompiledPlan<I: Scalar> {
+    instructions: Vec<Instruction<I>>,
+    inputs: Vec<(String, (usize, usize))>,
+    constants: Vec<Matrix<I>>,
+    outputs: Vec<(String, Value)>,
+    root: Value,
+    memory: MemoryReport,
+    /// buffers kept between executions, so their allocations are reused
+    workspace: Mutex<Vec<Matrix<I>>>,
+}
+
+impl<I: Scalar> Clone for CompiledPlan<I> {
+    fn clone(&self) -> Self {
+        Self {
+            instructions: self.instructions.clone(),
+            inputs: self.inputs.clone(),
+            constants: self.constants.clone(),
+            outputs: self.outputs.clone(),
+            root: self.root,
+            memory: self.memory,
+            workspace: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl<I: Scalar> CompiledPlan<I> {
+    pub fn new(plan: &MatrixPlan<I>, options: &CompileOptions) -> Self {
+        let symbols = plan.symbols();
+        assert!(symbols.is_empty(), "cannot compile a plan with symbolic dimensions {:?}, resolve them first", symbols);
+        let mut plan = plan.clone();
+        if options.reassociate {
+            plan = plan.reassociate_products();
+        }
+        if options.cse {
+            plan = plan.eliminate_common_subexpressions();
+        }
+        if options.transposes {
+            plan = products::fold_transposes(&plan);
+        }
+        if options.fusion {
+            plan = plan.fuse();
+        }
+        let plan = &plan;
+        let graph = PlanGraph::new(plan);
+        let mut values = Vec::with_capacity(graph.nodes.len());
+        let mut out = Self {
+            instructions: vec![],
+            inputs: vec![],
+            constants: vec![],
+            outputs: vec![],
+            root: Value::Constant(0),
+            memory: MemoryReport {
+                peak_bytes: 0,
+                pool_bytes: 0,
+                unplanned_bytes: 0,
+                buffers: 0,
+                in_place: 0,
+            },
+            workspace: Mutex::new(vec![]),
+        };
+
+        for node in &graph.nodes {
+            let value = match &*node.plan.source {
+                MatrixOp::Input { name } => {
+                    match out.inputs.iter().position(|(input, _)| input == name) {
+                        Some(slot) => Value::Input(slot),
+                        None => {
+                            out.inputs.push((name.clone(), (node.plan.rows(), node.plan.cols())));
+                            Value::Input(out.inputs.len() - 1)
+                        },
+                    }
+                },
+                MatrixOp::Constant { matrix } => {
+                    out.constants.push(matrix.clone());
+                    Value::Constant(out.constants.len() - 1)
+                },
+                MatrixOp::Output { name, .. } => {
+                    let value = values[node.operands[0]];
+                    out.outputs.push((name.clone(), value));
+                    value
+                },
+                // only orders evaluation, its readers read the value it stands for
+                MatrixOp::Recompute { .. } => values[node.operands[0]],
+                MatrixOp::Combine { .. } => {
+                    // carries no data, evaluates to an empty matrix
+                    out.constants.push(Matrix::default());
+                    Value::Constant(out.constants.len() - 1)
+                },
+                _ => {
+                    out.instructions.push(Instruction {
+                        plan: node.plan.clone(),
+                        operands: node.operands.iter().map(|operand| values[*operand]).collect(),
+                        slot: 0,
+                        in_place: false,
+                    });
+                    Value::Instruction(out.instructions.len() - 1)
+                },
+            };
+            values.push(value);
+        }
+        out.root = values[graph.root];
+        out.plan_memory();
+        out
+    }
+
+    fn plan_memory(&mut self) {
+        let mut usages = self.instructions.iter().map(|instruction| Usage {
+            size: instruction.plan.rows() * instruction.plan.cols(),
+            operands: instruction.operands.iter().filter_map(|operand| match operand {
+                Value::Instruction(index) => Some(*index),
+                _ => None,
+            }).collect(),
+            // only a first operand that is an intermediate result can be overwritten
+            elementwise: instruction.plan.source.is_elementwise() && matches!(instruction.operands.first(), Some(Value::Instruction(_))),
+            live_out: false,
+        }).collect::<Vec<_>>();
+        for value in self.outputs.iter().map(|(_, value)| value).chain(std::iter::once(&self.root)) {
+            if let Value::Instruction(index) = value {
+                usages[*index].live_out = true;
+            }
+        }
+
+        let plan = MemoryPlan::new(&usages);
+        for (index, instruction) in self.instructions.iter_mut().enumerate() {
+            instruction.slot = plan.slots[index];
+            instruction.in_place = plan.in_place[index];
+        }
+        let scalar = std::mem::size_of::<I>();
+        self.memory = MemoryReport {
+            peak_bytes: plan.peak * scalar,
+            pool_bytes: plan.slot_sizes.iter().sum::<usize>() * scalar,
+            unplanned_bytes: usages.iter().map(|usage| usage.size).sum::<usize>() * scalar,
+            buffers: plan.slot_sizes.len(),
+            in_place: self.instructions.iter().filter(|instruction| instruction.in_place).count(),
+        };
+    }
+
+    /// Named inputs in slot order, with their expected shapes
+    pub fn inputs(&self) -> impl Iterator<Item=(&str, (usize, usize))> + '_ {
+        self.inputs.iter().map(|(name, shape)| (&**name, *shape))
+    }
+
+    pub fn input_slot(&self, name: &str) -> Option<usize> {
+        self.inputs.iter().position(|(input, _)| input == name)
+    }
+
+    pub fn memory(&self) -> MemoryReport {
+        self.memory
+    }
+
+    pub fn execute(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let slots = self.inputs.iter()
+            .map(|(name, _)| *inputs.get(&**name).unwrap_or_else(|| panic!("missing input for '{}'", name)))
+            .collect::<Vec<_>>();
+        self.execute_slots(&slots)
+    }
+
+    /// Same as [`CompiledPlan::execute`], but fails instead of panicking on missing or mis-shaped inputs,
+    /// and if the result or a named output contains a NaN
+    pub fn try_execute(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> ExecuteResult<I> {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let declared = self.inputs().map(|(name, (rows, cols))| (name, (Dim::Fixed(rows), Dim::Fixed(cols))));
+        error::check_inputs(declared, &Bindings::new(), &inputs)?;
+        let slots = self.inputs.iter().map(|(name, _)| inputs[&**name]).collect::<Vec<_>>();
+        error::check_nan(self.execute_slots(&slots))
+    }
+
+    /// Executes with inputs bound by slot, see [`CompiledPlan::input_slot`]
+    pub fn execute_slots(&self, inputs: &[&Matrix<I>]) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
+        assert_eq!(inputs.len(), self.inputs.len());
+        for ((name, (rows, cols)), input) in self.inputs.iter().zip(inputs) {
+            assert_eq!((*rows, *cols), (input.rows(), input.cols()), "bad shape for input '{}'", name);
+        }
+
+        // concurrent executions of the same plan fall back to fresh buffers
+        let mut buffers = self.workspace.try_lock().map(|mut workspace| std::mem::take(&mut *workspace)).unwrap_or_default();
+        buffers.resize_with(self.memory.buffers, Matrix::default);
+
+        let no_inputs = HashMap::new();
+        for instruction in &self.instructions {
+            let mut target = std::mem::take(&mut buffers[instruction.slot]);
+            let read = |value: Value| self.read(inputs, &buffers, value);
+            let output = match &*instruction.plan.source {
+                MatrixOp::Fused { kernel, .. } if !instruction.in_place => {
+                    let arguments = instruction.operands.iter().map(|operand| read(*operand)).collect::<Vec<_>>();
+                    kernel.evaluate_into(&arguments, &mut target);
+                    target
+                },
+                op if op.is_elementwise() => {
+                    if !instruction.in_place {
+                        target.assign(read(instruction.operands[0]));
+                    }
+                    let rest = instruction.operands[1..].iter().map(|operand| read(*operand)).collect::<Vec<_>>();
+                    cpu_eval::evaluate_elementwise(&instruction.plan, target, &rest)
+                },
+                MatrixOp::Mul { transposed, .. } => {
+                    read(instruction.operands[0]).matmul_transposed_into(read(instruction.operands[1]), *transposed, &mut target);
+                    target
+                },
+                MatrixOp::Transpose { .. } => {
+                    read(instruction.operands[0]).transpose_into(&mut target);
+                    target
+                },
+                MatrixOp::FusedMul { transposed, kernel, .. } => {
+                    read(instruction.operands[0]).matmul_transposed_into(read(instruction.operands[1]), *transposed, &mut target);
+                    let rest = instruction.operands[2..].iter().map(|operand| read(*operand)).collect::<Vec<_>>();
+                    kernel.evaluate_in_place(&mut target, &rest);
+                    target
+                },
+                _ => {
+                    let operands = instruction.operands.iter().map(|operand| read(*operand).clone()).collect();
+                    cpu_eval::evaluate(&instruction.plan, &no_inputs, operands)
+                },
+            };
+            assert_eq!(instruction.plan.rows(), output.rows());
+            assert_eq!(instruction.plan.cols(), output.cols());
+            buffers[instruction.slot] = output;
+        }
+
+        let outputs = self.outputs.iter().map(|(name, value)| (name.clone(), self.read(inputs, &buffers, *value).clone())).collect();
+        let root = self.read(inputs, &buffers, self.root).clone();
+        if let Ok(mut workspace) = self.workspace.try_lock() {
+            *workspace = buffers;
+        }
+        (root, outputs)
+    }
+
+    fn read<'a>(&'a self, inputs: &[&'a Matrix<I>], buffers: &'a [Matrix<I>], value: Value) -> &'a Matrix<I> {
+        match value {
+            Value::Input(slot) => inputs[slot],
+            Value::Constant(index) => &self.constants[index],
+            Value::Instruction(index) => &buffers[self.instructions[index].slot],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MatrixPlan, plan::testing::{assert_bitwise_eq, assert_same_execution, bind, sample}};
+
+    use super::CompileOptions;
+
+    /// A layer with a named output, a reused input and both element-wise and product instructions
+    fn layer() -> MatrixPlan<f64> {
+        let x = MatrixPlan::input(4, 3, "x");
+        let w = MatrixPlan::input(3, 5, "w");
+        let b = MatrixPlan::input(1, 5, "b");
+        let z = (x.clone() * w + b.broadcast_rows(4)).output("z");
+        let y = z.clone().tanh().hadamard_mul(z.clone().sigmoid()).scale(0.5);
+        x.transpose() * (y - z.exp().max(1.0))
+    }
+
+    #[test]
+    fn repeated_executions_match_cpu_evaluation() {
+        let plan = layer();
+        for options in [CompileOptions::default().reassociate(false), CompileOptions::default().reassociate(false).cse(false).transposes(false).fusion(false)] {
+            let compiled = plan.compile_with(&options);
+            // the workspace of one execution is reused by the next
+            for seed in 0..3 {
+                let (x, w, b) = (sample(4, 3, seed), sample(3, 5, seed + 10), sample(1, 5, seed + 20));
+                assert_same_execution(&plan, &compiled, &bind(&[("x", &x), ("w", &w), ("b", &b)]));
+            }
+            assert_same_execution(&plan, &compiled.clone(), &bind(&[("x", &sample(4, 3, 7)), ("w", &sample(3, 5, 8)), ("b", &sample(1, 5, 9))]));
+        }
+    }
+
+    #[test]
+    fn inputs_are_slotted_once_each() {
+        let compiled = layer().compile();
+        let inputs = compiled.inputs().collect::<Vec<_>>();
+        assert_eq!(inputs.len(), 3);
+        for (slot, (name, _)) in inputs.iter().enumerate() {
+            assert_eq!(compiled.input_slot(name), Some(slot));
+        }
+        assert!(inputs.contains(&("x", (4, 3))));
+        assert!(inputs.contains(&("w", (3, 5))));
+        assert!(inputs.contains(&("b", (1, 5))));
+        assert_eq!(compiled.input_slot("y"), None);
+
+        let (x, w, b) = (sample(4, 3, 1), sample(3, 5, 2), sample(1, 5, 3));
+        let by_name = bind(&[("x", &x), ("w", &w), ("b", &b)]);
+        let slots = inputs.iter().map(|(name, _)| &by_name[*name]).collect::<Vec<_>>();
+        let (root, outputs) = compiled.execute_slots(&slots);
+        let (expected, expected_outputs) = compiled.execute(&by_name);
+        assert_bitwise_eq(&root, &expected);
+        assert_bitwise_eq(&outputs["z"], &expected_outputs["z"]);
+    }
+
+    #[test]
+    fn try_execute_rejects_missing_and_mis_shaped_inputs() {
+        let compiled = layer().compile();
+        let (x, w, b) = (sample(4, 3, 1), sample(3, 5, 2), sample(1, 5, 3));
+        assert!(compiled.try_execute(&bind(&[("x", &x), ("w", &w)])).is_err());
+        assert!(compiled.try_execute(&bind(&[("x", &x), ("w", &w), ("b", &sample(1, 4, 3))])).is_err());
+        assert!(compiled.try_execute(&bind(&[("x", &x), ("w", &w), ("b", &b)])).is_ok());
+    }
+}
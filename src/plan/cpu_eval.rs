@@ -1,29 +1,39 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{MatrixPlan, Scalar, plan::op::MatrixOp, Matrix};
+use crate::{MatrixPlan, Scalar, plan::{op::MatrixOp, profile::{self, NodeProfile}}, Matrix};
 
 pub struct MatrixPlanCPUContext<'b, I: Scalar> {
     inputs: &'b HashMap<&'b str, &'b Matrix<I>>,
     outputs: HashMap<String, Matrix<I>>,
     cache: HashMap<u64, Matrix<I>>,
+    /// evaluated nodes, when profiling
+    profile: Option<Vec<NodeProfile>>,
 }
 
 impl<'b, I: Scalar> MatrixPlanCPUContext<'b, I> {
 
-    pub fn execute(plan: &MatrixPlan<I>, inputs: &'b HashMap<&'b str, &'b Matrix<I>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
-        // let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
-        let mut self_ = Self {
+    fn new(inputs: &'b HashMap<&'b str, &'b Matrix<I>>, profile: Option<Vec<NodeProfile>>) -> Self {
+        Self {
             inputs,
             outputs: HashMap::new(),
             cache: HashMap::new(),
-        };
+            profile,
+        }
+    }
 
+    pub fn execute(plan: &MatrixPlan<I>, inputs: &'b HashMap<&'b str, &'b Matrix<I>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
+        let mut self_ = Self::new(inputs, None);
         let base_output = self_.execute_cpu_recur(plan);
-        // println!("cache entries = {}", self_.cache.len());
-
         (base_output, self_.outputs)
     }
 
+    /// Same as [`MatrixPlanCPUContext::execute`], also timing each node
+    pub fn execute_profiled(plan: &MatrixPlan<I>, inputs: &'b HashMap<&'b str, &'b Matrix<I>>) -> (Matrix<I>, HashMap<String, Matrix<I>>, Vec<NodeProfile>) {
+        let mut self_ = Self::new(inputs, Some(vec![]));
+        let base_output = self_.execute_cpu_recur(plan);
+        (base_output, self_.outputs, self_.profile.unwrap_or_default())
+    }
+
     fn execute_cpu_recur(&mut self, plan: &MatrixPlan<I>) -> Matrix<I> {
         let ptr = Arc::as_ptr(&plan.source) as u64;
         match self.cache.get(&ptr) {
@@ -42,58 +52,208 @@ impl<'b, I: Scalar> MatrixPlanCPUContext<'b, I> {
     }
 
     fn execute_cpu_recur_uncached(&mut self, plan: &MatrixPlan<I>) -> Matrix<I> {
-        let output = match &*plan.source {
-            MatrixOp::Input { name } => {
-                (*self.inputs.get(&**name).expect(&*format!("missing input for '{}'", name))).clone()
-            },
-            MatrixOp::Output { name, matrix } => {
-                let matrix = self.execute_cpu_recur(matrix);
-                self.outputs.insert(name.clone(), matrix.clone());
-                matrix
-            },
-            MatrixOp::Constant { matrix } => {
-                matrix.clone()
-            },
-            MatrixOp::Scale { matrix, scalar } => {
-                self.execute_cpu_recur(matrix).scale(*scalar)
-            },
-            MatrixOp::Max { matrix, scalar } => {
-                self.execute_cpu_recur(matrix).max(*scalar)
-            },
-            MatrixOp::Neg { matrix } => {
-                -self.execute_cpu_recur(matrix)
-            },
-            MatrixOp::Transpose { matrix } => {
-                self.execute_cpu_recur(matrix).transpose()
-            },
-            MatrixOp::Sign { matrix } => {
-                self.execute_cpu_recur(matrix).sign()
-            },
-            MatrixOp::Sigmoid { matrix } => {
-                self.execute_cpu_recur(matrix).sigmoid()
-            },
-            MatrixOp::Mul { left, right } => {
-                self.execute_cpu_recur(left) * self.execute_cpu_recur(right)
-            },
-            MatrixOp::HadamardMul { left, right } => {
-                self.execute_cpu_recur(left).hadamard_mul(self.execute_cpu_recur(right))
-            },
-            MatrixOp::Add { left, right } => {
-                self.execute_cpu_recur(left) + self.execute_cpu_recur(right)
-            },
-            MatrixOp::Sub { left, right } => {
-                self.execute_cpu_recur(left) - self.execute_cpu_recur(right)
-            },
-            MatrixOp::Combine { inner } => {
-                for inner in inner {
-                    self.execute_cpu_recur(inner);
-                }
-                Matrix::default()
-            },
-        };
+        let operands = plan.source.operands().into_iter().map(|operand| self.execute_cpu_recur(operand)).collect();
+        let start = Instant::now();
+        let output = evaluate(plan, self.inputs, operands);
+        if let Some(profile) = &mut self.profile {
+            let name = match &*plan.source {
+                MatrixOp::Input { name } | MatrixOp::Output { name, .. } => Some(name.clone()),
+                _ => None,
+            };
+            profile.push(NodeProfile {
+                op: plan.source.name(),
+                name,
+                rows: plan.rows(),
+                cols: plan.cols(),
+                time: start.elapsed(),
+                cost: profile::node_cost(plan),
+            });
+        }
+        if let MatrixOp::Output { name, .. } = &*plan.source {
+            self.outputs.insert(name.clone(), output.clone());
+        }
+        output
+    }
+
+}
+
+/// Evaluates a single op of `plan` from its already evaluated operands, given in [`MatrixOp::operands`] order.
+/// Recording of named outputs is left to the caller.
+pub(crate) fn evaluate<I: Scalar>(plan: &MatrixPlan<I>, inputs: &HashMap<&str, &Matrix<I>>, mut operands: Vec<Matrix<I>>) -> Matrix<I> {
+    if plan.source.is_elementwise() {
+        let target = operands.remove(0);
+        let rest = operands.iter().collect::<Vec<_>>();
+        let output = evaluate_elementwise(plan, target, &rest);
         assert_eq!(plan.rows(), output.rows());
         assert_eq!(plan.cols(), output.cols());
-        output
+        return output;
+    }
+
+    let mut operands = operands.into_iter();
+    let mut operand = || operands.next().expect("missing operand");
+    let output = match &*plan.source {
+        MatrixOp::Input { name } => {
+            (*inputs.get(&**name).unwrap_or_else(|| panic!("missing input for '{}'", name))).clone()
+        },
+        MatrixOp::Output { .. } | MatrixOp::Recompute { .. } => {
+            operand()
+        },
+        MatrixOp::Constant { matrix } => {
+            matrix.clone()
+        },
+        MatrixOp::Fill { value } => {
+            Matrix::new(plan.rows(), plan.cols()).fill(*value)
+        },
+        MatrixOp::Transpose { .. } => {
+            operand().transpose()
+        },
+        MatrixOp::Mul { transposed, .. } => {
+            let mut product = Matrix::default();
+            operand().matmul_transposed_into(&operand(), *transposed, &mut product);
+            product
+        },
+        MatrixOp::SumRows { .. } => {
+            operand().sum_rows()
+        },
+        MatrixOp::SumCols { .. } => {
+            operand().sum_cols()
+        },
+        MatrixOp::Mean { .. } => {
+            Matrix::from_col([operand().mean()])
+        },
+        MatrixOp::MaxReduce { .. } => {
+            operand().max_reduce()
+        },
+        MatrixOp::ArgMax { .. } => {
+            operand().argmax()
+        },
+        MatrixOp::OneHot { .. } => {
+            operand().one_hot(plan.rows())
+        },
+        MatrixOp::BroadcastRows { .. } => {
+            operand().broadcast_rows(plan.rows())
+        },
+        MatrixOp::BroadcastCols { .. } => {
+            operand().broadcast_cols(plan.cols())
+        },
+        MatrixOp::Softmax { .. } => {
+            operand().softmax()
+        },
+        MatrixOp::LogSoftmax { .. } => {
+            operand().log_softmax()
+        },
+        MatrixOp::Reshape { .. } => {
+            operand().reshape(plan.rows(), plan.cols())
+        },
+        MatrixOp::Slice { rows, cols, .. } => {
+            let matrix = operand();
+            let (all_rows, all_cols) = (0..matrix.rows(), 0..matrix.cols());
+            matrix.submatrix(rows.clone().unwrap_or(all_rows), cols.clone().unwrap_or(all_cols))
+        },
+        MatrixOp::ConcatRows { .. } => {
+            let parts = operands.collect::<Vec<_>>();
+            Matrix::vstack(&parts.iter().collect::<Vec<_>>())
+        },
+        MatrixOp::ConcatCols { .. } => {
+            let parts = operands.collect::<Vec<_>>();
+            Matrix::hstack(&parts.iter().collect::<Vec<_>>())
+        },
+        MatrixOp::Combine { .. } => {
+            Matrix::default()
+        },
+        MatrixOp::Custom { op, .. } => {
+            let operands = operands.collect::<Vec<_>>();
+            op.forward(&operands.iter().collect::<Vec<_>>())
+        },
+        MatrixOp::FusedMul { transposed, kernel, .. } => {
+            let mut product = Matrix::default();
+            operand().matmul_transposed_into(&operand(), *transposed, &mut product);
+            let rest = operands.collect::<Vec<_>>();
+            kernel.evaluate_in_place(&mut product, &rest.iter().collect::<Vec<_>>());
+            product
+        },
+        op => unreachable!("{:?} is element-wise", op),
+    };
+    assert_eq!(plan.rows(), output.rows());
+    assert_eq!(plan.cols(), output.cols());
+    output
+}
+
+/// Evaluates an element-wise op (see [`MatrixOp::is_elementwise`]) in place over `target`, its first operand.
+/// `rest` holds the remaining operands.
+pub(crate) fn evaluate_elementwise<I: Scalar>(plan: &MatrixPlan<I>, target: Matrix<I>, rest: &[&Matrix<I>]) -> Matrix<I> {
+    match &*plan.source {
+        MatrixOp::Scale { scalar, .. } => {
+            target.scale(*scalar)
+        },
+        MatrixOp::Max { scalar, .. } => {
+            target.max(*scalar)
+        },
+        MatrixOp::Neg { .. } => {
+            -target
+        },
+        MatrixOp::Sign { .. } => {
+            target.sign()
+        },
+        MatrixOp::Sigmoid { .. } => {
+            target.sigmoid()
+        },
+        MatrixOp::Exp { .. } => {
+            target.exp()
+        },
+        MatrixOp::Log { .. } => {
+            target.ln()
+        },
+        MatrixOp::Sqrt { .. } => {
+            target.sqrt()
+        },
+        MatrixOp::Abs { .. } => {
+            target.abs()
+        },
+        MatrixOp::Tanh { .. } => {
+            target.tanh()
+        },
+        MatrixOp::Pow { exponent, .. } => {
+            target.pow(*exponent)
+        },
+        MatrixOp::Min { scalar, .. } => {
+            target.min(*scalar)
+        },
+        MatrixOp::Clamp { min, max, .. } => {
+            target.clamp(*min, *max)
+        },
+        MatrixOp::Reciprocal { .. } => {
+            target.reciprocal()
+        },
+        MatrixOp::HadamardMul { .. } => {
+            target.hadamard_mul(rest[0])
+        },
+        MatrixOp::Div { .. } => {
+            target.hadamard_div(rest[0])
+        },
+        MatrixOp::Greater { .. } => {
+            target.greater(rest[0])
+        },
+        MatrixOp::Less { .. } => {
+            target.less(rest[0])
+        },
+        MatrixOp::Equal { .. } => {
+            target.equal(rest[0])
+        },
+        MatrixOp::Where { .. } => {
+            target.select(rest[0], rest[1])
+        },
+        MatrixOp::Add { .. } => {
+            target + rest[0]
+        },
+        MatrixOp::Sub { .. } => {
+            target - rest[0]
+        },
+        MatrixOp::Fused { kernel, .. } => {
+            let mut target = target;
+            kernel.evaluate_in_place(&mut target, rest);
+            target
+        },
+        op => unreachable!("{:?} is not element-wise", op),
     }
-    
 }
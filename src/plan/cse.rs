@@ -0,0 +1,153 @@
+use std::{collections::HashSet, hash::{Hash, Hasher}, mem, sync::Arc};
+
+use crate::{MatrixPlan, Scalar, plan::{graph::PlanGraph, op::MatrixOp}};
+
+/// Identity of a scalar for hashing, `0.0` and `-0.0` (and differently encoded NaNs) stay distinct
+fn bits<I: Scalar>(scalar: I) -> u64 {
+    scalar.to_f64().to_bits()
+}
+
+/// Structural identity of a single node: its shape, op and payload, with operands compared by pointer.
+/// Once the operands of two nodes have been merged, equal keys mean the nodes compute the same value.
+struct NodeKey<I: Scalar>(MatrixPlan<I>);
+
+impl<I: Scalar> PartialEq for NodeKey<I> {
+    fn eq(&self, other: &Self) -> bool {
+        let (left, right) = (&self.0, &other.0);
+        if (&left.rows, &left.cols) != (&right.rows, &right.cols) || mem::discriminant(&*left.source) != mem::discriminant(&*right.source) {
+            return false;
+        }
+        let payload = match (&*left.source, &*right.source) {
+            (MatrixOp::Input { name: left }, MatrixOp::Input { name: right }) |
+            (MatrixOp::Output { name: left, .. }, MatrixOp::Output { name: right, .. }) => left == right,
+            (MatrixOp::Constant { matrix: left }, MatrixOp::Constant { matrix: right }) => {
+                let (left, right): (&[I], &[I]) = (left.as_ref(), right.as_ref());
+                left.len() == right.len() && left.iter().zip(right).all(|(x, y)| bits(*x) == bits(*y))
+            },
+            (MatrixOp::Fill { value: left }, MatrixOp::Fill { value: right }) |
+            (MatrixOp::Scale { scalar: left, .. }, MatrixOp::Scale { scalar: right, .. }) |
+            (MatrixOp::Max { scalar: left, .. }, MatrixOp::Max { scalar: right, .. }) |
+            (MatrixOp::Min { scalar: left, .. }, MatrixOp::Min { scalar: right, .. }) |
+            (MatrixOp::Pow { exponent: left, .. }, MatrixOp::Pow { exponent: right, .. }) => bits(*left) == bits(*right),
+            (MatrixOp::Clamp { min: left_min, max: left_max, .. }, MatrixOp::Clamp { min: right_min, max: right_max, .. }) => {
+                bits(*left_min) == bits(*right_min) && bits(*left_max) == bits(*right_max)
+            },
+            (MatrixOp::Slice { rows: left_rows, cols: left_cols, .. }, MatrixOp::Slice { rows: right_rows, cols: right_cols, .. }) => {
+                left_rows == right_rows && left_cols == right_cols
+            },
+            (MatrixOp::Mul { transposed: left, .. }, MatrixOp::Mul { transposed: right, .. }) => left == right,
+            (MatrixOp::Fused { kernel: left, .. }, MatrixOp::Fused { kernel: right, .. }) |
+            (MatrixOp::FusedMul { kernel: left, .. }, MatrixOp::FusedMul { kernel: right, .. }) => Arc::ptr_eq(left, right),
+            (MatrixOp::Custom { op: left, .. }, MatrixOp::Custom { op: right, .. }) => Arc::as_ptr(left) as *const () == Arc::as_ptr(right) as *const (),
+            _ => true,
+        };
+        let (left, right) = (left.source.operands(), right.source.operands());
+        payload && left.len() == right.len() && left.iter().zip(&right).all(|(x, y)| Arc::ptr_eq(&x.source, &y.source))
+    }
+}
+
+impl<I: Scalar> Eq for NodeKey<I> {}
+
+impl<I: Scalar> Hash for NodeKey<I> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let plan = &self.0;
+        (&plan.rows, &plan.cols).hash(state);
+        mem::discriminant(&*plan.source).hash(state);
+        match &*plan.source {
+            MatrixOp::Input { name } | MatrixOp::Output { name, .. } => name.hash(state),
+            MatrixOp::Constant { matrix } => {
+                let data: &[I] = matrix.as_ref();
+                data.iter().for_each(|x| bits(*x).hash(state));
+            },
+            MatrixOp::Fill { value: scalar } |
+            MatrixOp::Scale { scalar, .. } |
+            MatrixOp::Max { scalar, .. } |
+            MatrixOp::Min { scalar, .. } |
+            MatrixOp::Pow { exponent: scalar, .. } => bits(*scalar).hash(state),
+            MatrixOp::Clamp { min, max, .. } => (bits(*min), bits(*max)).hash(state),
+            MatrixOp::Slice { rows, cols, .. } => (rows, cols).hash(state),
+            MatrixOp::Mul { transposed, .. } => transposed.hash(state),
+            MatrixOp::Fused { kernel, .. } | MatrixOp::FusedMul { kernel, .. } => Arc::as_ptr(kernel).hash(state),
+            MatrixOp::Custom { op, .. } => (Arc::as_ptr(op) as *const ()).hash(state),
+            _ => (),
+        }
+        for operand in plan.source.operands() {
+            Arc::as_ptr(&operand.source).hash(state);
+        }
+    }
+}
+
+/// Merges structurally identical nodes, so each distinct value is represented (and evaluated) once
+pub(crate) fn eliminate_common_subexpressions<I: Scalar>(plan: &MatrixPlan<I>) -> MatrixPlan<I> {
+    let graph = PlanGraph::new(plan);
+    let mut canonical: HashSet<NodeKey<I>> = HashSet::with_capacity(graph.nodes.len());
+    let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let operands = node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect();
+        let current = NodeKey(node.plan.with_operands(operands));
+        let current = match canonical.get(&current) {
+            Some(existing) => existing.0.clone(),
+            None => {
+                let plan = current.0.clone();
+                canonical.insert(current);
+                plan
+            },
+        };
+        rebuilt.push(current);
+    }
+    rebuilt.swap_remove(graph.root)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{MatrixPlan, plan::{graph::PlanGraph, testing::{Cube, assert_bitwise_eq, bind, sample}}};
+
+    use super::eliminate_common_subexpressions;
+
+    fn x() -> MatrixPlan<f64> {
+        MatrixPlan::input(2, 3, "x")
+    }
+
+    fn nodes(plan: &MatrixPlan<f64>) -> usize {
+        PlanGraph::new(plan).nodes.len()
+    }
+
+    /// Node count after elimination, checking that it keeps the value bit for bit
+    fn eliminated(plan: &MatrixPlan<f64>) -> usize {
+        let merged = eliminate_common_subexpressions(plan);
+        let inputs = bind(&[("x", &sample(2, 3, 1)), ("y", &sample(2, 3, 2))]);
+        assert_bitwise_eq(&merged.execute_cpu(&inputs).0, &plan.execute_cpu(&inputs).0);
+        nodes(&merged)
+    }
+
+    #[test]
+    fn merges_structurally_equal_nodes() {
+        // built twice, so nothing is shared before elimination
+        let branch = || x().scale(2.0).clamp(-1.0, 1.0).exp() + MatrixPlan::constant(sample(2, 3, 3));
+        let plan = branch().hadamard_mul(branch()) - branch();
+        assert_eq!(nodes(&plan), 20);
+        // input, scale, clamp, exp, constant, add, hadamard, sub
+        assert_eq!(eliminated(&plan), 8);
+    }
+
+    #[test]
+    fn keeps_nodes_with_different_payloads() {
+        assert_eq!(eliminated(&(x().scale(2.0) + x().scale(3.0))), 4);
+        assert_eq!(eliminated(&(x().max(0.0) + x().max(-0.0))), 4);
+        assert_eq!(eliminated(&(x().clamp(-0.0, 1.0) + x().clamp(0.0, 1.0))), 4);
+        assert_eq!(eliminated(&(MatrixPlan::splat(2, 3, 0.0) + MatrixPlan::splat(2, 3, -0.0) + x())), 5);
+        assert_eq!(eliminated(&(x().slice(0..1, 0..2) + x().slice(1..2, 0..2))), 4);
+        assert_eq!(eliminated(&(MatrixPlan::input(2, 3, "y") + x())), 3);
+    }
+
+    #[test]
+    fn merges_custom_ops_only_when_they_are_the_same_op() {
+        let (cube, other) = (Arc::new(Cube), Arc::new(Cube));
+        let same = MatrixPlan::custom(cube.clone(), [x()]) + MatrixPlan::custom(cube, [x()]);
+        assert_eq!(eliminated(&same), 3);
+        let different = MatrixPlan::custom(other, [x()]) + MatrixPlan::custom(Arc::new(Cube), [x()]);
+        assert_eq!(eliminated(&different), 4);
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+use crate::{Dim, Matrix, MatrixPlan, Scalar};
+
+/// A user-defined op, added to a plan with [`MatrixPlan::custom`].
+/// Ops must be deterministic: equal operands may be evaluated once and constant operands folded at build time.
+pub trait CustomOp<I: Scalar>: Send + Sync + 'static {
+    /// Shown when debugging plans
+    fn name(&self) -> &str;
+
+    /// Shape of the result for operands of the given shapes, `None` if their count or shapes are not accepted.
+    /// Called on any operands (e.g. ones read from text), so it must not index past them.
+    /// Shapes may be symbolic, [`Dim`] compares equal to a `usize` when fixed to it.
+    fn output_shape(&self, operands: &[(Dim, Dim)]) -> Option<(Dim, Dim)>;
+
+    /// Evaluates the op on the CPU
+    fn forward(&self, operands: &[&Matrix<I>]) -> Matrix<I>;
+
+    /// Estimated floating point operations of evaluating the op on operands of the given shapes, reported by [`MatrixPlan::cost`] and profiling
+    fn flops(&self, operands: &[(usize, usize)]) -> u64 {
+        let _ = operands;
+        0
+    }
+
+    /// Gradient plans of each operand given the gradient `adjoint` of the op's `output`, `None` for operands receiving no gradient.
+    /// Ops returning `None` (the default) cannot be differentiated in reverse mode.
+    fn backward_plan(&self, operands: &[MatrixPlan<I>], output: &MatrixPlan<I>, adjoint: &MatrixPlan<I>) -> Option<Vec<Option<MatrixPlan<I>>>> {
+        let _ = (operands, output, adjoint);
+        None
+    }
+
+    /// Derivative of `output` given the `tangents` of each operand (`None` where zero).
+    /// Ops returning `None` (the default) cannot be differentiated in forward mode.
+    fn tangent_plan(&self, operands: &[MatrixPlan<I>], output: &MatrixPlan<I>, tangents: &[Option<MatrixPlan<I>>]) -> Option<MatrixPlan<I>> {
+        let _ = (operands, output, tangents);
+        None
+    }
+}
+
+impl<I: Scalar> fmt::Debug for dyn CustomOp<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CustomOp({})", self.name())
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{Matrix, Scalar};
+
+/// Size of one axis of a plan: fixed when the plan is built, or a named symbol (e.g. the batch size) bound at execution from the shapes of the inputs using it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Dim {
+    Fixed(usize),
+    Symbol(Arc<str>),
+}
+
+/// Sizes bound to symbolic dimensions by name
+pub(crate) type Bindings = HashMap<String, usize>;
+
+impl Dim {
+    pub fn symbol(name: impl AsRef<str>) -> Self {
+        Dim::Symbol(name.as_ref().into())
+    }
+
+    pub fn fixed(&self) -> Option<usize> {
+        match self {
+            Dim::Fixed(size) => Some(*size),
+            Dim::Symbol(_) => None,
+        }
+    }
+
+    pub fn is_symbolic(&self) -> bool {
+        matches!(self, Dim::Symbol(_))
+    }
+
+    /// Size of this dimension under `bindings`, `None` for an unbound symbol
+    pub(crate) fn resolve(&self, bindings: &Bindings) -> Option<usize> {
+        match self {
+            Dim::Fixed(size) => Some(*size),
+            Dim::Symbol(name) => bindings.get(&**name).copied(),
+        }
+    }
+}
+
+impl From<usize> for Dim {
+    fn from(size: usize) -> Self {
+        Dim::Fixed(size)
+    }
+}
+
+impl From<&str> for Dim {
+    fn from(name: &str) -> Self {
+        Dim::symbol(name)
+    }
+}
+
+impl From<&Dim> for Dim {
+    fn from(dim: &Dim) -> Self {
+        dim.clone()
+    }
+}
+
+impl PartialEq<usize> for Dim {
+    fn eq(&self, other: &usize) -> bool {
+        self.fixed() == Some(*other)
+    }
+}
+
+impl fmt::Display for Dim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dim::Fixed(size) => write!(f, "{}", size),
+            Dim::Symbol(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Binds each symbolic dimension of the declared inputs to the size of the first bound matrix using it.
+/// Unbound inputs are skipped, conflicting sizes are left for the shape checks to report.
+pub(crate) fn bind<'a, I: Scalar>(declared: impl IntoIterator<Item=(&'a str, (Dim, Dim))>, inputs: &HashMap<&str, &Matrix<I>>) -> Bindings {
+    let mut bindings = Bindings::new();
+    for (name, (rows, cols)) in declared {
+        let Some(input) = inputs.get(name) else {
+            continue;
+        };
+        for (dim, size) in [(rows, input.rows()), (cols, input.cols())] {
+            if let Dim::Symbol(symbol) = dim {
+                bindings.entry(symbol.to_string()).or_insert(size);
+            }
+        }
+    }
+    bindings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Dim, Matrix, MatrixError, MatrixPlan, plan::testing::{assert_bitwise_eq, bind, sample}};
+
+    /// `tanh(w x + b)` over a symbolic batch of columns
+    fn plan() -> MatrixPlan<f64> {
+        let x = MatrixPlan::input(3, "batch", "x");
+        let w = MatrixPlan::input(2, 3, "w");
+        let b = MatrixPlan::input(2, 1, "b");
+        (w * x + b.broadcast_cols("batch")).tanh().output("y")
+    }
+
+    #[test]
+    fn one_plan_runs_at_every_batch_size() {
+        let plan = plan();
+        assert_eq!(plan.dims(), (Dim::Fixed(2), Dim::symbol("batch")));
+        assert_eq!(plan.symbols(), ["batch"]);
+        let (w, b) = (sample(2, 3, 1), sample(2, 1, 2));
+        for batch in [1, 6] {
+            let x = sample(3, batch, 3);
+            let inputs = bind(&[("x", &x), ("w", &w), ("b", &b)]);
+            let (result, outputs) = plan.try_execute_cpu(&inputs).unwrap();
+            assert_eq!((result.rows(), result.cols()), (2, batch));
+            assert_bitwise_eq(&outputs["y"], &result);
+            // the same as the plan resolved to the batch size, compiled
+            let resolved = plan.resolve(&[("batch", batch)]);
+            assert_eq!(resolved.dims(), (Dim::Fixed(2), Dim::Fixed(batch)));
+            assert_bitwise_eq(&resolved.compile().execute(&inputs).0, &result);
+        }
+    }
+
+    #[test]
+    fn conflicting_bindings_are_rejected() {
+        let x = MatrixPlan::<f64>::input(3, "batch", "x");
+        let y = MatrixPlan::input(3, "batch", "y");
+        let plan = x + y;
+        let (x, y) = (Matrix::new(3, 5), Matrix::new(3, 4));
+        // the batch is bound by `x`, the first input using it
+        assert_eq!(plan.try_execute_cpu(&bind(&[("x", &x), ("y", &y)])).unwrap_err(), MatrixError::InputShape {
+            name: "y".to_string(),
+            expected: (3, 5),
+            actual: (3, 4),
+        });
+        assert!(matches!(plan.try_resolve(&[]), Err(MatrixError::UnboundDim { .. })));
+    }
+}
@@ -0,0 +1,255 @@
+use std::fmt::Write;
+
+use crate::{Matrix, MatrixPlan, Scalar, plan::{graph::PlanGraph, op::MatrixOp, text::range}};
+
+/// Constants with at most this many components are listed in full, larger ones summarized
+const LISTED_COMPONENTS: usize = 4;
+
+/// Rendering options of [`MatrixPlan::to_dot_with`]
+#[derive(Clone, Debug)]
+pub struct DotOptions {
+    /// fill the nodes of named outputs
+    pub outputs: bool,
+    /// named outputs whose own subgraph (the nodes nothing else depends on) is shaded, e.g. the `gradient_` outputs of a backprop plan
+    pub highlight: Vec<String>,
+}
+
+impl Default for DotOptions {
+    fn default() -> Self {
+        Self {
+            outputs: true,
+            highlight: vec![],
+        }
+    }
+}
+
+impl DotOptions {
+    pub fn outputs(mut self, outputs: bool) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    /// Shades the nodes only the output `name` depends on, can be repeated for several outputs
+    pub fn highlight(mut self, name: impl AsRef<str>) -> Self {
+        self.highlight.push(name.as_ref().to_string());
+        self
+    }
+}
+
+pub(crate) fn to_dot<I: Scalar>(plan: &MatrixPlan<I>, options: &DotOptions) -> String {
+    let graph = PlanGraph::new(plan);
+    let highlighted = exclusive_subgraph(&graph, &options.highlight);
+    let mut dot = String::from("digraph plan {\n    node [shape=box, fontname=monospace];\n");
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let label = label(node.plan).iter().map(|line| escape(line)).collect::<Vec<_>>().join("\\n");
+        let mut attributes = vec![format!("label=\"{}\"", label)];
+        match &*node.plan.source {
+            MatrixOp::Input { .. } => attributes.push("shape=invhouse".to_string()),
+            MatrixOp::Output { .. } => attributes.push("shape=house".to_string()),
+            MatrixOp::Constant { .. } | MatrixOp::Fill { .. } => attributes.push("shape=note".to_string()),
+            _ => (),
+        }
+        let output = matches!(&*node.plan.source, MatrixOp::Output { .. }) && options.outputs;
+        if output || highlighted[index] {
+            let color = if output {
+                "lightblue"
+            } else {
+                "peachpuff"
+            };
+            attributes.push(format!("style=filled, fillcolor={}", color));
+        }
+        writeln!(dot, "    n{} [{}];", index, attributes.join(", ")).unwrap();
+    }
+    for (index, node) in graph.nodes.iter().enumerate() {
+        for (position, operand) in node.operands.iter().enumerate() {
+            // operand order matters for most ops taking several
+            if node.operands.len() > 1 {
+                writeln!(dot, "    n{} -> n{} [label=\"{}\"];", operand, index, position).unwrap();
+            } else {
+                writeln!(dot, "    n{} -> n{};", operand, index).unwrap();
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Nodes the outputs named in `names` depend on that neither the rest of the plan nor other outputs do.
+/// Merging outputs does not count as depending on them.
+fn exclusive_subgraph<I: Scalar>(graph: &PlanGraph<I>, names: &[String]) -> Vec<bool> {
+    let selected = |index: usize| matches!(&*graph.nodes[index].plan.source, MatrixOp::Output { name, .. } if names.contains(name));
+    let mut reached = vec![false; graph.nodes.len()];
+    let mut shared = vec![false; graph.nodes.len()];
+    if names.is_empty() {
+        return reached;
+    }
+    shared[graph.root] = !selected(graph.root);
+    // users come after their operands, so every node is settled before its operands are visited
+    for (index, node) in graph.nodes.iter().enumerate().rev() {
+        reached[index] |= selected(index);
+        let merge = matches!(&*node.plan.source, MatrixOp::Combine { .. });
+        for operand in &node.operands {
+            reached[*operand] |= reached[index];
+            if !(merge && selected(*operand)) {
+                shared[*operand] |= shared[index];
+            }
+        }
+    }
+    reached.iter().zip(&shared).map(|(reached, shared)| *reached && !shared).collect()
+}
+
+/// Lines describing a node: its op and payload, shape, and the components of constants
+fn label<I: Scalar>(plan: &MatrixPlan<I>) -> Vec<String> {
+    let source = &*plan.source;
+    let name = source.name();
+    let head = match source {
+        MatrixOp::Input { name: input } => format!("{} '{}'", name, input),
+        MatrixOp::Output { name: output, .. } => format!("{} '{}'", name, output),
+        MatrixOp::Fill { value: scalar } |
+        MatrixOp::Scale { scalar, .. } |
+        MatrixOp::Max { scalar, .. } |
+        MatrixOp::Min { scalar, .. } |
+        MatrixOp::Pow { exponent: scalar, .. } => format!("{} {}", name, scalar),
+        MatrixOp::Clamp { min, max, .. } => format!("{} [{}, {}]", name, min, max),
+        MatrixOp::Slice { rows, cols, .. } => format!("{} rows {} cols {}", name, range(rows), range(cols)),
+        MatrixOp::Custom { op, .. } => format!("{} {}", name, op.name()),
+        MatrixOp::Mul { transposed, .. } | MatrixOp::FusedMul { transposed, .. } => match transposed {
+            (false, false) => name.to_string(),
+            (true, false) => format!("{} left.T", name),
+            (false, true) => format!("{} right.T", name),
+            (true, true) => format!("{} left.T right.T", name),
+        },
+        _ => name.to_string(),
+    };
+    let mut lines = vec![head, format!("{}x{}", plan.rows, plan.cols)];
+    match source {
+        MatrixOp::Constant { matrix } => lines.push(summary(matrix)),
+        MatrixOp::Fused { kernel, .. } | MatrixOp::FusedMul { kernel, .. } => {
+            lines.push(kernel.steps.iter().map(|step| format!("{:?}", step.op)).collect::<Vec<_>>().join(", "));
+        },
+        _ => (),
+    }
+    lines
+}
+
+/// The components of small constants, the range and mean of larger ones
+fn summary<I: Scalar>(matrix: &Matrix<I>) -> String {
+    let data: &[I] = matrix.as_ref();
+    if data.len() <= LISTED_COMPONENTS {
+        return format!("[{}]", data.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "));
+    }
+    let (mut min, mut max) = (data[0], data[0]);
+    for x in data {
+        if *x < min {
+            min = *x;
+        }
+        if *x > max {
+            max = *x;
+        }
+    }
+    let mean = data.iter().map(|x| x.to_f64()).sum::<f64>() / data.len() as f64;
+    format!("min {} max {} mean {}", min, max, mean)
+}
+
+/// Escapes a label line for a quoted Graphviz string
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use crate::{Checkpointing, DotOptions, MatrixPlan, NeuralNetworkBuilder, activation::{Sigmoid, Tanh}, plan::{graph::PlanGraph, op::MatrixOp, testing::{matrix, sample}}};
+
+    /// Declared nodes and edges of a Graphviz graph
+    fn statements(dot: &str) -> (Vec<&str>, Vec<&str>) {
+        let lines = dot.lines().map(str::trim).filter(|line| line.starts_with('n') && line[1..].starts_with(|c: char| c.is_ascii_digit()));
+        lines.partition(|line| !line.contains("->"))
+    }
+
+    #[test]
+    fn shared_nodes_are_drawn_once() {
+        let x = MatrixPlan::<f64>::input(2, 3, "x");
+        let exp = x.exp();
+        let plan = (exp.clone() + exp.tanh()).output("y");
+        let dot = plan.to_dot();
+        let (nodes, edges) = statements(&dot);
+        assert_eq!(nodes.len(), PlanGraph::new(&plan).nodes.len());
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(edges, ["n0 -> n1;", "n1 -> n2;", "n1 -> n3 [label=\"0\"];", "n2 -> n3 [label=\"1\"];", "n3 -> n4;"]);
+        assert!(nodes[4].contains("label=\"output 'y'\\n2x3\", shape=house, style=filled, fillcolor=lightblue"));
+        assert!(!plan.to_dot_with(&DotOptions::default().outputs(false)).contains("fillcolor"));
+    }
+
+    #[test]
+    fn operands_read_twice_get_one_labeled_edge_each() {
+        let x = MatrixPlan::<f64>::input(2, 3, "x");
+        let dot = (x.clone() + x).to_dot();
+        let (_, edges) = statements(&dot);
+        assert_eq!(edges, ["n0 -> n1 [label=\"0\"];", "n0 -> n1 [label=\"1\"];"]);
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let dot = MatrixPlan::<f64>::input(1, 1, r#"a"b\c"#).to_dot();
+        assert!(dot.contains(r#"label="input 'a\"b\\c'\n1x1""#), "{}", dot);
+    }
+
+    #[test]
+    fn constants_are_listed_or_summarized() {
+        let small = MatrixPlan::constant(matrix(2, 2, &[1.0, 2.0, 3.0, 4.5]));
+        assert!(small.to_dot().contains(r#"label="constant\n2x2\n[1, 2, 3, 4.5]""#));
+        let large = MatrixPlan::constant(matrix(2, 3, &[1.0, 6.0, 2.0, 3.0, 4.0, 5.0]));
+        assert!(large.to_dot().contains(r#"label="constant\n2x3\nmin 1 max 6 mean 3.5""#));
+    }
+
+    /// Indices of the shaded nodes when highlighting `names`
+    fn highlighted(plan: &MatrixPlan<f64>, names: &[&str]) -> HashSet<usize> {
+        let options = names.iter().fold(DotOptions::default().outputs(false), |options, name| options.highlight(name));
+        let dot = plan.to_dot_with(&options);
+        statements(&dot).0.into_iter()
+            .filter(|node| node.contains("fillcolor=peachpuff"))
+            .map(|node| node[1..node.find(' ').unwrap()].parse().unwrap())
+            .collect()
+    }
+
+    /// Nodes reached from the merged outputs named in `names` and from none of the others, found by walking each of them
+    fn exclusive(plan: &MatrixPlan<f64>, names: &[&str]) -> HashSet<usize> {
+        let MatrixOp::Combine { inner } = &*plan.source else {
+            panic!("not merged outputs");
+        };
+        let is_selected = |part: &MatrixPlan<f64>| matches!(&*part.source, MatrixOp::Output { name, .. } if names.contains(&name.as_str()));
+        let reached = |selected: bool| inner.iter()
+            .filter(|part| selected == is_selected(part))
+            .flat_map(|part| PlanGraph::new(part).nodes.into_iter().map(|node| Arc::as_ptr(&node.plan.source) as *const ()))
+            .collect::<HashSet<_>>();
+        let (own, others) = (reached(true), reached(false));
+        PlanGraph::new(plan).nodes.iter().enumerate()
+            .filter(|(_, node)| {
+                let node = Arc::as_ptr(&node.plan.source) as *const ();
+                own.contains(&node) && !others.contains(&node)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn highlighting_shades_the_nodes_only_that_gradient_needs() {
+        for checkpointing in [Checkpointing::Disabled, Checkpointing::EveryLayers(2)] {
+            let plan = NeuralNetworkBuilder::<f64>::new()
+                .input(3)
+                .add_dense_layer_weighted(sample(4, 3, 1), Tanh)
+                .add_dense_layer_weighted(sample(4, 4, 2), Sigmoid)
+                .add_dense_layer_weighted(sample(2, 4, 3), Tanh)
+                .checkpointing(checkpointing)
+                .plan_backprop(5);
+            for names in [&["gradient_0"][..], &["gradient_2"], &["gradient_0", "gradient_1"]] {
+                let shaded = highlighted(&plan, names);
+                assert!(!shaded.is_empty());
+                assert_eq!(shaded, exclusive(&plan, names), "{:?} {:?}", checkpointing, names);
+            }
+            assert!(highlighted(&plan, &[]).is_empty());
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{Matrix, MatrixPlan, Scalar, matrix, parallel, plan::{graph::PlanGraph, op::MatrixOp}};
+
+/// Components carried through every step of a kernel before moving on, small enough for the step registers to stay in L1
+const BLOCK: usize = 256;
+
+/// A single element-wise operation inside a [`FusedKernel`]
+#[derive(Clone, Copy, Debug)]
+pub enum ElementOp<I: Scalar> {
+    Scale(I),
+    Max(I),
+    Neg,
+    Sign,
+    Sigmoid,
+    Exp,
+    Log,
+    Sqrt,
+    Abs,
+    Tanh,
+    Pow(I),
+    Min(I),
+    Clamp(I, I),
+    Reciprocal,
+    Add,
+    Sub,
+    HadamardMul,
+    Div,
+    Greater,
+    Less,
+    Equal,
+    Where,
+}
+
+impl<I: Scalar> ElementOp<I> {
+    fn from_op(op: &MatrixOp<I>) -> Option<Self> {
+        Some(match op {
+            MatrixOp::Scale { scalar, .. } => ElementOp::Scale(*scalar),
+            MatrixOp::Max { scalar, .. } => ElementOp::Max(*scalar),
+            MatrixOp::Neg { .. } => ElementOp::Neg,
+            MatrixOp::Sign { .. } => ElementOp::Sign,
+            MatrixOp::Sigmoid { .. } => ElementOp::Sigmoid,
+            MatrixOp::Exp { .. } => ElementOp::Exp,
+            MatrixOp::Log { .. } => ElementOp::Log,
+            MatrixOp::Sqrt { .. } => ElementOp::Sqrt,
+            MatrixOp::Abs { .. } => ElementOp::Abs,
+            MatrixOp::Tanh { .. } => ElementOp::Tanh,
+            MatrixOp::Pow { exponent, .. } => ElementOp::Pow(*exponent),
+            MatrixOp::Min { scalar, .. } => ElementOp::Min(*scalar),
+            MatrixOp::Clamp { min, max, .. } => ElementOp::Clamp(*min, *max),
+            MatrixOp::Reciprocal { .. } => ElementOp::Reciprocal,
+            MatrixOp::Add { .. } => ElementOp::Add,
+            MatrixOp::Sub { .. } => ElementOp::Sub,
+            MatrixOp::HadamardMul { .. } => ElementOp::HadamardMul,
+            MatrixOp::Div { .. } => ElementOp::Div,
+            MatrixOp::Greater { .. } => ElementOp::Greater,
+            MatrixOp::Less { .. } => ElementOp::Less,
+            MatrixOp::Equal { .. } => ElementOp::Equal,
+            MatrixOp::Where { .. } => ElementOp::Where,
+            _ => return None,
+        })
+    }
+
+    /// Applies the op over `target`, combining with the other operands `rest` for binary and ternary ops
+    fn apply(&self, target: &mut [I], rest: [Option<&[I]>; 2]) {
+        let rhs = || rest[0].expect("binary op without right hand side").iter().copied();
+        let flag = |set: bool| if set {
+            I::ONE
+        } else {
+            I::default()
+        };
+        match *self {
+            ElementOp::Scale(scalar) => target.iter_mut().for_each(|x| *x = *x * scalar),
+            // same comparisons as the unfused ops, which replace NaNs with the scalar
+            ElementOp::Max(scalar) => target.iter_mut().for_each(|x| *x = matrix::max(*x, scalar)),
+            ElementOp::Neg => target.iter_mut().for_each(|x| *x = -*x),
+            ElementOp::Sign => target.iter_mut().for_each(|x| *x = x.sign()),
+            ElementOp::Sigmoid => target.iter_mut().for_each(|x| *x = x.sigmoid()),
+            ElementOp::Exp => target.iter_mut().for_each(|x| *x = x.exp()),
+            ElementOp::Log => target.iter_mut().for_each(|x| *x = x.ln()),
+            ElementOp::Sqrt => target.iter_mut().for_each(|x| *x = x.sqrt()),
+            ElementOp::Abs => target.iter_mut().for_each(|x| *x = x.abs()),
+            ElementOp::Tanh => target.iter_mut().for_each(|x| *x = x.tanh()),
+            ElementOp::Pow(exponent) => target.iter_mut().for_each(|x| *x = x.power(exponent)),
+            ElementOp::Min(scalar) => target.iter_mut().for_each(|x| *x = matrix::min(*x, scalar)),
+            ElementOp::Clamp(min, max) => target.iter_mut().for_each(|x| *x = matrix::min(matrix::max(*x, min), max)),
+            ElementOp::Reciprocal => target.iter_mut().for_each(|x| *x = x.recip()),
+            ElementOp::Add => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = *x + y),
+            ElementOp::Sub => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = *x - y),
+            ElementOp::HadamardMul => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = *x * y),
+            ElementOp::Div => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = *x / y),
+            ElementOp::Greater => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = flag(*x > y)),
+            ElementOp::Less => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = flag(*x < y)),
+            ElementOp::Equal => target.iter_mut().zip(rhs()).for_each(|(x, y)| *x = flag(*x == y)),
+            ElementOp::Where => {
+                let otherwise = rest[1].expect("select without else operand").iter().copied();
+                target.iter_mut().zip(rhs()).zip(otherwise).for_each(|((x, then), otherwise)| *x = if *x != I::default() {
+                    then
+                } else {
+                    otherwise
+                });
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// a matrix the kernel reads
+    Argument(usize),
+    /// the result of an earlier step
+    Step(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct Step<I: Scalar> {
+    pub op: ElementOp<I>,
+    pub sources: Vec<Register>,
+}
+
+/// A straight-line program of element-wise steps over equally shaped arguments; the last step is the result.
+#[derive(Debug)]
+pub struct FusedKernel<I: Scalar> {
+    pub arguments: usize,
+    pub steps: Vec<Step<I>>,
+}
+
+impl<I: Scalar> FusedKernel<I> {
+    pub(crate) fn evaluate_into(&self, arguments: &[&Matrix<I>], out: &mut Matrix<I>) {
+        out.resize(arguments[0].rows(), arguments[0].cols());
+        let cols = out.cols();
+        let arguments = arguments.iter().map(|argument| -> &[I] { argument.as_ref() }).collect::<Vec<_>>();
+        self.run(out.as_mut(), cols, false, &arguments);
+    }
+
+    /// Evaluates over `first`, which holds the first argument, followed by `rest`
+    pub(crate) fn evaluate_in_place(&self, first: &mut Matrix<I>, rest: &[&Matrix<I>]) {
+        let cols = first.cols();
+        let arguments = std::iter::once(&[][..]).chain(rest.iter().map(|argument| -> &[I] { argument.as_ref() })).collect::<Vec<_>>();
+        self.run(first.as_mut(), cols, true, &arguments);
+    }
+
+    /// The kernel as a tree of plain ops over `arguments`
+    fn expand(&self, arguments: &[MatrixPlan<I>]) -> MatrixPlan<I> {
+        let mut steps: Vec<MatrixPlan<I>> = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            let source = |index: usize| match step.sources[index] {
+                Register::Argument(argument) => arguments[argument].clone(),
+                Register::Step(step) => steps[step].clone(),
+            };
+            let plan = match step.op {
+                ElementOp::Scale(scalar) => source(0).scale(scalar),
+                ElementOp::Max(scalar) => source(0).max(scalar),
+                ElementOp::Neg => -source(0),
+                ElementOp::Sign => source(0).sign(),
+                ElementOp::Sigmoid => source(0).sigmoid(),
+                ElementOp::Exp => source(0).exp(),
+                ElementOp::Log => source(0).ln(),
+                ElementOp::Sqrt => source(0).sqrt(),
+                ElementOp::Abs => source(0).abs(),
+                ElementOp::Tanh => source(0).tanh(),
+                ElementOp::Pow(exponent) => source(0).pow(exponent),
+                ElementOp::Min(scalar) => source(0).min(scalar),
+                ElementOp::Clamp(min, max) => source(0).clamp(min, max),
+                ElementOp::Reciprocal => source(0).reciprocal(),
+                ElementOp::Add => source(0) + source(1),
+                ElementOp::Sub => source(0) - source(1),
+                ElementOp::HadamardMul => source(0).hadamard_mul(source(1)),
+                ElementOp::Div => source(0).hadamard_div(source(1)),
+                ElementOp::Greater => source(0).greater(source(1)),
+                ElementOp::Less => source(0).less(source(1)),
+                ElementOp::Equal => source(0).equal(source(1)),
+                ElementOp::Where => source(0).select(source(1), source(2)),
+            };
+            steps.push(plan);
+        }
+        steps.pop().expect("empty kernel")
+    }
+
+    fn run(&self, out: &mut [I], cols: usize, in_place: bool, arguments: &[&[I]]) {
+        assert_eq!(arguments.len(), self.arguments);
+        let work = out.len() * self.steps.len();
+        parallel::for_each_row_block(out, cols, work, |first_row, block| {
+            let offset = first_row * cols;
+            let mut registers = vec![vec![I::default(); BLOCK]; self.steps.len()];
+            let mut first = vec![I::default(); if in_place { BLOCK } else { 0 }];
+            for start in (0..block.len()).step_by(BLOCK) {
+                let len = BLOCK.min(block.len() - start);
+                let range = offset + start..offset + start + len;
+                if in_place {
+                    first[..len].copy_from_slice(&block[start..start + len]);
+                }
+                for (index, step) in self.steps.iter().enumerate() {
+                    let (done, current) = registers.split_at_mut(index);
+                    let read = |register: Register| -> &[I] {
+                        match register {
+                            Register::Argument(0) if in_place => &first[..len],
+                            Register::Argument(argument) => &arguments[argument][range.clone()],
+                            Register::Step(step) => &done[step][..len],
+                        }
+                    };
+                    let target = &mut current[0][..len];
+                    target.copy_from_slice(read(step.sources[0]));
+                    step.op.apply(target, [step.sources.get(1).map(|source| read(*source)), step.sources.get(2).map(|source| read(*source))]);
+                }
+                block[start..start + len].copy_from_slice(&registers[self.steps.len() - 1][..len]);
+            }
+        });
+    }
+}
+
+/// Collapses runs of single-use element-wise ops into [`MatrixOp::Fused`] nodes, and a single-use product feeding such a run into [`MatrixOp::FusedMul`].
+pub(crate) fn fuse<I: Scalar>(plan: &MatrixPlan<I>) -> MatrixPlan<I> {
+    let graph = PlanGraph::new(plan);
+    let nodes = &graph.nodes;
+    let fusible = |index: usize| ElementOp::from_op(&nodes[index].plan.source).is_some();
+    // evaluated inside the kernel of its only user
+    let mut absorbed = nodes.iter().enumerate()
+        .map(|(index, node)| fusible(index) && node.users.len() == 1 && fusible(node.users[0]))
+        .collect::<Vec<_>>();
+
+    let roots = (0..nodes.len()).filter(|index| fusible(*index) && !absorbed[*index]).collect::<Vec<_>>();
+    let mut groups = HashMap::new();
+    for root in roots {
+        let mut members = vec![];
+        let mut stack = vec![root];
+        while let Some(member) = stack.pop() {
+            members.push(member);
+            stack.extend(nodes[member].operands.iter().copied().filter(|operand| absorbed[*operand]));
+        }
+        let product = members.iter()
+            .flat_map(|member| nodes[*member].operands.iter().copied())
+            .find(|operand| matches!(&*nodes[*operand].plan.source, MatrixOp::Mul { .. }) && nodes[*operand].users.len() == 1);
+        if members.len() < 2 && product.is_none() {
+            continue;
+        }
+        if let Some(product) = product {
+            absorbed[product] = true;
+        }
+        groups.insert(root, product);
+    }
+
+    let mut rebuilt: Vec<Option<MatrixPlan<I>>> = vec![None; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        if absorbed[index] {
+            continue;
+        }
+        let plan = match groups.get(&index) {
+            Some(product) => build_group(&graph, &absorbed, &rebuilt, index, *product),
+            None => {
+                let operands = node.operands.iter().map(|operand| rebuilt[*operand].clone().expect("operand rebuilt")).collect();
+                node.plan.with_operands(operands)
+            },
+        };
+        rebuilt[index] = Some(plan);
+    }
+    rebuilt[graph.root].take().expect("root rebuilt")
+}
+
+fn build_group<I: Scalar>(graph: &PlanGraph<'_, I>, absorbed: &[bool], rebuilt: &[Option<MatrixPlan<I>>], root: usize, product: Option<usize>) -> MatrixPlan<I> {
+    let nodes = &graph.nodes;
+    let mut registers = HashMap::new();
+    let mut arguments = vec![];
+    if let Some(product) = product {
+        registers.insert(product, Register::Argument(0));
+        arguments.push(product);
+    }
+    let mut steps = vec![];
+
+    let mut stack = vec![(root, false)];
+    while let Some((index, expanded)) = stack.pop() {
+        if registers.contains_key(&index) {
+            continue;
+        }
+        if index != root && !absorbed[index] {
+            registers.insert(index, Register::Argument(arguments.len()));
+            arguments.push(index);
+            continue;
+        }
+        if !expanded {
+            stack.push((index, true));
+            stack.extend(nodes[index].operands.iter().rev().map(|operand| (*operand, false)));
+            continue;
+        }
+        steps.push(Step {
+            op: ElementOp::from_op(&nodes[index].plan.source).expect("fused member is element-wise"),
+            sources: nodes[index].operands.iter().map(|operand| registers[operand]).collect(),
+        });
+        registers.insert(index, Register::Step(steps.len() - 1));
+    }
+
+    let kernel = Arc::new(FusedKernel {
+        arguments: arguments.len(),
+        steps,
+    });
+    let argument = |index: &usize| rebuilt[*index].clone().expect("argument rebuilt");
+    let source = match product {
+        Some(product) => MatrixOp::FusedMul {
+            left: argument(&nodes[product].operands[0]),
+            right: argument(&nodes[product].operands[1]),
+            transposed: match &*nodes[product].plan.source {
+                MatrixOp::Mul { transposed, .. } => *transposed,
+                _ => unreachable!("fused product is a mul"),
+            },
+            kernel,
+            inputs: arguments[1..].iter().map(argument).collect(),
+        },
+        None => MatrixOp::Fused {
+            kernel,
+            inputs: arguments.iter().map(argument).collect(),
+        },
+    };
+    MatrixPlan {
+        rows: nodes[root].plan.rows.clone(),
+        cols: nodes[root].plan.cols.clone(),
+        source: Arc::new(source),
+    }
+}
+
+/// Replaces [`MatrixOp::Fused`] and [`MatrixOp::FusedMul`] nodes with the plain ops they were built from
+pub(crate) fn unfuse<I: Scalar>(plan: &MatrixPlan<I>) -> MatrixPlan<I> {
+    let graph = PlanGraph::new(plan);
+    let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let mut operands = node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect::<Vec<_>>();
+        let plan = match &*node.plan.source {
+            MatrixOp::Fused { kernel, .. } => kernel.expand(&operands),
+            MatrixOp::FusedMul { transposed, kernel, .. } => {
+                let inputs = operands.split_off(2);
+                let right = operands.pop().expect("product operand");
+                let product = operands.pop().expect("product operand").try_mul_transposed(&right, *transposed).expect("fused product is well-formed");
+                kernel.expand(&std::iter::once(product).chain(inputs).collect::<Vec<_>>())
+            },
+            _ => node.plan.with_operands(operands),
+        };
+        rebuilt.push(plan);
+    }
+    rebuilt.swap_remove(graph.root)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Matrix, MatrixPlan, plan::{CompileOptions, graph::PlanGraph, op::MatrixOp, testing::{assert_bitwise_eq, assert_same_execution, bind, matrix, sample}}};
+
+    use super::{fuse, unfuse};
+
+    /// Rows of special values, negated on every other row, to meet the scalars and bounds of the ops below from both sides
+    fn non_finite(rows: usize) -> Matrix<f64> {
+        let specials = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0, 0.0, 1.0, -1.0, 0.5];
+        let data = (0..rows).flat_map(|row| specials.map(|x| if row % 2 == 0 { x } else { -x })).collect::<Vec<_>>();
+        matrix(rows, specials.len(), &data)
+    }
+
+    fn fused_nodes(plan: &MatrixPlan<f64>) -> usize {
+        PlanGraph::new(plan).nodes.iter().filter(|node| matches!(&*node.plan.source, MatrixOp::Fused { .. } | MatrixOp::FusedMul { .. })).count()
+    }
+
+    #[test]
+    fn max_replaces_nan_like_the_unfused_op() {
+        let x = MatrixPlan::input(1, 2, "x");
+        let plan = x.scale(2.0).max(0.0).exp().max(0.0);
+        let inputs = bind(&[("x", &matrix(1, 2, &[f64::NAN, 1.0]))]);
+        assert_eq!(fused_nodes(&fuse(&plan)), 1);
+        let (expected, _) = plan.execute_cpu(&inputs);
+        assert_eq!(expected[0][0], 1.0);
+        assert_bitwise_eq(&fuse(&plan).execute_cpu(&inputs).0, &expected);
+        assert_same_execution(&plan, &plan.compile(), &inputs);
+    }
+
+    #[test]
+    fn fused_kernels_match_unfused_ops_on_non_finite_inputs() {
+        let x = MatrixPlan::input(4, 8, "x");
+        let y = MatrixPlan::input(4, 8, "y");
+        let w = MatrixPlan::input(8, 8, "w");
+        let elementwise = x.clone().max(0.0).min(-0.0).clamp(-0.5, 0.0).hadamard_mul(&y).exp().sign()
+            + x.clone().clamp(0.0, 1.0).scale(-1.0).max(-0.0).tanh().greater(&y).select(y.clone().abs().sqrt(), x.clone().min(0.0).sigmoid());
+        // a product epilogue reading the same special values
+        let epilogue = (x.clone() * &w).min(0.5).clamp(f64::NEG_INFINITY, 0.25).hadamard_div(&y).max(-1.0);
+        let plan = (elementwise.output("elementwise") + epilogue).pow(2.0).ln();
+
+        let fused = fuse(&plan);
+        assert!(fused_nodes(&fused) >= 2);
+        let inputs = bind(&[("x", &non_finite(4)), ("y", &non_finite(4).scale(-1.0)), ("w", &sample(8, 8, 1))]);
+        let (expected, expected_outputs) = plan.execute_cpu(&inputs);
+        let (actual, actual_outputs) = fused.execute_cpu(&inputs);
+        assert_bitwise_eq(&actual, &expected);
+        assert_bitwise_eq(&actual_outputs["elementwise"], &expected_outputs["elementwise"]);
+        assert_bitwise_eq(&unfuse(&fused).execute_cpu(&inputs).0, &expected);
+        for fusion in [false, true] {
+            assert_same_execution(&plan, &plan.compile_with(&CompileOptions::default().reassociate(false).fusion(fusion)), &inputs);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{MatrixPlan, Scalar};
+
+/// A plan flattened into one node per unique `MatrixOp`, in topological order (operands before their users).
+pub(crate) struct PlanGraph<'a, I: Scalar> {
+    pub nodes: Vec<GraphNode<'a, I>>,
+    pub root: usize,
+}
+
+pub(crate) struct GraphNode<'a, I: Scalar> {
+    pub plan: &'a MatrixPlan<I>,
+    /// indices of operand nodes, in `MatrixOp::operands` order (repeated if an operand is used twice)
+    pub operands: Vec<usize>,
+    /// indices of nodes reading this one, once per operand edge
+    pub users: Vec<usize>,
+}
+
+impl<'a, I: Scalar> PlanGraph<'a, I> {
+    pub fn new(plan: &'a MatrixPlan<I>) -> Self {
+        let mut nodes: Vec<GraphNode<'a, I>> = vec![];
+        let mut indices: HashMap<*const (), usize> = HashMap::new();
+        // iterative post-order walk, deep plans would overflow the stack otherwise
+        let mut stack = vec![(plan, false)];
+        while let Some((current, expanded)) = stack.pop() {
+            let ptr = Arc::as_ptr(&current.source) as *const ();
+            if indices.contains_key(&ptr) {
+                continue;
+            }
+            let operands = current.source.operands();
+            if !expanded {
+                stack.push((current, true));
+                for operand in operands.into_iter().rev() {
+                    if !indices.contains_key(&(Arc::as_ptr(&operand.source) as *const ())) {
+                        stack.push((operand, false));
+                    }
+                }
+                continue;
+            }
+            let operands = operands.into_iter().map(|operand| indices[&(Arc::as_ptr(&operand.source) as *const ())]).collect::<Vec<_>>();
+            let index = nodes.len();
+            for operand in &operands {
+                nodes[*operand].users.push(index);
+            }
+            indices.insert(ptr, index);
+            nodes.push(GraphNode {
+                plan: current,
+                operands,
+                users: vec![],
+            });
+        }
+        let root = nodes.len() - 1;
+        Self {
+            nodes,
+            root,
+        }
+    }
+}
@@ -0,0 +1,167 @@
+/// What the planner needs to know about one instruction of a tape
+pub(crate) struct Usage {
+    /// components of the result
+    pub size: usize,
+    /// instructions whose results are read, in operand order
+    pub operands: Vec<usize>,
+    /// whether the first operand is an instruction result this instruction may overwrite
+    pub elementwise: bool,
+    /// whether the result must survive until the end of execution (named outputs and the plan root)
+    pub live_out: bool,
+}
+
+/// Static assignment of instruction results to a pool of reusable buffers, derived from the liveness of each result
+pub(crate) struct MemoryPlan {
+    /// buffer each instruction writes its result into
+    pub slots: Vec<usize>,
+    /// whether each instruction overwrites its first operand, which dies there
+    pub in_place: Vec<bool>,
+    /// components of each buffer
+    pub slot_sizes: Vec<usize>,
+    /// largest number of components alive at once
+    pub peak: usize,
+}
+
+impl MemoryPlan {
+    pub fn new(usages: &[Usage]) -> Self {
+        let mut last_use: Vec<usize> = (0..usages.len()).collect();
+        for (index, usage) in usages.iter().enumerate() {
+            for operand in &usage.operands {
+                last_use[*operand] = index;
+            }
+        }
+        for (index, usage) in usages.iter().enumerate() {
+            if usage.live_out {
+                last_use[index] = usize::MAX;
+            }
+        }
+
+        let mut out = Self {
+            slots: Vec::with_capacity(usages.len()),
+            in_place: Vec::with_capacity(usages.len()),
+            slot_sizes: vec![],
+            peak: 0,
+        };
+        let mut free: Vec<usize> = vec![];
+        let mut live = 0usize;
+
+        for (index, usage) in usages.iter().enumerate() {
+            let reused = usage.operands.first().copied().filter(|first| {
+                usage.elementwise
+                    && last_use[*first] == index
+                    && usage.operands.iter().filter(|operand| *operand == first).count() == 1
+            });
+
+            let slot = match reused {
+                Some(first) => out.slots[first],
+                None => match Self::best_fit(&free, &out.slot_sizes, usage.size) {
+                    Some(position) => free.swap_remove(position),
+                    None => {
+                        out.slot_sizes.push(0);
+                        out.slot_sizes.len() - 1
+                    },
+                },
+            };
+            out.slot_sizes[slot] = out.slot_sizes[slot].max(usage.size);
+            out.slots.push(slot);
+            out.in_place.push(reused.is_some());
+
+            // an in-place result takes over its operand's components, which are not counted twice
+            if reused.is_none() {
+                live += usage.size;
+            }
+            out.peak = out.peak.max(live);
+
+            let mut dead = usage.operands.iter().copied().filter(|operand| last_use[*operand] == index).collect::<Vec<_>>();
+            dead.sort_unstable();
+            dead.dedup();
+            if last_use[index] == index {
+                dead.push(index);
+            }
+            for value in dead {
+                if Some(value) != reused {
+                    live -= usages[value].size;
+                    free.push(out.slots[value]);
+                }
+            }
+        }
+        out
+    }
+
+    /// Smallest free buffer holding at least `size` components, or else the largest one (which will grow)
+    fn best_fit(free: &[usize], slot_sizes: &[usize], size: usize) -> Option<usize> {
+        let fitting = free.iter().enumerate()
+            .filter(|(_, slot)| slot_sizes[**slot] >= size)
+            .min_by_key(|(_, slot)| slot_sizes[**slot]);
+        fitting.or_else(|| free.iter().enumerate().max_by_key(|(_, slot)| slot_sizes[**slot]))
+            .map(|(position, _)| position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MatrixPlan, plan::{CompileOptions, testing::{assert_same_execution, bind, sample}}};
+
+    use super::{MemoryPlan, Usage};
+
+    fn usage(size: usize, operands: &[usize], elementwise: bool) -> Usage {
+        Usage { size, operands: operands.to_vec(), elementwise, live_out: false }
+    }
+
+    #[test]
+    fn element_wise_chain_runs_in_place() {
+        let mut usages = vec![usage(6, &[], false), usage(6, &[0], true), usage(6, &[1], true)];
+        usages[2].live_out = true;
+        let plan = MemoryPlan::new(&usages);
+        assert_eq!(plan.in_place, [false, true, true]);
+        assert_eq!(plan.slots, [0, 0, 0]);
+        assert_eq!(plan.slot_sizes, [6]);
+        assert_eq!(plan.peak, 6);
+    }
+
+    #[test]
+    fn operands_read_later_or_twice_are_not_overwritten() {
+        // 1 reads 0 which 2 reads again, 3 reads 2 twice
+        let mut usages = vec![usage(4, &[], false), usage(4, &[0], true), usage(4, &[0, 1], true), usage(4, &[2, 2], true)];
+        usages[3].live_out = true;
+        let plan = MemoryPlan::new(&usages);
+        assert_eq!(plan.in_place, [false, false, true, false]);
+        assert_ne!(plan.slots[1], plan.slots[0]);
+        assert_eq!(plan.slots[2], plan.slots[0]);
+        assert_ne!(plan.slots[3], plan.slots[2]);
+        assert_eq!(plan.peak, 8);
+    }
+
+    #[test]
+    fn dead_buffers_are_reused_and_live_outs_kept() {
+        let mut usages = vec![usage(2, &[], false), usage(8, &[0], false), usage(4, &[1], false), usage(3, &[2], false)];
+        usages[1].live_out = true;
+        let plan = MemoryPlan::new(&usages);
+        // 0 dies at 1 and 2 dies at 3, the live out result of 1 is never handed out again
+        assert_eq!(plan.slots[2], plan.slots[0]);
+        assert_ne!(plan.slots[3], plan.slots[1]);
+        assert!(plan.slots.iter().enumerate().all(|(index, slot)| index == 1 || *slot != plan.slots[1]));
+        assert_eq!(plan.slot_sizes[plan.slots[2]], 4);
+        assert_eq!(plan.peak, 15);
+    }
+
+    #[test]
+    fn shared_and_in_place_operands_match_cpu_evaluation() {
+        let x = MatrixPlan::input(3, 4, "x");
+        let y = MatrixPlan::input(3, 4, "y");
+        let shared = x.clone().hadamard_mul(&y).tanh();
+        let squared = shared.clone().hadamard_mul(&shared);
+        let chain = (squared + &shared).exp().scale(0.25).sigmoid().output("chain");
+        let plan = (chain.clone() - shared).hadamard_div(chain.abs() + x.fill(1.0)) * y.transpose();
+        let inputs = bind(&[("x", &sample(3, 4, 1)), ("y", &sample(3, 4, 2))]);
+        for fusion in [false, true] {
+            let compiled = plan.compile_with(&CompileOptions::default().fusion(fusion));
+            if !fusion {
+                assert!(compiled.memory().in_place > 0);
+            }
+            assert!(compiled.memory().pool_bytes < compiled.memory().unplanned_bytes);
+            assert_same_execution(&plan, &compiled, &inputs);
+            assert_same_execution(&plan, &compiled, &bind(&[("x", &sample(3, 4, 3)), ("y", &sample(3, 4, 4))]));
+        }
+    }
+}
@@ -1,90 +1,194 @@
-use std::{ops::{Mul, Add, Neg, Sub}, sync::Arc, collections::HashMap};
+use std::{ops::{Mul, Add, Neg, Sub, Range}, sync::Arc, collections::HashMap};
 
 use half::f16;
 
-use crate::{Scalar, Matrix};
+use crate::{Scalar, Matrix, MatrixError, ExecuteResult, error::{self, or_panic}};
 
 mod op;
 use op::MatrixOp;
 
+mod dim;
+pub use dim::Dim;
+pub(crate) use dim::Bindings;
+
+mod fusion;
+
+mod custom;
+pub use custom::CustomOp;
+
+mod autodiff;
+pub(crate) use autodiff::PLACEHOLDER;
+
+mod cse;
+
+mod products;
+
+mod signature;
+pub use signature::PlanSignature;
+
+mod optimize;
+pub use optimize::{OptimizeReport, Rewrite};
+
 mod cpu_eval;
 
+mod graph;
+
+mod dot;
+pub use dot::DotOptions;
+
+mod text;
+
+mod profile;
+pub use profile::{Cost, NodeProfile, OpProfile, ProfileReport};
+
+mod trace;
+pub use trace::{ComponentStats, NonFiniteTrace};
+
+mod memory;
+
+mod compiled;
+pub use compiled::{CompiledPlan, CompileOptions, MemoryReport};
+
+#[cfg(feature = "parallel")]
+mod scheduler;
+
+#[cfg(test)]
+pub(crate) mod testing;
+
 #[derive(Clone, Debug)]
 pub struct MatrixPlan<I: Scalar> {
-    rows: usize,
-    cols: usize,
+    rows: Dim,
+    cols: Dim,
     source: Arc<MatrixOp<I>>,
 }
 
 impl<I: Scalar> Default for MatrixPlan<I> {
     fn default() -> Self {
-        Self { rows: 0, cols: 0, source: Arc::new(MatrixOp::Input { name: String::new() }) }
+        Self { rows: Dim::Fixed(0), cols: Dim::Fixed(0), source: Arc::new(MatrixOp::Input { name: String::new() }) }
     }
 }
 
 impl<I: Scalar> MatrixPlan<I> {
-    pub fn input(rows: usize, cols: usize, name: impl AsRef<str>) -> Self {
+    /// An input bound by name at execution, either dimension may be symbolic (e.g. `MatrixPlan::input(3, "batch", "inputs")`)
+    pub fn input(rows: impl Into<Dim>, cols: impl Into<Dim>, name: impl AsRef<str>) -> Self {
         Self {
-            rows,
-            cols,
+            rows: rows.into(),
+            cols: cols.into(),
             source: Arc::new(MatrixOp::Input { name: name.as_ref().to_string() }),
         }
     }
 
     pub fn output(self, name: impl AsRef<str>) -> Self {
         Self {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Output { name: name.as_ref().to_string(), matrix: self }),
         }
     }
 
+    /// The value of `self`, read only once `after` has been evaluated.
+    /// Ops built on it are evaluated again instead of being merged with the same ops on `self`, so a value kept for later can be recomputed from rather than every result along the way being kept.
+    pub fn recompute_after(self, after: impl AsRef<MatrixPlan<I>>) -> Self {
+        Self {
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
+            source: Arc::new(MatrixOp::Recompute { matrix: self, after: after.as_ref().clone() }),
+        }
+    }
+
     pub fn constant(matrix: Matrix<I>) -> Self {
         Self {
-            rows: matrix.rows(),
-            cols: matrix.cols(),
+            rows: Dim::Fixed(matrix.rows()),
+            cols: Dim::Fixed(matrix.cols()),
             source: Arc::new(MatrixOp::Constant {
                 matrix,
             }),
         }
     }
 
+    /// Every component set to `value`, a constant that may have symbolic dimensions
+    pub fn splat(rows: impl Into<Dim>, cols: impl Into<Dim>, value: I) -> Self {
+        let (rows, cols) = (rows.into(), cols.into());
+        match (rows.fixed(), cols.fixed()) {
+            (Some(rows), Some(cols)) => Self::constant(Matrix::new(rows, cols).fill(value)),
+            _ => Self {
+                rows,
+                cols,
+                source: Arc::new(MatrixOp::Fill { value }),
+            },
+        }
+    }
+
+    /// A constant `value` in the shape of this plan, which is not evaluated
+    pub fn fill(&self, value: I) -> Self {
+        Self::splat(&self.rows, &self.cols, value)
+    }
+
     /// Copys outputs from all internal plans, but throws away anonymous outputs
     pub fn merge_outputs(inner: impl IntoIterator<Item=MatrixPlan<I>>) -> MatrixPlan<I> {
         Self {
-            rows: 0,
-            cols: 0,
+            rows: Dim::Fixed(0),
+            cols: Dim::Fixed(0),
             source: Arc::new(MatrixOp::Combine { inner: inner.into_iter().collect() }),
         }
     }
 
+    /// Applies a user-defined op to `operands`, its shape is inferred by [`CustomOp::output_shape`]
+    pub fn custom(op: Arc<dyn CustomOp<I>>, operands: impl IntoIterator<Item=MatrixPlan<I>>) -> Self {
+        or_panic(Self::try_custom(op, operands))
+    }
+
+    /// Fails if the op does not accept the operands
+    pub fn try_custom(op: Arc<dyn CustomOp<I>>, operands: impl IntoIterator<Item=MatrixPlan<I>>) -> Result<Self, MatrixError> {
+        let inputs = operands.into_iter().collect::<Vec<_>>();
+        let shapes = inputs.iter().map(MatrixPlan::dims).collect::<Vec<_>>();
+        let (rows, cols) = op.output_shape(&shapes).ok_or_else(|| MatrixError::CustomOperands { op: op.name().to_string(), operands: shapes })?;
+        Ok(Self {
+            rows,
+            cols,
+            source: Arc::new(MatrixOp::Custom { op, inputs }),
+        })
+    }
+
+    /// The same op reading from `operands` instead, reusing `self` if nothing changed
+    pub(crate) fn with_operands(&self, operands: Vec<MatrixPlan<I>>) -> MatrixPlan<I> {
+        if operands.iter().zip(self.source.operands()).all(|(new, old)| Arc::ptr_eq(&new.source, &old.source)) {
+            return self.clone();
+        }
+        MatrixPlan {
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
+            source: Arc::new(self.source.with_operands(operands)),
+        }
+    }
+
+    /// Panics if the rows are a symbolic dimension, see [`MatrixPlan::dims`]
     pub fn rows(&self) -> usize {
-        self.rows
+        self.rows.fixed().unwrap_or_else(|| panic!("rows are the symbolic dimension '{}'", self.rows))
     }
 
+    /// Panics if the columns are a symbolic dimension, see [`MatrixPlan::dims`]
     pub fn cols(&self) -> usize {
-        self.cols
+        self.cols.fixed().unwrap_or_else(|| panic!("cols are the symbolic dimension '{}'", self.cols))
+    }
+
+    /// Rows and columns, fixed or symbolic
+    pub fn dims(&self) -> (Dim, Dim) {
+        (self.rows.clone(), self.cols.clone())
     }
 
     pub fn hadamard_mul<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Self {
-        let rhs = rhs.as_ref();
-        assert_eq!(self.cols, rhs.cols);
-        assert_eq!(self.rows, rhs.rows);
-        MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
-            source: Arc::new(MatrixOp::HadamardMul {
-                left: self,
-                right: rhs.clone(),
-            }),
-        }
+        or_panic(self.try_hadamard_mul(rhs))
+    }
 
+    pub fn try_hadamard_mul<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "hadamard_mul", |left, right| MatrixOp::HadamardMul { left, right })
     }
 
     pub fn transpose(self) -> Self {
         MatrixPlan {
-            rows: self.cols,
-            cols: self.rows,
+            rows: self.cols.clone(),
+            cols: self.rows.clone(),
             source: Arc::new(MatrixOp::Transpose {
                 matrix: self,
             }),
@@ -93,8 +197,8 @@ impl<I: Scalar> MatrixPlan<I> {
 
     pub fn sign(self) -> Self {
         MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Sign {
                 matrix: self,
             }),
@@ -103,67 +207,749 @@ impl<I: Scalar> MatrixPlan<I> {
 
     pub fn sigmoid(self) -> Self {
         MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Sigmoid {
                 matrix: self,
             }),
         }
     }
 
-    fn inputs_recur<'a>(&'a self, out: &mut Vec<(&'a str, (usize, usize))>) {
+    fn unary(self, source: impl FnOnce(MatrixPlan<I>) -> MatrixOp<I>) -> Self {
+        MatrixPlan {
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
+            source: Arc::new(source(self)),
+        }
+    }
+
+    pub fn exp(self) -> Self {
+        self.unary(|matrix| MatrixOp::Exp { matrix })
+    }
+
+    /// natural logarithm of each component
+    pub fn ln(self) -> Self {
+        self.unary(|matrix| MatrixOp::Log { matrix })
+    }
+
+    pub fn sqrt(self) -> Self {
+        self.unary(|matrix| MatrixOp::Sqrt { matrix })
+    }
+
+    pub fn abs(self) -> Self {
+        self.unary(|matrix| MatrixOp::Abs { matrix })
+    }
+
+    pub fn tanh(self) -> Self {
+        self.unary(|matrix| MatrixOp::Tanh { matrix })
+    }
+
+    /// raises each component to `exponent`
+    pub fn pow(self, exponent: I) -> Self {
+        self.unary(|matrix| MatrixOp::Pow { matrix, exponent })
+    }
+
+    pub fn min(self, rhs: I) -> Self {
+        self.unary(|matrix| MatrixOp::Min { matrix, scalar: rhs })
+    }
+
+    /// clamps each component to `min..=max`
+    pub fn clamp(self, min: I, max: I) -> Self {
+        self.unary(|matrix| MatrixOp::Clamp { matrix, min, max })
+    }
+
+    pub fn reciprocal(self) -> Self {
+        self.unary(|matrix| MatrixOp::Reciprocal { matrix })
+    }
+
+    fn unary_shaped(self, rows: Dim, cols: Dim, source: impl FnOnce(MatrixPlan<I>) -> MatrixOp<I>) -> Self {
+        MatrixPlan {
+            rows,
+            cols,
+            source: Arc::new(source(self)),
+        }
+    }
+
+    /// Adds the rows together into a `1 x cols` row
+    pub fn sum_rows(self) -> Self {
+        let cols = self.cols.clone();
+        self.unary_shaped(Dim::Fixed(1), cols, |matrix| MatrixOp::SumRows { matrix })
+    }
+
+    /// Adds the columns together into a `rows x 1` column
+    pub fn sum_cols(self) -> Self {
+        let rows = self.rows.clone();
+        self.unary_shaped(rows, Dim::Fixed(1), |matrix| MatrixOp::SumCols { matrix })
+    }
+
+    /// Mean of all components, as a 1x1 plan
+    pub fn mean(self) -> Self {
+        self.unary_shaped(Dim::Fixed(1), Dim::Fixed(1), |matrix| MatrixOp::Mean { matrix })
+    }
+
+    /// Largest component of each column, as a `1 x cols` row
+    pub fn max_reduce(self) -> Self {
+        or_panic(self.try_max_reduce())
+    }
+
+    /// Fails if there are no rows to reduce
+    pub fn try_max_reduce(self) -> Result<Self, MatrixError> {
+        if self.rows == 0 {
+            return Err(MatrixError::EmptyReduce { op: "max_reduce" });
+        }
+        let cols = self.cols.clone();
+        Ok(self.unary_shaped(Dim::Fixed(1), cols, |matrix| MatrixOp::MaxReduce { matrix }))
+    }
+
+    /// Row index of the largest component of each column (the first one on ties), as a `1 x cols` row
+    pub fn argmax(self) -> Self {
+        or_panic(self.try_argmax())
+    }
+
+    /// Fails if there are no rows to reduce
+    pub fn try_argmax(self) -> Result<Self, MatrixError> {
+        if self.rows == 0 {
+            return Err(MatrixError::EmptyReduce { op: "argmax" });
+        }
+        let cols = self.cols.clone();
+        Ok(self.unary_shaped(Dim::Fixed(1), cols, |matrix| MatrixOp::ArgMax { matrix }))
+    }
+
+    /// `rows x cols` plan with a one in each column at the row given by the matching component of a `1 x cols` row of indices (e.g. from [`MatrixPlan::argmax`]), zero elsewhere.
+    /// Columns whose index is not a row are all zero.
+    pub fn one_hot(self, rows: impl Into<Dim>) -> Self {
+        or_panic(self.try_one_hot(rows))
+    }
+
+    pub fn try_one_hot(self, rows: impl Into<Dim>) -> Result<Self, MatrixError> {
+        if self.rows != 1 {
+            return Err(MatrixError::ShapeMismatch { op: "one_hot", left: self.dims(), right: (Dim::Fixed(1), self.cols) });
+        }
+        let cols = self.cols.clone();
+        Ok(self.unary_shaped(rows.into(), cols, |matrix| MatrixOp::OneHot { matrix }))
+    }
+
+    /// Repeats a `1 x cols` row `rows` times
+    pub fn broadcast_rows(self, rows: impl Into<Dim>) -> Self {
+        or_panic(self.try_broadcast_rows(rows))
+    }
+
+    pub fn try_broadcast_rows(self, rows: impl Into<Dim>) -> Result<Self, MatrixError> {
+        if self.rows != 1 {
+            return Err(MatrixError::ShapeMismatch { op: "broadcast_rows", left: self.dims(), right: (Dim::Fixed(1), self.cols) });
+        }
+        let cols = self.cols.clone();
+        Ok(self.unary_shaped(rows.into(), cols, |matrix| MatrixOp::BroadcastRows { matrix }))
+    }
+
+    /// Repeats a `rows x 1` column `cols` times, e.g. to add a bias to every column of a batch
+    pub fn broadcast_cols(self, cols: impl Into<Dim>) -> Self {
+        or_panic(self.try_broadcast_cols(cols))
+    }
+
+    pub fn try_broadcast_cols(self, cols: impl Into<Dim>) -> Result<Self, MatrixError> {
+        if self.cols != 1 {
+            return Err(MatrixError::ShapeMismatch { op: "broadcast_cols", left: self.dims(), right: (self.rows, Dim::Fixed(1)) });
+        }
+        let rows = self.rows.clone();
+        Ok(self.unary_shaped(rows, cols.into(), |matrix| MatrixOp::BroadcastCols { matrix }))
+    }
+
+    /// Softmax of each column, computed with the column maximum subtracted
+    pub fn softmax(self) -> Self {
+        self.unary(|matrix| MatrixOp::Softmax { matrix })
+    }
+
+    /// Logarithm of the softmax of each column, without evaluating the softmax itself
+    pub fn log_softmax(self) -> Self {
+        self.unary(|matrix| MatrixOp::LogSoftmax { matrix })
+    }
+
+    /// The same components in row-major order as a `rows x cols` plan
+    /// Both shapes must be fixed, unless they are equal
+    pub fn reshape(self, rows: impl Into<Dim>, cols: impl Into<Dim>) -> Self {
+        or_panic(self.try_reshape(rows, cols))
+    }
+
+    pub fn try_reshape(self, rows: impl Into<Dim>, cols: impl Into<Dim>) -> Result<Self, MatrixError> {
+        let (rows, cols) = (rows.into(), cols.into());
+        let fits = match (self.rows.fixed(), self.cols.fixed(), rows.fixed(), cols.fixed()) {
+            (Some(from_rows), Some(from_cols), Some(to_rows), Some(to_cols)) => from_rows * from_cols == to_rows * to_cols,
+            _ => (&self.rows, &self.cols) == (&rows, &cols),
+        };
+        if !fits {
+            return Err(MatrixError::ShapeMismatch { op: "reshape", left: self.dims(), right: (rows, cols) });
+        }
+        Ok(self.unary_shaped(rows, cols, |matrix| MatrixOp::Reshape { matrix }))
+    }
+
+    /// The sliced axes must be fixed
+    pub fn slice(self, rows: Range<usize>, cols: Range<usize>) -> Self {
+        or_panic(self.try_slice(rows, cols))
+    }
+
+    /// Fails with the bounds the ranges need if they do not fit, or are decreasing
+    pub fn try_slice(self, rows: Range<usize>, cols: Range<usize>) -> Result<Self, MatrixError> {
+        self.try_slice_axes("slice", Some(rows), Some(cols))
+    }
+
+    /// Band of rows keeping every column, which may be symbolic
+    pub fn slice_rows(self, rows: Range<usize>) -> Self {
+        or_panic(self.try_slice_rows(rows))
+    }
+
+    pub fn try_slice_rows(self, rows: Range<usize>) -> Result<Self, MatrixError> {
+        self.try_slice_axes("slice_rows", Some(rows), None)
+    }
+
+    /// Band of columns keeping every row, which may be symbolic
+    pub fn slice_cols(self, cols: Range<usize>) -> Self {
+        or_panic(self.try_slice_cols(cols))
+    }
+
+    pub fn try_slice_cols(self, cols: Range<usize>) -> Result<Self, MatrixError> {
+        self.try_slice_axes("slice_cols", None, Some(cols))
+    }
+
+    fn try_slice_axes(self, op: &'static str, rows: Option<Range<usize>>, cols: Option<Range<usize>>) -> Result<Self, MatrixError> {
+        let mut fits = true;
+        for (range, dim) in [(&rows, &self.rows), (&cols, &self.cols)] {
+            if let Some(range) = range {
+                let size = dim.fixed().ok_or_else(|| MatrixError::SymbolicDim { op, dim: dim.clone() })?;
+                fits &= range.start <= range.end && range.end <= size;
+            }
+        }
+        let bound = |range: &Option<Range<usize>>, dim: &Dim| range.as_ref().map_or(dim.clone(), |range| Dim::Fixed(range.start.max(range.end)));
+        if !fits {
+            return Err(MatrixError::ShapeMismatch { op, left: self.dims(), right: (bound(&rows, &self.rows), bound(&cols, &self.cols)) });
+        }
+        let length = |range: &Option<Range<usize>>, dim: &Dim| range.as_ref().map_or(dim.clone(), |range| Dim::Fixed(range.len()));
+        let (sliced_rows, sliced_cols) = (length(&rows, &self.rows), length(&cols, &self.cols));
+        Ok(self.unary_shaped(sliced_rows, sliced_cols, |matrix| MatrixOp::Slice { matrix, rows, cols }))
+    }
+
+    /// Splits into consecutive row bands of the given heights, which must add up to the row count.
+    /// Sugar over one [`MatrixPlan::slice_rows`] per band, so the rows must be fixed.
+    pub fn split_rows(&self, heights: &[usize]) -> Vec<Self> {
+        or_panic(self.try_split_rows(heights))
+    }
+
+    /// Fails if the rows are symbolic, or if `heights` does not add up to them
+    pub fn try_split_rows(&self, heights: &[usize]) -> Result<Vec<Self>, MatrixError> {
+        let rows = self.rows.fixed().ok_or_else(|| MatrixError::SymbolicDim { op: "split_rows", dim: self.rows.clone() })?;
+        let total = heights.iter().sum::<usize>();
+        if total != rows {
+            return Err(MatrixError::ShapeMismatch { op: "split_rows", left: self.dims(), right: (Dim::Fixed(total), self.cols.clone()) });
+        }
+        let mut start = 0;
+        heights.iter().map(|height| {
+            start += height;
+            self.clone().try_slice_rows(start - height..start)
+        }).collect()
+    }
+
+    /// Splits into consecutive column bands of the given widths, which must add up to the column count.
+    /// Sugar over one [`MatrixPlan::slice_cols`] per band, so the columns must be fixed.
+    pub fn split_cols(&self, widths: &[usize]) -> Vec<Self> {
+        or_panic(self.try_split_cols(widths))
+    }
+
+    /// Fails if the columns are symbolic, or if `widths` does not add up to them
+    pub fn try_split_cols(&self, widths: &[usize]) -> Result<Vec<Self>, MatrixError> {
+        let cols = self.cols.fixed().ok_or_else(|| MatrixError::SymbolicDim { op: "split_cols", dim: self.cols.clone() })?;
+        let total = widths.iter().sum::<usize>();
+        if total != cols {
+            return Err(MatrixError::ShapeMismatch { op: "split_cols", left: self.dims(), right: (self.rows.clone(), Dim::Fixed(total)) });
+        }
+        let mut start = 0;
+        widths.iter().map(|width| {
+            start += width;
+            self.clone().try_slice_cols(start - width..start)
+        }).collect()
+    }
+
+    /// Stacks `parts` on top of each other, they must have the same column count and fixed row counts
+    pub fn concat_rows(parts: impl IntoIterator<Item=MatrixPlan<I>>) -> Self {
+        or_panic(Self::try_concat_rows(parts))
+    }
+
+    /// Fails if `parts` is empty, or if their shapes do not line up
+    pub fn try_concat_rows(parts: impl IntoIterator<Item=MatrixPlan<I>>) -> Result<Self, MatrixError> {
+        let inner = parts.into_iter().collect::<Vec<_>>();
+        if inner.is_empty() {
+            return Err(MatrixError::EmptyConcat { op: "concat_rows" });
+        }
+        let cols = inner[0].cols.clone();
+        if let Some(part) = inner.iter().find(|part| part.cols != cols) {
+            return Err(MatrixError::ShapeMismatch { op: "concat_rows", left: inner[0].dims(), right: part.dims() });
+        }
+        let mut rows = 0;
+        for part in &inner {
+            rows += part.rows.fixed().ok_or_else(|| MatrixError::SymbolicDim { op: "concat_rows", dim: part.rows.clone() })?;
+        }
+        Ok(MatrixPlan {
+            rows: Dim::Fixed(rows),
+            cols,
+            source: Arc::new(MatrixOp::ConcatRows { inner }),
+        })
+    }
+
+    /// Places `parts` side by side, they must have the same row count and fixed column counts
+    pub fn concat_cols(parts: impl IntoIterator<Item=MatrixPlan<I>>) -> Self {
+        or_panic(Self::try_concat_cols(parts))
+    }
+
+    /// Fails if `parts` is empty, or if their shapes do not line up
+    pub fn try_concat_cols(parts: impl IntoIterator<Item=MatrixPlan<I>>) -> Result<Self, MatrixError> {
+        let inner = parts.into_iter().collect::<Vec<_>>();
+        if inner.is_empty() {
+            return Err(MatrixError::EmptyConcat { op: "concat_cols" });
+        }
+        let rows = inner[0].rows.clone();
+        if let Some(part) = inner.iter().find(|part| part.rows != rows) {
+            return Err(MatrixError::ShapeMismatch { op: "concat_cols", left: inner[0].dims(), right: part.dims() });
+        }
+        let mut cols = 0;
+        for part in &inner {
+            cols += part.cols.fixed().ok_or_else(|| MatrixError::SymbolicDim { op: "concat_cols", dim: part.cols.clone() })?;
+        }
+        Ok(MatrixPlan {
+            rows,
+            cols: Dim::Fixed(cols),
+            source: Arc::new(MatrixOp::ConcatCols { inner }),
+        })
+    }
+
+    fn try_binary(self, rhs: &MatrixPlan<I>, op: &'static str, source: impl FnOnce(MatrixPlan<I>, MatrixPlan<I>) -> MatrixOp<I>) -> Result<Self, MatrixError> {
+        if (&self.rows, &self.cols) != (&rhs.rows, &rhs.cols) {
+            return Err(MatrixError::ShapeMismatch { op, left: self.dims(), right: rhs.dims() });
+        }
+        Ok(MatrixPlan {
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
+            source: Arc::new(source(self, rhs.clone())),
+        })
+    }
+
+    /// 1 where a component is greater than the matching component of `rhs`, 0 elsewhere
+    pub fn greater<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Self {
+        or_panic(self.try_greater(rhs))
+    }
+
+    pub fn try_greater<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "greater", |left, right| MatrixOp::Greater { left, right })
+    }
+
+    /// 1 where a component is less than the matching component of `rhs`, 0 elsewhere
+    pub fn less<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Self {
+        or_panic(self.try_less(rhs))
+    }
+
+    pub fn try_less<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "less", |left, right| MatrixOp::Less { left, right })
+    }
+
+    /// 1 where a component equals the matching component of `rhs`, 0 elsewhere
+    pub fn equal<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Self {
+        or_panic(self.try_equal(rhs))
+    }
+
+    pub fn try_equal<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "equal", |left, right| MatrixOp::Equal { left, right })
+    }
+
+    /// Treating `self` as a mask, takes components of `then` where it is non-zero and of `otherwise` elsewhere.
+    /// No gradient flows into the mask.
+    pub fn select<T: AsRef<MatrixPlan<I>>, E: AsRef<MatrixPlan<I>>>(self, then: T, otherwise: E) -> Self {
+        or_panic(self.try_select(then, otherwise))
+    }
+
+    pub fn try_select<T: AsRef<MatrixPlan<I>>, E: AsRef<MatrixPlan<I>>>(self, then: T, otherwise: E) -> Result<Self, MatrixError> {
+        let (then, otherwise) = (then.as_ref(), otherwise.as_ref());
+        for branch in [then, otherwise] {
+            if (&self.rows, &self.cols) != (&branch.rows, &branch.cols) {
+                return Err(MatrixError::ShapeMismatch { op: "select", left: self.dims(), right: branch.dims() });
+            }
+        }
+        Ok(MatrixPlan {
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
+            source: Arc::new(MatrixOp::Where {
+                cond: self,
+                then: then.clone(),
+                otherwise: otherwise.clone(),
+            }),
+        })
+    }
+
+    pub fn hadamard_div<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Self {
+        or_panic(self.try_hadamard_div(rhs))
+    }
+
+    pub fn try_hadamard_div<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "hadamard_div", |left, right| MatrixOp::Div { left, right })
+    }
+
+    pub fn try_add<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "add", |left, right| MatrixOp::Add { left, right })
+    }
+
+    pub fn try_sub<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_binary(rhs.as_ref(), "sub", |left, right| MatrixOp::Sub { left, right })
+    }
+
+    /// Matrix product, the column count of `self` must match the row count of `rhs`
+    pub fn try_mul<M: AsRef<MatrixPlan<I>>>(self, rhs: M) -> Result<Self, MatrixError> {
+        self.try_mul_transposed(rhs.as_ref(), (false, false))
+    }
+
+    /// Matrix product reading `self` and `rhs` transposed as flagged in `transposed`, without materializing the transposes
+    pub(crate) fn try_mul_transposed(self, rhs: &MatrixPlan<I>, transposed: (bool, bool)) -> Result<Self, MatrixError> {
+        let oriented = |plan: &MatrixPlan<I>, transposed: bool| if transposed {
+            (plan.cols.clone(), plan.rows.clone())
+        } else {
+            plan.dims()
+        };
+        let (left, right) = (oriented(&self, transposed.0), oriented(rhs, transposed.1));
+        if left.1 != right.0 {
+            return Err(MatrixError::ShapeMismatch { op: "mul", left, right });
+        }
+        Ok(MatrixPlan {
+            rows: left.0,
+            cols: right.1,
+            source: Arc::new(MatrixOp::Mul {
+                left: self,
+                right: rhs.clone(),
+                transposed,
+            }),
+        })
+    }
+
+    fn inputs_recur<'a>(&'a self, out: &mut Vec<(&'a str, (Dim, Dim))>) {
         match &*self.source {
             MatrixOp::Input { name } => {
-                out.push((name, (self.rows, self.cols)));
+                out.push((name, self.dims()));
             },
-            MatrixOp::Constant { .. } => (),
+            MatrixOp::Constant { .. } |
+            MatrixOp::Fill { .. } => (),
             MatrixOp::Output { matrix, .. } |
             MatrixOp::Scale { matrix, .. } |
             MatrixOp::Max { matrix, .. } |
             MatrixOp::Neg { matrix } |
             MatrixOp::Transpose { matrix } |
             MatrixOp::Sigmoid { matrix } |
-            MatrixOp::Sign { matrix } => {
+            MatrixOp::Sign { matrix } |
+            MatrixOp::Exp { matrix } |
+            MatrixOp::Log { matrix } |
+            MatrixOp::Sqrt { matrix } |
+            MatrixOp::Abs { matrix } |
+            MatrixOp::Tanh { matrix } |
+            MatrixOp::Pow { matrix, .. } |
+            MatrixOp::Min { matrix, .. } |
+            MatrixOp::Clamp { matrix, .. } |
+            MatrixOp::Reciprocal { matrix } |
+            MatrixOp::SumRows { matrix } |
+            MatrixOp::SumCols { matrix } |
+            MatrixOp::Mean { matrix } |
+            MatrixOp::MaxReduce { matrix } |
+            MatrixOp::ArgMax { matrix } |
+            MatrixOp::OneHot { matrix } |
+            MatrixOp::BroadcastRows { matrix } |
+            MatrixOp::BroadcastCols { matrix } |
+            MatrixOp::Softmax { matrix } |
+            MatrixOp::LogSoftmax { matrix } |
+            MatrixOp::Reshape { matrix } |
+            MatrixOp::Slice { matrix, .. } => {
                 matrix.inputs_recur(out);
             },
             MatrixOp::Add { left, right } |
             MatrixOp::Sub { left, right } |
+            MatrixOp::Div { left, right } |
+            MatrixOp::Greater { left, right } |
+            MatrixOp::Less { left, right } |
+            MatrixOp::Equal { left, right } |
             MatrixOp::HadamardMul { left, right } |
-            MatrixOp::Mul { left, right } => {
+            MatrixOp::Mul { left, right, .. } |
+            MatrixOp::Recompute { matrix: left, after: right } => {
                 left.inputs_recur(out);
                 right.inputs_recur(out);
             },
-            MatrixOp::Combine { inner } => {
+            MatrixOp::Where { cond, then, otherwise } => {
+                cond.inputs_recur(out);
+                then.inputs_recur(out);
+                otherwise.inputs_recur(out);
+            },
+            MatrixOp::Combine { inner } |
+            MatrixOp::ConcatRows { inner } |
+            MatrixOp::ConcatCols { inner } |
+            MatrixOp::Custom { inputs: inner, .. } |
+            MatrixOp::Fused { inputs: inner, .. } => {
                 for inner in inner {
                     inner.inputs_recur(out);
                 }
             },
+            MatrixOp::FusedMul { left, right, inputs, .. } => {
+                left.inputs_recur(out);
+                right.inputs_recur(out);
+                for inner in inputs {
+                    inner.inputs_recur(out);
+                }
+            },
         }
     }
 
-    pub fn inputs(&self) -> Vec<(&str, (usize, usize))> {
+    /// Every input read by the plan, once per occurrence in the tree, see [`MatrixPlan::signature`] for a deduplicated listing
+    pub fn inputs(&self) -> Vec<(&str, (Dim, Dim))> {
         let mut out = vec![];
         self.inputs_recur(&mut out);
         out
     }
 
+    /// Each named input and output once, with its shape
+    pub fn signature(&self) -> PlanSignature {
+        PlanSignature::new(self)
+    }
+
+    /// Checks `inputs` before any computation, see [`PlanSignature::validate`]
+    pub fn validate(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> Result<(), Vec<MatrixError>> {
+        self.signature().validate(inputs)
+    }
+
+    /// Names of the symbolic dimensions used anywhere in the plan, in order of first use
+    pub fn symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = vec![];
+        for node in graph::PlanGraph::new(self).nodes {
+            for dim in [&node.plan.rows, &node.plan.cols] {
+                if let Dim::Symbol(name) = dim {
+                    if !symbols.iter().any(|symbol| **symbol == **name) {
+                        symbols.push(name.to_string());
+                    }
+                }
+            }
+        }
+        symbols
+    }
+
+    /// The same plan with each symbolic dimension replaced by its size in `bindings`, panics if one is left unbound
+    pub fn resolve(&self, bindings: &[(&str, usize)]) -> MatrixPlan<I> {
+        or_panic(self.try_resolve(bindings))
+    }
+
+    pub fn try_resolve(&self, bindings: &[(&str, usize)]) -> Result<MatrixPlan<I>, MatrixError> {
+        self.resolve_with(&bindings.iter().map(|(name, size)| (name.to_string(), *size)).collect())
+    }
+
+    fn resolve_with(&self, bindings: &Bindings) -> Result<MatrixPlan<I>, MatrixError> {
+        let graph = graph::PlanGraph::new(self);
+        let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+        for node in &graph.nodes {
+            let operands = node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect::<Vec<_>>();
+            let plan = node.plan;
+            if !plan.rows.is_symbolic() && !plan.cols.is_symbolic() {
+                rebuilt.push(plan.with_operands(operands));
+                continue;
+            }
+            let resolve = |dim: &Dim| dim.resolve(bindings).ok_or_else(|| MatrixError::UnboundDim { name: dim.to_string() });
+            let (rows, cols) = (resolve(&plan.rows)?, resolve(&plan.cols)?);
+            rebuilt.push(match &*plan.source {
+                MatrixOp::Fill { value } => MatrixPlan::constant(Matrix::new(rows, cols).fill(*value)),
+                source => MatrixPlan {
+                    rows: Dim::Fixed(rows),
+                    cols: Dim::Fixed(cols),
+                    source: Arc::new(source.with_operands(operands)),
+                },
+            });
+        }
+        Ok(rebuilt.swap_remove(graph.root))
+    }
+
+    /// Resolves the symbolic dimensions from the shapes of the bound inputs
+    fn resolve_inputs(&self, inputs: &HashMap<&str, &Matrix<I>>) -> Result<MatrixPlan<I>, MatrixError> {
+        self.resolve_with(&dim::bind(self.signature().inputs(), inputs))
+    }
+
+    /// Symbolic dimensions are resolved from the shapes of the bound inputs
     pub fn execute_cpu(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
         let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
-        cpu_eval::MatrixPlanCPUContext::execute(self, &inputs)
+        let plan = or_panic(self.resolve_inputs(&inputs));
+        cpu_eval::MatrixPlanCPUContext::execute(&plan, &inputs)
+    }
+
+    /// Same as [`MatrixPlan::execute_cpu`], also reporting the time and estimated cost of each node and of each op kind
+    pub fn execute_cpu_profiled(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> (Matrix<I>, HashMap<String, Matrix<I>>, ProfileReport) {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let plan = or_panic(self.resolve_inputs(&inputs));
+        let (result, outputs, nodes) = cpu_eval::MatrixPlanCPUContext::execute_profiled(&plan, &inputs);
+        (result, outputs, ProfileReport::new(nodes))
+    }
+
+    /// Estimated floating point operations and result sizes of evaluating each unique node once, without executing the plan.
+    /// Symbolic dimensions must be resolved first, see [`MatrixPlan::resolve`].
+    pub fn cost(&self) -> Cost {
+        graph::PlanGraph::new(self).nodes.iter().fold(Cost::default(), |total, node| total + profile::node_cost(node.plan))
+    }
+
+    /// Same as [`MatrixPlan::execute_cpu`], but fails instead of panicking on missing or mis-shaped inputs,
+    /// and if the result or a named output contains a NaN
+    pub fn try_execute_cpu(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> ExecuteResult<I> {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let signature = self.signature();
+        let bindings = dim::bind(signature.inputs(), &inputs);
+        error::check_inputs(signature.inputs(), &bindings, &inputs)?;
+        let plan = self.resolve_with(&bindings)?;
+        error::check_nan(cpu_eval::MatrixPlanCPUContext::execute(&plan, &inputs))
+    }
+
+    /// Debugging counterpart of [`MatrixPlan::try_execute_cpu`], checking every intermediate result.
+    /// Fails on the first node producing a NaN or infinite component, with its op, operands and the named inputs and outputs around it.
+    pub fn try_execute_cpu_checked(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> ExecuteResult<I> {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let signature = self.signature();
+        let bindings = dim::bind(signature.inputs(), &inputs);
+        error::check_inputs(signature.inputs(), &bindings, &inputs)?;
+        trace::execute(&self.resolve_with(&bindings)?, &inputs)
+    }
+
+    /// Gradients of the sum of all components of this plan (the value itself for a 1x1 plan) with respect to each named input, in `wrt` order
+    pub fn gradients(&self, wrt: &[&str]) -> Vec<MatrixPlan<I>> {
+        let seed = MatrixPlan::splat(self.rows.clone(), self.cols.clone(), I::ONE);
+        self.gradients_seeded(seed, wrt)
+    }
+
+    /// Gradients with respect to each named input given `seed`, the gradient of some loss with respect to this plan (a vector-Jacobian product)
+    pub fn gradients_seeded(&self, seed: MatrixPlan<I>, wrt: &[&str]) -> Vec<MatrixPlan<I>> {
+        autodiff::gradients(self, seed, wrt)
+    }
+
+    /// Derivative of this plan in the direction of `tangents`, given per named input (a Jacobian-vector product, forward mode).
+    /// Inputs without a tangent are held constant.
+    pub fn jvp(&self, tangents: &[(&str, MatrixPlan<I>)]) -> MatrixPlan<I> {
+        autodiff::jacobian_vector_product(self, tangents)
+    }
+
+    /// Hessian of the sum of all components of this plan with respect to the named inputs, times the vector `vectors` (given per input).
+    /// Returns one plan per input, in `vectors` order.
+    pub fn hvp(&self, vectors: &[(&str, MatrixPlan<I>)]) -> Vec<MatrixPlan<I>> {
+        autodiff::hessian_vector_product(self, vectors)
+    }
+
+    /// Full Jacobian at `inputs` of this plan's components (row-major) with respect to the components of the input `wrt`, one row per output component
+    pub fn jacobian_cpu(&self, wrt: &str, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> Matrix<I> {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        autodiff::jacobian_cpu(&or_panic(self.resolve_inputs(&inputs)), wrt, &inputs)
+    }
+
+    /// Replaces every input named `name` with `with`
+    pub fn substitute(&self, name: &str, with: &MatrixPlan<I>) -> MatrixPlan<I> {
+        or_panic(self.try_substitute(name, with))
+    }
+
+    /// Fails if an input named `name` does not have the shape of `with`
+    pub fn try_substitute(&self, name: &str, with: &MatrixPlan<I>) -> Result<MatrixPlan<I>, MatrixError> {
+        let graph = graph::PlanGraph::new(self);
+        let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+        for node in &graph.nodes {
+            let plan = match &*node.plan.source {
+                MatrixOp::Input { name: input } if input == name => {
+                    if node.plan.dims() != with.dims() {
+                        return Err(MatrixError::ShapeMismatch { op: "substitute", left: node.plan.dims(), right: with.dims() });
+                    }
+                    with.clone()
+                },
+                _ => node.plan.with_operands(node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect()),
+            };
+            rebuilt.push(plan);
+        }
+        Ok(rebuilt.swap_remove(graph.root))
+    }
+
+    /// Lowers this plan into a reusable instruction tape, for plans executed many times with new inputs.
+    /// Symbolic dimensions must be resolved first, see [`MatrixPlan::resolve`].
+    pub fn compile(&self) -> CompiledPlan<I> {
+        self.compile_with(&CompileOptions::default())
+    }
+
+    pub fn compile_with(&self, options: &CompileOptions) -> CompiledPlan<I> {
+        CompiledPlan::new(self, options)
+    }
+
+    /// Simplifies the plan: folds constant subtrees, drops identity ops (`x ⊙ 1`, `x + 0`, double transposes), merges scales, pushes negations into subtractions and lets products read transposed operands in place
+    pub fn optimize(&self) -> MatrixPlan<I> {
+        self.optimize_with_report().0
+    }
+
+    /// Same as [`MatrixPlan::optimize`], also reporting which rewrites fired
+    pub fn optimize_with_report(&self) -> (MatrixPlan<I>, OptimizeReport) {
+        optimize::optimize(self)
+    }
+
+    /// Merges structurally identical subgraphs built separately (e.g. the `sigmoid` of a layer in both the forward pass and its derivative), so each is evaluated once
+    pub fn eliminate_common_subexpressions(&self) -> MatrixPlan<I> {
+        cse::eliminate_common_subexpressions(self)
+    }
+
+    /// Reorders chains of products (e.g. `a * b * c` into `a * (b * c)`) to need the fewest multiply-adds given their shapes.
+    /// Chains with symbolic shapes are kept, and results may differ from the original order by rounding.
+    pub fn reassociate_products(&self) -> MatrixPlan<I> {
+        products::reassociate(self)
+    }
+
+    /// Collapses runs of element-wise ops into kernels evaluated in one pass over memory, and fuses a product with the element-wise ops applied to it (bias add, activation)
+    pub fn fuse(&self) -> MatrixPlan<I> {
+        fusion::fuse(self)
+    }
+
+    /// The plan as a Graphviz graph (e.g. for `dot -Tsvg`) with one node per unique op, showing its kind, shape, names and constants, and named outputs filled
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&DotOptions::default())
+    }
+
+    /// Same as [`MatrixPlan::to_dot`], with the subgraphs of some outputs shaded, e.g. `DotOptions::default().highlight("gradient_0")` for the backward pass of a layer
+    pub fn to_dot_with(&self, options: &DotOptions) -> String {
+        dot::to_dot(self, options)
+    }
+
+    /// The plan in a line-based SSA text format with one statement per unique op (e.g. `%3 = mul %weights_0, %inputs : 3xbatch`), for diffing, review and golden files.
+    /// Fused kernels are printed as the ops they were fused from.
+    pub fn to_text(&self) -> String {
+        text::print(self)
+    }
+
+    /// Parses the format of [`MatrixPlan::to_text`] into an equivalent plan, the last statement being the result.
+    /// Blank lines and lines starting with `#` are skipped. Plans with custom ops need [`MatrixPlan::from_text_with`].
+    pub fn from_text(text: &str) -> Result<Self, MatrixError> {
+        text::parse(text, &[])
+    }
+
+    /// Same as [`MatrixPlan::from_text`], finding custom ops in `ops` by [`CustomOp::name`]
+    pub fn from_text_with(text: &str, ops: &[Arc<dyn CustomOp<I>>]) -> Result<Self, MatrixError> {
+        text::parse(text, ops)
+    }
+
+    /// Largest total size in bytes of intermediate results alive at once when executed as a [`CompiledPlan`]
+    pub fn peak_memory(&self) -> usize {
+        self.compile().memory().peak_bytes
+    }
+
+    /// Same as [`MatrixPlan::execute_cpu`], but independent nodes (e.g. the per-layer gradients of a backprop plan) are evaluated concurrently on the `parallel` thread pool.
+    #[cfg(feature = "parallel")]
+    pub fn execute_cpu_concurrent(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        scheduler::execute(&or_panic(self.resolve_inputs(&inputs)), &inputs)
     }
 
     pub fn scale(self, rhs: I) -> Self {
         MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Scale { matrix: self, scalar: rhs }),
         }
     }
 
     pub fn max(self, rhs: I) -> Self {
         MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Max { matrix: self, scalar: rhs }),
         }
     }
@@ -182,8 +968,8 @@ macro_rules! mul_impl {
         
             fn mul(self, rhs: $scalar) -> Self::Output {
                 MatrixPlan {
-                    rows: self.rows,
-                    cols: self.cols,
+                    rows: self.rows.clone(),
+                    cols: self.cols.clone(),
                     source: Arc::new(MatrixOp::Scale { matrix: self, scalar: rhs }),
                 }
             }
@@ -207,18 +993,7 @@ impl<I: Scalar, M: AsRef<MatrixPlan<I>>> Mul<M> for MatrixPlan<I> {
     type Output = MatrixPlan<I>;
 
     fn mul(self, rhs: M) -> Self::Output {
-        let rhs = rhs.as_ref();
-        if self.cols != rhs.rows {
-            panic!("cannot multiply _x{} by {}x_ matrix", self.cols, rhs.rows);
-        }
-        MatrixPlan {
-            rows: self.rows,
-            cols: rhs.cols,
-            source: Arc::new(MatrixOp::Mul {
-                left: self,
-                right: rhs.clone(),
-            }),
-        }
+        or_panic(self.try_mul(rhs))
     }
 }
 
@@ -226,17 +1001,7 @@ impl<I: Scalar, M: AsRef<MatrixPlan<I>>> Add<M> for MatrixPlan<I> {
     type Output = MatrixPlan<I>;
 
     fn add(self, rhs: M) -> Self::Output {
-        let rhs = rhs.as_ref();
-        assert_eq!(self.rows, rhs.rows);
-        assert_eq!(self.cols, rhs.cols);
-        MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
-            source: Arc::new(MatrixOp::Add {
-                left: self,
-                right: rhs.clone(),
-            }),
-        }
+        or_panic(self.try_add(rhs))
     }
 
 }
@@ -246,8 +1011,8 @@ impl<I: Scalar> Neg for MatrixPlan<I> {
 
     fn neg(self) -> Self::Output {
         MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
+            rows: self.rows.clone(),
+            cols: self.cols.clone(),
             source: Arc::new(MatrixOp::Neg {
                 matrix: self,
             }),
@@ -259,16 +1024,106 @@ impl<I: Scalar, M: AsRef<MatrixPlan<I>>> Sub<M> for MatrixPlan<I> {
     type Output = MatrixPlan<I>;
 
     fn sub(self, rhs: M) -> Self::Output {
-        let rhs = rhs.as_ref();
-        assert_eq!(self.rows, rhs.rows);
-        assert_eq!(self.cols, rhs.cols);
-        MatrixPlan {
-            rows: self.rows,
-            cols: self.cols,
-            source: Arc::new(MatrixOp::Sub {
-                left: self,
-                right: rhs.clone(),
-            }),
-        }
+        or_panic(self.try_sub(rhs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Dim, MatrixError, MatrixPlan, plan::testing::{assert_bitwise_eq, bind, matrix}};
+
+    #[test]
+    fn concatenating_nothing_fails() {
+        assert_eq!(MatrixPlan::<f64>::try_concat_rows([]).unwrap_err(), MatrixError::EmptyConcat { op: "concat_rows" });
+        assert_eq!(MatrixPlan::<f64>::try_concat_cols([]).unwrap_err(), MatrixError::EmptyConcat { op: "concat_cols" });
+    }
+
+    #[test]
+    #[should_panic(expected = "concat_cols needs at least one part")]
+    fn concat_of_nothing_panics_with_the_error() {
+        MatrixPlan::<f64>::concat_cols([]);
+    }
+
+    #[test]
+    fn concatenated_shapes_must_line_up() {
+        let (x, y) = (MatrixPlan::<f64>::input(2, 3, "x"), MatrixPlan::input(3, 2, "y"));
+        assert_eq!(MatrixPlan::try_concat_rows([x.clone(), y.clone()]).unwrap_err(), MatrixError::ShapeMismatch {
+            op: "concat_rows",
+            left: (Dim::Fixed(2), Dim::Fixed(3)),
+            right: (Dim::Fixed(3), Dim::Fixed(2)),
+        });
+        assert!(MatrixPlan::try_concat_cols([x.clone(), y.transpose()]).is_ok());
+        let batch = MatrixPlan::input(3, "batch", "batch");
+        assert!(matches!(MatrixPlan::try_concat_cols([x.transpose(), batch]), Err(MatrixError::SymbolicDim { op: "concat_cols", .. })));
+    }
+
+    #[test]
+    fn reshape_keeps_row_major_order() {
+        let x = MatrixPlan::<f64>::input(2, 3, "x");
+        let inputs = bind(&[("x", &matrix(2, 3, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]))]);
+        let reshaped = x.clone().reshape(3, 2);
+        assert_eq!(reshaped.dims(), (Dim::Fixed(3), Dim::Fixed(2)));
+        assert_bitwise_eq(&reshaped.execute_cpu(&inputs).0, &matrix(3, 2, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+        assert_bitwise_eq(&x.clone().reshape(1, 6).reshape(2, 3).execute_cpu(&inputs).0, &inputs["x"]);
+        assert_eq!(x.try_reshape(4, 2).unwrap_err(), MatrixError::ShapeMismatch {
+            op: "reshape",
+            left: (Dim::Fixed(2), Dim::Fixed(3)),
+            right: (Dim::Fixed(4), Dim::Fixed(2)),
+        });
+        // symbolic shapes can only be reshaped into themselves
+        let batch = MatrixPlan::<f64>::input(3, "batch", "batch");
+        assert!(batch.clone().try_reshape(3, "batch").is_ok());
+        assert!(batch.try_reshape("batch", 3).is_err());
+    }
+
+    #[test]
+    fn split_bands_stack_back_into_the_whole() {
+        let x = MatrixPlan::<f64>::input(3, 4, "x");
+        let inputs = bind(&[("x", &matrix(3, 4, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0]))]);
+        let bands = x.split_rows(&[1, 0, 2]);
+        assert_eq!(bands.iter().map(|band| band.rows()).collect::<Vec<_>>(), [1, 0, 2]);
+        assert_bitwise_eq(&bands[2].execute_cpu(&inputs).0, &matrix(2, 4, &[4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0]));
+        assert_bitwise_eq(&MatrixPlan::concat_rows(bands).execute_cpu(&inputs).0, &inputs["x"]);
+        let bands = x.split_cols(&[3, 1]);
+        assert_bitwise_eq(&bands[1].execute_cpu(&inputs).0, &matrix(3, 1, &[3.0, 7.0, 11.0]));
+        assert_bitwise_eq(&MatrixPlan::concat_cols(bands).execute_cpu(&inputs).0, &inputs["x"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "split_cols needs a fixed size, got the symbolic dimension 'batch'")]
+    fn split_of_a_symbolic_dimension_panics() {
+        MatrixPlan::<f64>::input(3, "batch", "x").split_cols(&[1, 2]);
+    }
+
+    #[test]
+    fn fallible_builders_report_bad_shapes() {
+        let x = MatrixPlan::<f64>::input(3, 4, "x");
+        let empty = MatrixPlan::<f64>::input(0, 4, "empty");
+        assert_eq!(empty.clone().try_max_reduce().unwrap_err(), MatrixError::EmptyReduce { op: "max_reduce" });
+        assert_eq!(empty.try_argmax().unwrap_err(), MatrixError::EmptyReduce { op: "argmax" });
+        assert_eq!(x.clone().try_argmax().unwrap().dims(), (Dim::Fixed(1), Dim::Fixed(4)));
+
+        assert_eq!(x.try_split_rows(&[1, 1]).unwrap_err(), MatrixError::ShapeMismatch {
+            op: "split_rows",
+            left: (Dim::Fixed(3), Dim::Fixed(4)),
+            right: (Dim::Fixed(2), Dim::Fixed(4)),
+        });
+        assert_eq!(x.try_split_cols(&[2, 3]).unwrap_err(), MatrixError::ShapeMismatch {
+            op: "split_cols",
+            left: (Dim::Fixed(3), Dim::Fixed(4)),
+            right: (Dim::Fixed(3), Dim::Fixed(5)),
+        });
+        let batch = MatrixPlan::<f64>::input("batch", 4, "batch");
+        assert_eq!(batch.try_split_rows(&[1]).unwrap_err(), MatrixError::SymbolicDim { op: "split_rows", dim: Dim::symbol("batch") });
+        assert_eq!(batch.try_split_cols(&[4]).unwrap().len(), 1);
+
+        let plan = x.clone().exp() + x;
+        assert_eq!(plan.try_substitute("x", &MatrixPlan::input(4, 3, "y")).unwrap_err(), MatrixError::ShapeMismatch {
+            op: "substitute",
+            left: (Dim::Fixed(3), Dim::Fixed(4)),
+            right: (Dim::Fixed(4), Dim::Fixed(3)),
+        });
+        let substituted = plan.try_substitute("x", &MatrixPlan::input(3, 4, "y")).unwrap();
+        assert_eq!(substituted.signature().inputs().map(|(name, _)| name).collect::<Vec<_>>(), ["y"]);
     }
 }
@@ -1,4 +1,6 @@
-use crate::{Scalar, MatrixPlan, Matrix};
+use std::{ops::Range, sync::Arc};
+
+use crate::{Scalar, MatrixPlan, Matrix, plan::{CustomOp, fusion::FusedKernel}};
 
 
 #[derive(Clone, Debug)]
@@ -13,6 +15,10 @@ pub enum MatrixOp<I: Scalar> {
     Constant {
         matrix: Matrix<I>,
     },
+    /// every component set to `value`, in the plan's shape, for constants with a symbolic dimension
+    Fill {
+        value: I,
+    },
     Scale {
         matrix: MatrixPlan<I>,
         scalar: I,
@@ -33,9 +39,101 @@ pub enum MatrixOp<I: Scalar> {
     Sigmoid {
         matrix: MatrixPlan<I>,
     },
+    Exp {
+        matrix: MatrixPlan<I>,
+    },
+    /// natural logarithm
+    Log {
+        matrix: MatrixPlan<I>,
+    },
+    Sqrt {
+        matrix: MatrixPlan<I>,
+    },
+    Abs {
+        matrix: MatrixPlan<I>,
+    },
+    Tanh {
+        matrix: MatrixPlan<I>,
+    },
+    Pow {
+        matrix: MatrixPlan<I>,
+        exponent: I,
+    },
+    Min {
+        matrix: MatrixPlan<I>,
+        scalar: I,
+    },
+    Clamp {
+        matrix: MatrixPlan<I>,
+        min: I,
+        max: I,
+    },
+    Reciprocal {
+        matrix: MatrixPlan<I>,
+    },
+    /// adds the rows together into a single row
+    SumRows {
+        matrix: MatrixPlan<I>,
+    },
+    /// adds the columns together into a single column
+    SumCols {
+        matrix: MatrixPlan<I>,
+    },
+    /// mean of all components, as a 1x1 matrix
+    Mean {
+        matrix: MatrixPlan<I>,
+    },
+    /// largest component of each column, as a single row
+    MaxReduce {
+        matrix: MatrixPlan<I>,
+    },
+    /// row index of the largest component of each column, as a single row
+    ArgMax {
+        matrix: MatrixPlan<I>,
+    },
+    /// one at the row given by each component of a single row of indices, zero elsewhere, with the plan's row count
+    OneHot {
+        matrix: MatrixPlan<I>,
+    },
+    /// repeats a single row to the plan's row count
+    BroadcastRows {
+        matrix: MatrixPlan<I>,
+    },
+    /// repeats a single column to the plan's column count
+    BroadcastCols {
+        matrix: MatrixPlan<I>,
+    },
+    /// softmax of each column
+    Softmax {
+        matrix: MatrixPlan<I>,
+    },
+    /// logarithm of the softmax of each column
+    LogSoftmax {
+        matrix: MatrixPlan<I>,
+    },
+    /// the same components in row-major order, in the plan's shape
+    Reshape {
+        matrix: MatrixPlan<I>,
+    },
+    /// `None` keeps the whole axis
+    Slice {
+        matrix: MatrixPlan<I>,
+        rows: Option<Range<usize>>,
+        cols: Option<Range<usize>>,
+    },
+    /// operands stacked on top of each other
+    ConcatRows {
+        inner: Vec<MatrixPlan<I>>,
+    },
+    /// operands placed side by side
+    ConcatCols {
+        inner: Vec<MatrixPlan<I>>,
+    },
+    /// matrix product, each operand being read transposed if its flag in `transposed` is set, without materializing the transpose
     Mul {
         left: MatrixPlan<I>,
         right: MatrixPlan<I>,
+        transposed: (bool, bool),
     },
     HadamardMul {
         left: MatrixPlan<I>,
@@ -49,7 +147,248 @@ pub enum MatrixOp<I: Scalar> {
         left: MatrixPlan<I>,
         right: MatrixPlan<I>,
     },
+    /// element-wise division
+    Div {
+        left: MatrixPlan<I>,
+        right: MatrixPlan<I>,
+    },
+    /// 1 where `left` is greater than `right`, 0 elsewhere
+    Greater {
+        left: MatrixPlan<I>,
+        right: MatrixPlan<I>,
+    },
+    /// 1 where `left` is less than `right`, 0 elsewhere
+    Less {
+        left: MatrixPlan<I>,
+        right: MatrixPlan<I>,
+    },
+    /// 1 where `left` equals `right`, 0 elsewhere
+    Equal {
+        left: MatrixPlan<I>,
+        right: MatrixPlan<I>,
+    },
+    /// components of `then` where `cond` is non-zero, of `otherwise` elsewhere
+    Where {
+        cond: MatrixPlan<I>,
+        then: MatrixPlan<I>,
+        otherwise: MatrixPlan<I>,
+    },
     Combine {
         inner: Vec<MatrixPlan<I>>,
     },
+    /// the value of `matrix`, available once `after` is evaluated: ops built on it are evaluated again instead of being merged with the same ops on `matrix`
+    Recompute {
+        matrix: MatrixPlan<I>,
+        after: MatrixPlan<I>,
+    },
+    /// A user-defined op, see [`CustomOp`]
+    Custom {
+        op: Arc<dyn CustomOp<I>>,
+        inputs: Vec<MatrixPlan<I>>,
+    },
+    /// A run of element-wise ops evaluated in one pass over memory
+    Fused {
+        kernel: Arc<FusedKernel<I>>,
+        inputs: Vec<MatrixPlan<I>>,
+    },
+    /// A matrix product followed by a fused element-wise epilogue, which reads the product as its first argument
+    FusedMul {
+        left: MatrixPlan<I>,
+        right: MatrixPlan<I>,
+        transposed: (bool, bool),
+        kernel: Arc<FusedKernel<I>>,
+        inputs: Vec<MatrixPlan<I>>,
+    },
+}
+
+impl<I: Scalar> MatrixOp<I> {
+    /// Whether the op maps each component of its first operand (combined with the matching component of any other operand) to the same position of its output
+    pub fn is_elementwise(&self) -> bool {
+        matches!(self,
+            MatrixOp::Scale { .. } |
+            MatrixOp::Max { .. } |
+            MatrixOp::Neg { .. } |
+            MatrixOp::Sign { .. } |
+            MatrixOp::Sigmoid { .. } |
+            MatrixOp::Exp { .. } |
+            MatrixOp::Log { .. } |
+            MatrixOp::Sqrt { .. } |
+            MatrixOp::Abs { .. } |
+            MatrixOp::Tanh { .. } |
+            MatrixOp::Pow { .. } |
+            MatrixOp::Min { .. } |
+            MatrixOp::Clamp { .. } |
+            MatrixOp::Reciprocal { .. } |
+            MatrixOp::Add { .. } |
+            MatrixOp::Sub { .. } |
+            MatrixOp::Div { .. } |
+            MatrixOp::Greater { .. } |
+            MatrixOp::Less { .. } |
+            MatrixOp::Equal { .. } |
+            MatrixOp::Where { .. } |
+            MatrixOp::HadamardMul { .. } |
+            MatrixOp::Fused { .. }
+        )
+    }
+
+    /// Name of the op kind, after the [`MatrixPlan`] method building it
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatrixOp::Input { .. } => "input",
+            MatrixOp::Output { .. } => "output",
+            MatrixOp::Constant { .. } => "constant",
+            MatrixOp::Fill { .. } => "fill",
+            MatrixOp::Scale { .. } => "scale",
+            MatrixOp::Max { .. } => "max",
+            MatrixOp::Neg { .. } => "neg",
+            MatrixOp::Transpose { .. } => "transpose",
+            MatrixOp::Sign { .. } => "sign",
+            MatrixOp::Sigmoid { .. } => "sigmoid",
+            MatrixOp::Exp { .. } => "exp",
+            MatrixOp::Log { .. } => "ln",
+            MatrixOp::Sqrt { .. } => "sqrt",
+            MatrixOp::Abs { .. } => "abs",
+            MatrixOp::Tanh { .. } => "tanh",
+            MatrixOp::Pow { .. } => "pow",
+            MatrixOp::Min { .. } => "min",
+            MatrixOp::Clamp { .. } => "clamp",
+            MatrixOp::Reciprocal { .. } => "reciprocal",
+            MatrixOp::SumRows { .. } => "sum_rows",
+            MatrixOp::SumCols { .. } => "sum_cols",
+            MatrixOp::Mean { .. } => "mean",
+            MatrixOp::MaxReduce { .. } => "max_reduce",
+            MatrixOp::ArgMax { .. } => "argmax",
+            MatrixOp::OneHot { .. } => "one_hot",
+            MatrixOp::BroadcastRows { .. } => "broadcast_rows",
+            MatrixOp::BroadcastCols { .. } => "broadcast_cols",
+            MatrixOp::Softmax { .. } => "softmax",
+            MatrixOp::LogSoftmax { .. } => "log_softmax",
+            MatrixOp::Reshape { .. } => "reshape",
+            MatrixOp::Slice { .. } => "slice",
+            MatrixOp::ConcatRows { .. } => "concat_rows",
+            MatrixOp::ConcatCols { .. } => "concat_cols",
+            MatrixOp::Mul { .. } => "mul",
+            MatrixOp::HadamardMul { .. } => "hadamard_mul",
+            MatrixOp::Add { .. } => "add",
+            MatrixOp::Sub { .. } => "sub",
+            MatrixOp::Div { .. } => "hadamard_div",
+            MatrixOp::Greater { .. } => "greater",
+            MatrixOp::Less { .. } => "less",
+            MatrixOp::Equal { .. } => "equal",
+            MatrixOp::Where { .. } => "select",
+            MatrixOp::Combine { .. } => "merge_outputs",
+            MatrixOp::Recompute { .. } => "recompute",
+            MatrixOp::Custom { .. } => "custom",
+            MatrixOp::Fused { .. } => "fused",
+            MatrixOp::FusedMul { .. } => "fused_mul",
+        }
+    }
+
+    /// Plans this op reads from, in evaluation order
+    pub fn operands(&self) -> Vec<&MatrixPlan<I>> {
+        match self {
+            MatrixOp::Input { .. } |
+            MatrixOp::Constant { .. } |
+            MatrixOp::Fill { .. } => vec![],
+            MatrixOp::Output { matrix, .. } |
+            MatrixOp::Scale { matrix, .. } |
+            MatrixOp::Max { matrix, .. } |
+            MatrixOp::Neg { matrix } |
+            MatrixOp::Transpose { matrix } |
+            MatrixOp::Sigmoid { matrix } |
+            MatrixOp::Sign { matrix } |
+            MatrixOp::Exp { matrix } |
+            MatrixOp::Log { matrix } |
+            MatrixOp::Sqrt { matrix } |
+            MatrixOp::Abs { matrix } |
+            MatrixOp::Tanh { matrix } |
+            MatrixOp::Pow { matrix, .. } |
+            MatrixOp::Min { matrix, .. } |
+            MatrixOp::Clamp { matrix, .. } |
+            MatrixOp::Reciprocal { matrix } |
+            MatrixOp::SumRows { matrix } |
+            MatrixOp::SumCols { matrix } |
+            MatrixOp::Mean { matrix } |
+            MatrixOp::MaxReduce { matrix } |
+            MatrixOp::ArgMax { matrix } |
+            MatrixOp::OneHot { matrix } |
+            MatrixOp::BroadcastRows { matrix } |
+            MatrixOp::BroadcastCols { matrix } |
+            MatrixOp::Softmax { matrix } |
+            MatrixOp::LogSoftmax { matrix } |
+            MatrixOp::Reshape { matrix } |
+            MatrixOp::Slice { matrix, .. } => vec![matrix],
+            MatrixOp::Add { left, right } |
+            MatrixOp::Sub { left, right } |
+            MatrixOp::Div { left, right } |
+            MatrixOp::Greater { left, right } |
+            MatrixOp::Less { left, right } |
+            MatrixOp::Equal { left, right } |
+            MatrixOp::HadamardMul { left, right } |
+            MatrixOp::Mul { left, right, .. } => vec![left, right],
+            MatrixOp::Where { cond, then, otherwise } => vec![cond, then, otherwise],
+            MatrixOp::Recompute { matrix, after } => vec![matrix, after],
+            MatrixOp::Combine { inner } |
+            MatrixOp::ConcatRows { inner } |
+            MatrixOp::ConcatCols { inner } |
+            MatrixOp::Custom { inputs: inner, .. } => inner.iter().collect(),
+            MatrixOp::Fused { inputs, .. } => inputs.iter().collect(),
+            MatrixOp::FusedMul { left, right, inputs, .. } => [left, right].into_iter().chain(inputs.iter()).collect(),
+        }
+    }
+
+    /// The same op reading from `operands` instead, given in [`MatrixOp::operands`] order
+    pub fn with_operands(&self, operands: Vec<MatrixPlan<I>>) -> MatrixOp<I> {
+        let mut operands = operands.into_iter();
+        let mut operand = || operands.next().expect("missing operand");
+        match self {
+            MatrixOp::Input { .. } |
+            MatrixOp::Constant { .. } |
+            MatrixOp::Fill { .. } => self.clone(),
+            MatrixOp::Output { name, .. } => MatrixOp::Output { name: name.clone(), matrix: operand() },
+            MatrixOp::Scale { scalar, .. } => MatrixOp::Scale { matrix: operand(), scalar: *scalar },
+            MatrixOp::Max { scalar, .. } => MatrixOp::Max { matrix: operand(), scalar: *scalar },
+            MatrixOp::Neg { .. } => MatrixOp::Neg { matrix: operand() },
+            MatrixOp::Transpose { .. } => MatrixOp::Transpose { matrix: operand() },
+            MatrixOp::Sign { .. } => MatrixOp::Sign { matrix: operand() },
+            MatrixOp::Sigmoid { .. } => MatrixOp::Sigmoid { matrix: operand() },
+            MatrixOp::Exp { .. } => MatrixOp::Exp { matrix: operand() },
+            MatrixOp::Log { .. } => MatrixOp::Log { matrix: operand() },
+            MatrixOp::Sqrt { .. } => MatrixOp::Sqrt { matrix: operand() },
+            MatrixOp::Abs { .. } => MatrixOp::Abs { matrix: operand() },
+            MatrixOp::Tanh { .. } => MatrixOp::Tanh { matrix: operand() },
+            MatrixOp::Pow { exponent, .. } => MatrixOp::Pow { matrix: operand(), exponent: *exponent },
+            MatrixOp::Min { scalar, .. } => MatrixOp::Min { matrix: operand(), scalar: *scalar },
+            MatrixOp::Clamp { min, max, .. } => MatrixOp::Clamp { matrix: operand(), min: *min, max: *max },
+            MatrixOp::Reciprocal { .. } => MatrixOp::Reciprocal { matrix: operand() },
+            MatrixOp::SumRows { .. } => MatrixOp::SumRows { matrix: operand() },
+            MatrixOp::SumCols { .. } => MatrixOp::SumCols { matrix: operand() },
+            MatrixOp::Mean { .. } => MatrixOp::Mean { matrix: operand() },
+            MatrixOp::MaxReduce { .. } => MatrixOp::MaxReduce { matrix: operand() },
+            MatrixOp::ArgMax { .. } => MatrixOp::ArgMax { matrix: operand() },
+            MatrixOp::OneHot { .. } => MatrixOp::OneHot { matrix: operand() },
+            MatrixOp::BroadcastRows { .. } => MatrixOp::BroadcastRows { matrix: operand() },
+            MatrixOp::BroadcastCols { .. } => MatrixOp::BroadcastCols { matrix: operand() },
+            MatrixOp::Softmax { .. } => MatrixOp::Softmax { matrix: operand() },
+            MatrixOp::LogSoftmax { .. } => MatrixOp::LogSoftmax { matrix: operand() },
+            MatrixOp::Reshape { .. } => MatrixOp::Reshape { matrix: operand() },
+            MatrixOp::Slice { rows, cols, .. } => MatrixOp::Slice { matrix: operand(), rows: rows.clone(), cols: cols.clone() },
+            MatrixOp::Mul { transposed, .. } => MatrixOp::Mul { left: operand(), right: operand(), transposed: *transposed },
+            MatrixOp::HadamardMul { .. } => MatrixOp::HadamardMul { left: operand(), right: operand() },
+            MatrixOp::Add { .. } => MatrixOp::Add { left: operand(), right: operand() },
+            MatrixOp::Sub { .. } => MatrixOp::Sub { left: operand(), right: operand() },
+            MatrixOp::Div { .. } => MatrixOp::Div { left: operand(), right: operand() },
+            MatrixOp::Greater { .. } => MatrixOp::Greater { left: operand(), right: operand() },
+            MatrixOp::Less { .. } => MatrixOp::Less { left: operand(), right: operand() },
+            MatrixOp::Equal { .. } => MatrixOp::Equal { left: operand(), right: operand() },
+            MatrixOp::Where { .. } => MatrixOp::Where { cond: operand(), then: operand(), otherwise: operand() },
+            MatrixOp::Combine { .. } => MatrixOp::Combine { inner: operands.collect() },
+            MatrixOp::Recompute { .. } => MatrixOp::Recompute { matrix: operand(), after: operand() },
+            MatrixOp::Custom { op, .. } => MatrixOp::Custom { op: op.clone(), inputs: operands.collect() },
+            MatrixOp::ConcatRows { .. } => MatrixOp::ConcatRows { inner: operands.collect() },
+            MatrixOp::ConcatCols { .. } => MatrixOp::ConcatCols { inner: operands.collect() },
+            MatrixOp::Fused { kernel, .. } => MatrixOp::Fused { kernel: kernel.clone(), inputs: operands.collect() },
+            MatrixOp::FusedMul { transposed, kernel, .. } => MatrixOp::FusedMul { left: operand(), right: operand(), transposed: *transposed, kernel: kernel.clone(), inputs: operands.collect() },
+        }
+    }
 }
@@ -0,0 +1,223 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{MatrixPlan, Scalar, plan::{cpu_eval, graph::PlanGraph, op::MatrixOp, products}};
+
+/// A kind of rewrite performed by [`MatrixPlan::optimize`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rewrite {
+    /// an op whose operands are all constants was evaluated at build time
+    ConstantFold,
+    /// `x ⊙ 1`, `x / 1`, `x + 0`, `x - 0`, `x * I`, `x ^ 1` or a scale by one was replaced by `x`
+    IdentityElimination,
+    /// `transpose(transpose(x))` was replaced by `x`
+    DoubleTranspose,
+    /// `scale(scale(x, a), b)` was replaced by `scale(x, a * b)`
+    ScaleMerge,
+    /// negations were pushed into a subtraction or a scale, or cancelled
+    NegCanonicalization,
+    /// a transpose feeding a product was folded into the product, which reads the operand transposed instead
+    TransposedProduct,
+}
+
+/// Rewrites fired by an optimization pass, in the order they fired
+#[derive(Clone, Debug, Default)]
+pub struct OptimizeReport {
+    pub rewrites: Vec<Rewrite>,
+}
+
+impl OptimizeReport {
+    pub fn count(&self, rewrite: Rewrite) -> usize {
+        self.rewrites.iter().filter(|fired| **fired == rewrite).count()
+    }
+}
+
+impl fmt::Display for OptimizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut counts = HashMap::new();
+        for rewrite in &self.rewrites {
+            *counts.entry(*rewrite).or_insert(0usize) += 1;
+        }
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort();
+        for (rewrite, count) in counts {
+            writeln!(f, "{:?}: {}", rewrite, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Value of every component of a non-empty constant, if they are all equal
+fn splat<I: Scalar>(plan: &MatrixPlan<I>) -> Option<I> {
+    match &*plan.source {
+        MatrixOp::Fill { value } => Some(*value),
+        MatrixOp::Constant { matrix } => {
+            let data: &[I] = matrix.as_ref();
+            let first = *data.first()?;
+            data.iter().all(|x| *x == first).then_some(first)
+        },
+        _ => None,
+    }
+}
+
+fn is_identity<I: Scalar>(plan: &MatrixPlan<I>) -> bool {
+    match &*plan.source {
+        MatrixOp::Constant { matrix } => {
+            matrix.rows() == matrix.cols() && (0..matrix.rows()).all(|row| (0..matrix.cols()).all(|col| {
+                matrix[row][col] == if row == col { I::ONE } else { I::default() }
+            }))
+        },
+        _ => false,
+    }
+}
+
+fn is_constant<I: Scalar>(plan: &MatrixPlan<I>) -> bool {
+    matches!(&*plan.source, MatrixOp::Constant { .. })
+}
+
+/// One rewrite of the top op of `plan`, whose operands are already simplified
+fn simplify<I: Scalar>(plan: &MatrixPlan<I>) -> Option<(Rewrite, MatrixPlan<I>)> {
+    let operands = plan.source.operands();
+    let foldable = !matches!(&*plan.source, MatrixOp::Input { .. } | MatrixOp::Output { .. } | MatrixOp::Constant { .. } | MatrixOp::Fill { .. } | MatrixOp::Combine { .. });
+    if foldable && operands.iter().all(|operand| is_constant(operand)) {
+        let operands = operands.into_iter().map(|operand| match &*operand.source {
+            MatrixOp::Constant { matrix } => matrix.clone(),
+            _ => unreachable!(),
+        }).collect();
+        let folded = cpu_eval::evaluate(plan, &HashMap::new(), operands);
+        return Some((Rewrite::ConstantFold, MatrixPlan::constant(folded)));
+    }
+
+    let zero = I::default();
+    Some(match &*plan.source {
+        MatrixOp::HadamardMul { left, right } if splat(right) == Some(I::ONE) => (Rewrite::IdentityElimination, left.clone()),
+        MatrixOp::HadamardMul { left, right } if splat(left) == Some(I::ONE) => (Rewrite::IdentityElimination, right.clone()),
+        MatrixOp::Add { left, right } if splat(right) == Some(zero) => (Rewrite::IdentityElimination, left.clone()),
+        MatrixOp::Add { left, right } if splat(left) == Some(zero) => (Rewrite::IdentityElimination, right.clone()),
+        MatrixOp::Sub { left, right } if splat(right) == Some(zero) => (Rewrite::IdentityElimination, left.clone()),
+        MatrixOp::Mul { left, right, transposed: (false, _) } if is_identity(right) => (Rewrite::IdentityElimination, left.clone()),
+        MatrixOp::Mul { left, right, transposed: (_, false) } if is_identity(left) => (Rewrite::IdentityElimination, right.clone()),
+        MatrixOp::Div { left, right } if splat(right) == Some(I::ONE) => (Rewrite::IdentityElimination, left.clone()),
+        MatrixOp::Scale { matrix, scalar } if *scalar == I::ONE => (Rewrite::IdentityElimination, matrix.clone()),
+        MatrixOp::Pow { matrix, exponent } if *exponent == I::ONE => (Rewrite::IdentityElimination, matrix.clone()),
+
+        MatrixOp::Transpose { matrix } => match &*matrix.source {
+            MatrixOp::Transpose { matrix } => (Rewrite::DoubleTranspose, matrix.clone()),
+            _ => return None,
+        },
+        MatrixOp::Mul { .. } => (Rewrite::TransposedProduct, products::fold_transposes_into(plan)?),
+
+        MatrixOp::Scale { matrix, scalar } => match &*matrix.source {
+            MatrixOp::Scale { matrix, scalar: inner } => (Rewrite::ScaleMerge, matrix.clone().scale(*inner * *scalar)),
+            MatrixOp::Neg { matrix } => (Rewrite::NegCanonicalization, matrix.clone().scale(-*scalar)),
+            _ => return None,
+        },
+
+        MatrixOp::Neg { matrix } => match &*matrix.source {
+            MatrixOp::Neg { matrix } => (Rewrite::NegCanonicalization, matrix.clone()),
+            MatrixOp::Sub { left, right } => (Rewrite::NegCanonicalization, right.clone() - left),
+            MatrixOp::Scale { matrix, scalar } => (Rewrite::NegCanonicalization, matrix.clone().scale(-*scalar)),
+            _ => return None,
+        },
+        MatrixOp::Sub { left, right } if splat(left) == Some(zero) => (Rewrite::NegCanonicalization, -right.clone()),
+        MatrixOp::Add { left, right } => match (&*left.source, &*right.source) {
+            (_, MatrixOp::Neg { matrix }) => (Rewrite::NegCanonicalization, left.clone() - matrix),
+            (MatrixOp::Neg { matrix }, _) => (Rewrite::NegCanonicalization, right.clone() - matrix),
+            _ => return None,
+        },
+        MatrixOp::Sub { left, right } => match &*right.source {
+            MatrixOp::Neg { matrix } => (Rewrite::NegCanonicalization, left.clone() + matrix),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+pub(crate) fn optimize<I: Scalar>(plan: &MatrixPlan<I>) -> (MatrixPlan<I>, OptimizeReport) {
+    let graph = PlanGraph::new(plan);
+    let mut report = OptimizeReport::default();
+    let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let operands = node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect();
+        let mut current = node.plan.with_operands(operands);
+        while let Some((rewrite, next)) = simplify(&current) {
+            report.rewrites.push(rewrite);
+            current = next;
+        }
+        rebuilt.push(current);
+    }
+    (rebuilt.swap_remove(graph.root), report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Matrix, MatrixPlan, plan::testing::{assert_close, bind, sample}};
+
+    use super::Rewrite;
+
+    fn x() -> MatrixPlan<f64> {
+        MatrixPlan::input(3, 4, "x")
+    }
+
+    fn y() -> MatrixPlan<f64> {
+        MatrixPlan::input(3, 4, "y")
+    }
+
+    /// Optimizes `plan`, checking that `rewrite` fired `count` times and that the value at sampled inputs is unchanged
+    fn assert_fires(plan: MatrixPlan<f64>, rewrite: Rewrite, count: usize) -> MatrixPlan<f64> {
+        let (optimized, report) = plan.optimize_with_report();
+        assert_eq!(report.count(rewrite), count, "{:?} in {}", rewrite, report);
+        let inputs = bind(&[("x", &sample(3, 4, 1)), ("y", &sample(3, 4, 2))]);
+        assert_close(&optimized.execute_cpu(&inputs).0, &plan.execute_cpu(&inputs).0, 1e-12);
+        optimized
+    }
+
+    fn identity(size: usize) -> Matrix<f64> {
+        let mut out = Matrix::new(size, size);
+        for index in 0..size {
+            out[index][index] = 1.0;
+        }
+        out
+    }
+
+    #[test]
+    fn constant_fold() {
+        let (a, b) = (MatrixPlan::constant(sample(3, 4, 3)), MatrixPlan::constant(sample(3, 4, 4)));
+        let folded = assert_fires(x() + (a.exp() + b.abs().sqrt()).scale(0.5), Rewrite::ConstantFold, 5);
+        assert_eq!(folded.to_text().matches("= constant").count(), 1);
+    }
+
+    #[test]
+    fn identity_elimination() {
+        let ones = MatrixPlan::splat(3, 4, 1.0);
+        let zeros = MatrixPlan::splat(3, 4, 0.0);
+        let plan = (x().hadamard_mul(&ones) + &zeros - zeros).hadamard_div(ones).scale(1.0).pow(1.0) * MatrixPlan::constant(identity(4));
+        let optimized = assert_fires(plan, Rewrite::IdentityElimination, 7);
+        assert_eq!(optimized.to_text(), x().to_text());
+    }
+
+    #[test]
+    fn double_transpose() {
+        let optimized = assert_fires(x().transpose().transpose().exp(), Rewrite::DoubleTranspose, 1);
+        assert_eq!(optimized.to_text(), x().exp().to_text());
+    }
+
+    #[test]
+    fn scale_merge() {
+        let optimized = assert_fires(x().scale(3.0).scale(0.5).scale(-2.0), Rewrite::ScaleMerge, 2);
+        assert_eq!(optimized.to_text(), x().scale(-3.0).to_text());
+    }
+
+    #[test]
+    fn neg_canonicalization() {
+        let plan = -(-x()) + -y().scale(2.0) - -(x() - y()) + (MatrixPlan::splat(3, 4, 0.0) - y());
+        assert_fires(plan, Rewrite::NegCanonicalization, 5);
+    }
+
+    #[test]
+    fn transposed_product() {
+        let optimized = assert_fires(x().transpose() * y() + y().transpose() * x(), Rewrite::TransposedProduct, 2);
+        let text = optimized.to_text();
+        assert!(!text.contains("= transpose"));
+        assert_eq!(text.matches(".T").count(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use crate::{MatrixPlan, Scalar, plan::{graph::PlanGraph, op::MatrixOp}};
+
+/// Folds `Transpose` operands of the product at the top of `plan` into its transpose flags, if it has any
+pub(crate) fn fold_transposes_into<I: Scalar>(plan: &MatrixPlan<I>) -> Option<MatrixPlan<I>> {
+    let (left, right, transposed) = match &*plan.source {
+        MatrixOp::Mul { left, right, transposed } |
+        MatrixOp::FusedMul { left, right, transposed, .. } => (left, right, *transposed),
+        _ => return None,
+    };
+    let unwrap = |operand: &MatrixPlan<I>, transposed: bool| match &*operand.source {
+        MatrixOp::Transpose { matrix } => Some((matrix.clone(), !transposed)),
+        _ => None,
+    };
+    let (folded_left, folded_right) = (unwrap(left, transposed.0), unwrap(right, transposed.1));
+    if folded_left.is_none() && folded_right.is_none() {
+        return None;
+    }
+    let (left, left_transposed) = folded_left.unwrap_or_else(|| (left.clone(), transposed.0));
+    let (right, right_transposed) = folded_right.unwrap_or_else(|| (right.clone(), transposed.1));
+    let transposed = (left_transposed, right_transposed);
+    let source = match &*plan.source {
+        MatrixOp::FusedMul { kernel, inputs, .. } => MatrixOp::FusedMul { left, right, transposed, kernel: kernel.clone(), inputs: inputs.clone() },
+        _ => MatrixOp::Mul { left, right, transposed },
+    };
+    Some(MatrixPlan {
+        rows: plan.rows.clone(),
+        cols: plan.cols.clone(),
+        source: Arc::new(source),
+    })
+}
+
+/// Makes every product read its `Transpose` operands in place, the transposes being left to their other users if any
+pub(crate) fn fold_transposes<I: Scalar>(plan: &MatrixPlan<I>) -> MatrixPlan<I> {
+    let graph = PlanGraph::new(plan);
+    let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let mut plan = node.plan.with_operands(node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect());
+        while let Some(folded) = fold_transposes_into(&plan) {
+            plan = folded;
+        }
+        rebuilt.push(plan);
+    }
+    rebuilt.swap_remove(graph.root)
+}
+
+/// A factor of a product chain, read transposed if the flag is set
+type Factor<I> = (MatrixPlan<I>, bool);
+
+/// Reorders chains of products into the association needing the fewest multiply-adds, found by dynamic programming over the factor shapes.
+/// A chain runs through products whose only user is another product, and is left alone if any of its shapes is symbolic.
+pub(crate) fn reassociate<I: Scalar>(plan: &MatrixPlan<I>) -> MatrixPlan<I> {
+    let graph = PlanGraph::new(plan);
+    let nodes = &graph.nodes;
+    let product = |index: usize| matches!(&*nodes[index].plan.source, MatrixOp::Mul { .. });
+    let absorbed = (0..nodes.len())
+        .map(|index| product(index) && nodes[index].users.len() == 1 && product(nodes[index].users[0]))
+        .collect::<Vec<_>>();
+
+    let mut rebuilt: Vec<MatrixPlan<I>> = Vec::with_capacity(nodes.len());
+    for (index, node) in nodes.iter().enumerate() {
+        let plan = node.plan.with_operands(node.operands.iter().map(|operand| rebuilt[*operand].clone()).collect());
+        let chain = if product(index) && !absorbed[index] {
+            reorder(&graph, &absorbed, &rebuilt, index)
+        } else {
+            None
+        };
+        rebuilt.push(chain.unwrap_or(plan));
+    }
+    rebuilt.swap_remove(graph.root)
+}
+
+/// The chain rooted at `root` in its cheapest association, if that is cheaper than the current one
+fn reorder<I: Scalar>(graph: &PlanGraph<'_, I>, absorbed: &[bool], rebuilt: &[MatrixPlan<I>], root: usize) -> Option<MatrixPlan<I>> {
+    let mut factors = vec![];
+    let current = flatten(graph, absorbed, rebuilt, root, false, &mut factors)?;
+    if factors.len() < 3 {
+        return None;
+    }
+    // factor i is dims[i] x dims[i + 1]
+    let mut dims = Vec::with_capacity(factors.len() + 1);
+    for (position, (plan, transposed)) in factors.iter().enumerate() {
+        let (rows, cols) = if *transposed {
+            (&plan.cols, &plan.rows)
+        } else {
+            (&plan.rows, &plan.cols)
+        };
+        if position == 0 {
+            dims.push(rows.fixed()?);
+        }
+        dims.push(cols.fixed()?);
+    }
+
+    let count = factors.len();
+    // cost[i][j] is the cheapest product of factors i..=j, split after split[i][j]
+    let mut cost = vec![vec![0u64; count]; count];
+    let mut split = vec![vec![0; count]; count];
+    for length in 2..=count {
+        for first in 0..=count - length {
+            let last = first + length - 1;
+            cost[first][last] = u64::MAX;
+            for middle in first..last {
+                let total = cost[first][middle] + cost[middle + 1][last] + (dims[first] * dims[middle + 1] * dims[last + 1]) as u64;
+                if total < cost[first][last] {
+                    cost[first][last] = total;
+                    split[first][last] = middle;
+                }
+            }
+        }
+    }
+    if cost[0][count - 1] >= current {
+        return None;
+    }
+    Some(build(&factors, &split, 0, count - 1).0)
+}
+
+/// Collects the factors of the chain below `index` in order, the whole chain being read transposed if `transposed` is set.
+/// Returns the multiply-adds of its current association, or `None` if a shape is symbolic.
+fn flatten<I: Scalar>(graph: &PlanGraph<'_, I>, absorbed: &[bool], rebuilt: &[MatrixPlan<I>], index: usize, transposed: bool, factors: &mut Vec<Factor<I>>) -> Option<u64> {
+    let node = &graph.nodes[index];
+    let flags = match &*node.plan.source {
+        MatrixOp::Mul { transposed, .. } => *transposed,
+        _ => unreachable!("chain member is a product"),
+    };
+    let (rows, cols) = (node.plan.rows.fixed()?, node.plan.cols.fixed()?);
+    let left = &graph.nodes[node.operands[0]].plan;
+    let inner = if flags.0 { left.rows.fixed()? } else { left.cols.fixed()? };
+    let mut operands = [(node.operands[0], flags.0), (node.operands[1], flags.1)];
+    // (l r)ᵀ = rᵀ lᵀ
+    if transposed {
+        operands.reverse();
+        operands.iter_mut().for_each(|(_, flag)| *flag = !*flag);
+    }
+    let mut total = (rows * inner * cols) as u64;
+    for (operand, flag) in operands {
+        if absorbed[operand] {
+            total += flatten(graph, absorbed, rebuilt, operand, flag, factors)?;
+        } else {
+            factors.push((rebuilt[operand].clone(), flag));
+        }
+    }
+    Some(total)
+}
+
+/// Product of factors `first..=last` in the association recorded in `split`, and whether it is to be read transposed
+fn build<I: Scalar>(factors: &[Factor<I>], split: &[Vec<usize>], first: usize, last: usize) -> Factor<I> {
+    if first == last {
+        return factors[first].clone();
+    }
+    let middle = split[first][last];
+    let (left, left_transposed) = build(factors, split, first, middle);
+    let (right, right_transposed) = build(factors, split, middle + 1, last);
+    (left.try_mul_transposed(&right, (left_transposed, right_transposed)).expect("chain shapes match"), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{Matrix, MatrixPlan, plan::{graph::PlanGraph, op::MatrixOp, testing::{assert_bitwise_eq, assert_close, bind, sample}}};
+
+    use super::{fold_transposes, reassociate};
+
+    fn input(rows: usize, cols: usize, name: &str) -> MatrixPlan<f64> {
+        MatrixPlan::input(rows, cols, name)
+    }
+
+    fn inputs(plan: &MatrixPlan<f64>) -> HashMap<String, Matrix<f64>> {
+        plan.inputs().into_iter().enumerate()
+            .map(|(seed, (name, (rows, cols)))| (name.to_string(), sample(rows.fixed().unwrap(), cols.fixed().unwrap(), seed as u64)))
+            .collect()
+    }
+
+    fn products(plan: &MatrixPlan<f64>) -> usize {
+        PlanGraph::new(plan).nodes.iter().filter(|node| matches!(&*node.plan.source, MatrixOp::Mul { .. })).count()
+    }
+
+    /// `reassociate(plan)` is cheaper and computes the same value
+    fn assert_cheaper(plan: &MatrixPlan<f64>) {
+        let reassociated = reassociate(plan);
+        assert!(reassociated.cost().flops < plan.cost().flops, "{} >= {}", reassociated.cost().flops, plan.cost().flops);
+        assert_eq!(products(&reassociated), products(plan));
+        let inputs = inputs(plan);
+        assert_close(&reassociated.execute_cpu(&inputs).0, &plan.execute_cpu(&inputs).0, 1e-12);
+    }
+
+    #[test]
+    fn chains_are_reassociated_to_fewer_flops() {
+        // ((a b) c) d, cheapest as a (b c) d with the 2-wide products first
+        let chain = input(40, 2, "a") * input(2, 40, "b") * input(40, 2, "c") * input(2, 40, "d");
+        assert_cheaper(&chain);
+        // right-leaning chain of a vector through square matrices
+        let vector = input(30, 30, "a") * (input(30, 30, "b") * input(30, 30, "c")) * input(30, 1, "v");
+        assert_cheaper(&vector.exp());
+    }
+
+    #[test]
+    fn chains_with_transposed_factors_are_reassociated() {
+        let (a, b, c) = (input(2, 40, "a"), input(40, 2, "b"), input(2, 40, "c"));
+        let chain = a.try_mul_transposed(&b, (true, true)).unwrap() * c.transpose() * input(2, 40, "d");
+        assert_cheaper(&chain);
+        let folded = fold_transposes(&chain);
+        assert_cheaper(&folded);
+        assert_cheaper(&(folded.transpose() * input(40, 1, "e")));
+    }
+
+    #[test]
+    fn cheapest_or_symbolic_chains_are_left_alone() {
+        let cheapest = input(2, 40, "a") * input(40, 2, "b") * input(2, 40, "c");
+        assert_eq!(reassociate(&cheapest).to_text(), cheapest.to_text());
+        let symbolic = MatrixPlan::<f64>::input(40, "n", "a").transpose() * input(40, 2, "b") * input(2, 40, "c") * input(40, 2, "d");
+        assert_eq!(reassociate(&symbolic).to_text(), symbolic.to_text());
+    }
+
+    #[test]
+    fn shared_products_end_their_chain() {
+        // `a b` is read twice, so it is kept and only the products above it are reordered into `(a b) (c v)`
+        let shared = input(40, 2, "a") * input(2, 40, "b");
+        let plan = shared.clone() * input(40, 40, "c") * input(40, 1, "v") + shared.slice_cols(0..1);
+        assert_cheaper(&plan);
+        let text = reassociate(&plan).to_text();
+        assert!(text.contains("= mul %a, %b : 40x40"));
+        assert!(text.contains("= mul %c, %v : 40x1"));
+    }
+
+    #[test]
+    fn transposed_flags_match_materialized_transposes() {
+        // odd shapes, so the flags cannot be confused with a square product
+        let (left, right) = (input(5, 7, "l"), input(7, 3, "r"));
+        let inputs = bind(&[("l", &sample(5, 7, 1)), ("r", &sample(7, 3, 2)), ("lt", &sample(5, 7, 1).transpose()), ("rt", &sample(7, 3, 2).transpose())]);
+        let expected = (left * right).execute_cpu(&inputs).0;
+        for transposed in [(false, false), (false, true), (true, false), (true, true)] {
+            let left = if transposed.0 { input(7, 5, "lt") } else { input(5, 7, "l") };
+            let right = if transposed.1 { input(3, 7, "rt") } else { input(7, 3, "r") };
+            let flagged = left.clone().try_mul_transposed(&right, transposed).unwrap();
+            assert_bitwise_eq(&flagged.execute_cpu(&inputs).0, &expected);
+            let materialized = |plan: MatrixPlan<f64>, flag| if flag { plan.transpose() } else { plan };
+            let unfolded = materialized(left, transposed.0) * materialized(right, transposed.1);
+            assert_bitwise_eq(&unfolded.execute_cpu(&inputs).0, &expected);
+            assert_bitwise_eq(&fold_transposes(&unfolded).execute_cpu(&inputs).0, &expected);
+            assert_bitwise_eq(&unfolded.compile().execute(&inputs).0, &expected);
+        }
+    }
+}
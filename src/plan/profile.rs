@@ -0,0 +1,224 @@
+use std::{cmp::Reverse, fmt::{self, Write}, ops::Add, time::Duration};
+
+use crate::{MatrixPlan, Scalar, plan::op::MatrixOp};
+
+/// Floating point operations per component of a softmax: maximum, shift, exponential, sum and normalization
+const SOFTMAX_FLOPS: u64 = 5;
+
+/// Estimated work of a node or a group of nodes, see [`MatrixPlan::cost`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cost {
+    /// floating point operations, counting one per component for element-wise ops (including transcendental ones) and `2mkn` for products
+    pub flops: u64,
+    /// size of the results produced, named outputs, recomputation barriers and merges being free.
+    /// Derived from the shapes like `flops`, not measured: copies and scratch buffers of the evaluation are not counted.
+    pub result_bytes: usize,
+}
+
+impl Add for Cost {
+    type Output = Cost;
+
+    fn add(self, rhs: Cost) -> Cost {
+        Cost {
+            flops: self.flops + rhs.flops,
+            result_bytes: self.result_bytes + rhs.result_bytes,
+        }
+    }
+}
+
+/// One evaluated node of a profiled execution
+#[derive(Clone, Debug)]
+pub struct NodeProfile {
+    /// kind of the op, see [`MatrixPlan::to_text`] for the names
+    pub op: &'static str,
+    /// name of inputs and outputs
+    pub name: Option<String>,
+    pub rows: usize,
+    pub cols: usize,
+    /// time spent evaluating the node itself, its operands excluded
+    pub time: Duration,
+    /// estimated from the shapes, see [`Cost`]
+    pub cost: Cost,
+}
+
+/// Nodes of one op kind in a profiled execution
+#[derive(Clone, Debug)]
+pub struct OpProfile {
+    pub op: &'static str,
+    pub nodes: usize,
+    pub time: Duration,
+    pub cost: Cost,
+}
+
+/// Where time went in [`MatrixPlan::execute_cpu_profiled`]
+#[derive(Clone, Debug, Default)]
+pub struct ProfileReport {
+    /// every evaluated node, in evaluation order
+    pub nodes: Vec<NodeProfile>,
+    /// nodes aggregated by op kind, slowest first
+    pub ops: Vec<OpProfile>,
+}
+
+impl ProfileReport {
+    pub(crate) fn new(nodes: Vec<NodeProfile>) -> Self {
+        let mut ops: Vec<OpProfile> = vec![];
+        for node in &nodes {
+            match ops.iter_mut().find(|op| op.op == node.op) {
+                Some(op) => {
+                    op.nodes += 1;
+                    op.time += node.time;
+                    op.cost = op.cost + node.cost;
+                },
+                None => ops.push(OpProfile {
+                    op: node.op,
+                    nodes: 1,
+                    time: node.time,
+                    cost: node.cost,
+                }),
+            }
+        }
+        ops.sort_by_key(|op| Reverse(op.time));
+        Self {
+            nodes,
+            ops,
+        }
+    }
+
+    pub fn time(&self) -> Duration {
+        self.nodes.iter().map(|node| node.time).sum()
+    }
+
+    pub fn cost(&self) -> Cost {
+        self.nodes.iter().fold(Cost::default(), |total, node| total + node.cost)
+    }
+
+    /// The report as a JSON object with `time_ns`, `flops` and `result_bytes` totals, and `ops` and `nodes` arrays of the same fields
+    pub fn to_json(&self) -> String {
+        let fields = |time: Duration, cost: Cost| format!("\"time_ns\": {}, \"flops\": {}, \"result_bytes\": {}", time.as_nanos(), cost.flops, cost.result_bytes);
+        let ops = self.ops.iter().map(|op| format!("{{\"op\": \"{}\", \"nodes\": {}, {}}}", op.op, op.nodes, fields(op.time, op.cost)));
+        let nodes = self.nodes.iter().map(|node| {
+            let name = match &node.name {
+                Some(name) => format!("\"{}\"", escape(name)),
+                None => "null".to_string(),
+            };
+            format!("{{\"op\": \"{}\", \"name\": {}, \"rows\": {}, \"cols\": {}, {}}}", node.op, name, node.rows, node.cols, fields(node.time, node.cost))
+        });
+        format!("{{{}, \"ops\": [{}], \"nodes\": [{}]}}", fields(self.time(), self.cost()), ops.collect::<Vec<_>>().join(", "), nodes.collect::<Vec<_>>().join(", "))
+    }
+}
+
+/// A table of the op kinds, slowest first, and the total
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<16} {:>6} {:>12} {:>7} {:>14} {:>12}", "op", "nodes", "time", "%", "flops", "result bytes")?;
+        let total = self.time().as_secs_f64();
+        let row = |f: &mut fmt::Formatter<'_>, op: &str, nodes: usize, time: Duration, cost: Cost| {
+            let share = if total > 0.0 {
+                time.as_secs_f64() / total * 100.0
+            } else {
+                0.0
+            };
+            writeln!(f, "{:<16} {:>6} {:>12} {:>6.1}% {:>14} {:>12}", op, nodes, format!("{:.3?}", time), share, cost.flops, cost.result_bytes)
+        };
+        for op in &self.ops {
+            row(f, op.op, op.nodes, op.time, op.cost)?;
+        }
+        row(f, "total", self.nodes.len(), self.time(), self.cost())
+    }
+}
+
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Estimated cost of evaluating the op of `plan` alone, its dimensions and those of its operands being fixed
+pub(crate) fn node_cost<I: Scalar>(plan: &MatrixPlan<I>) -> Cost {
+    let components = |plan: &MatrixPlan<I>| (plan.rows() * plan.cols()) as u64;
+    let operands = plan.source.operands();
+    let product = |transposed: &(bool, bool)| {
+        let inner = if transposed.0 { operands[0].rows() } else { operands[0].cols() };
+        2 * (plan.rows() * inner * plan.cols()) as u64
+    };
+    let flops = match &*plan.source {
+        MatrixOp::Input { .. } |
+        MatrixOp::Output { .. } |
+        MatrixOp::Recompute { .. } |
+        MatrixOp::Constant { .. } |
+        MatrixOp::Fill { .. } |
+        MatrixOp::Transpose { .. } |
+        MatrixOp::BroadcastRows { .. } |
+        MatrixOp::BroadcastCols { .. } |
+        MatrixOp::Reshape { .. } |
+        MatrixOp::Slice { .. } |
+        MatrixOp::ConcatRows { .. } |
+        MatrixOp::ConcatCols { .. } |
+        MatrixOp::Combine { .. } => 0,
+        MatrixOp::SumRows { .. } |
+        MatrixOp::SumCols { .. } |
+        MatrixOp::Mean { .. } |
+        MatrixOp::MaxReduce { .. } |
+        MatrixOp::ArgMax { .. } => components(operands[0]),
+        MatrixOp::Softmax { .. } |
+        MatrixOp::LogSoftmax { .. } => SOFTMAX_FLOPS * components(plan),
+        MatrixOp::Mul { transposed, .. } => product(transposed),
+        MatrixOp::Custom { op, .. } => op.flops(&operands.iter().map(|operand| (operand.rows(), operand.cols())).collect::<Vec<_>>()),
+        MatrixOp::Fused { kernel, .. } => kernel.steps.len() as u64 * components(plan),
+        MatrixOp::FusedMul { transposed, kernel, .. } => product(transposed) + kernel.steps.len() as u64 * components(plan),
+        _ => components(plan),
+    };
+    let result_bytes = match &*plan.source {
+        MatrixOp::Output { .. } | MatrixOp::Recompute { .. } | MatrixOp::Combine { .. } => 0,
+        _ => plan.rows() * plan.cols() * std::mem::size_of::<I>(),
+    };
+    Cost {
+        flops,
+        result_bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MatrixPlan, plan::testing::{assert_bitwise_eq, bind, sample}};
+
+    use super::Cost;
+
+    #[test]
+    fn cost_is_estimated_from_shapes() {
+        let x = MatrixPlan::<f64>::input(3, 4, "x");
+        let w = MatrixPlan::input(4, 5, "w");
+        let plan = (x * w).exp().output("y");
+        // 2mkn for the product and one per component for the exponential, the named output being free
+        assert_eq!(plan.cost(), Cost {
+            flops: 2 * 3 * 4 * 5 + 3 * 5,
+            result_bytes: (3 * 4 + 4 * 5 + 3 * 5 + 3 * 5) * 8,
+        });
+    }
+
+    #[test]
+    fn profiled_execution_matches_plain_execution() {
+        let x = MatrixPlan::<f64>::input(3, 4, "x");
+        let shared = x.clone().tanh();
+        let plan = (shared.clone() + shared.transpose().transpose()).output("y").softmax();
+        let inputs = bind(&[("x", &sample(3, 4, 1))]);
+        let (expected, expected_outputs) = plan.execute_cpu(&inputs);
+        let (actual, outputs, report) = plan.execute_cpu_profiled(&inputs);
+        assert_bitwise_eq(&actual, &expected);
+        assert_bitwise_eq(&outputs["y"], &expected_outputs["y"]);
+
+        // each node once, the shared one included
+        assert_eq!(report.nodes.iter().map(|node| node.op).collect::<Vec<_>>(), ["input", "tanh", "transpose", "transpose", "add", "output", "softmax"]);
+        assert_eq!(report.nodes[0].name.as_deref(), Some("x"));
+        assert_eq!(report.cost(), plan.cost());
+        assert_eq!(report.ops.iter().map(|op| op.nodes).sum::<usize>(), report.nodes.len());
+        assert!(report.to_json().contains(&format!("\"result_bytes\": {}", plan.cost().result_bytes)));
+    }
+}
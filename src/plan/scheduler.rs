@@ -0,0 +1,105 @@
+use std::{collections::HashMap, sync::{Mutex, atomic::{AtomicUsize, Ordering}}};
+
+use crate::{MatrixPlan, Scalar, Matrix, parallel, plan::{op::MatrixOp, graph::PlanGraph, cpu_eval}};
+
+/// Evaluates independent nodes of a plan concurrently.
+/// A node is spawned on the worker pool as soon as its last operand finishes, and each result is dropped after its last reader has consumed it.
+struct Scheduler<'a, 'b, I: Scalar> {
+    graph: PlanGraph<'a, I>,
+    inputs: &'b HashMap<&'b str, &'b Matrix<I>>,
+    /// operand edges each node is still waiting on
+    pending: Vec<AtomicUsize>,
+    /// user edges that have not yet consumed each node's result
+    remaining_uses: Vec<AtomicUsize>,
+    results: Vec<Mutex<Option<Matrix<I>>>>,
+    outputs: Mutex<HashMap<String, Matrix<I>>>,
+}
+
+impl<'a, 'b, I: Scalar> Scheduler<'a, 'b, I> {
+    fn take_operand(&self, index: usize) -> Matrix<I> {
+        let mut result = self.results[index].lock().unwrap();
+        if self.remaining_uses[index].fetch_sub(1, Ordering::AcqRel) == 1 && index != self.graph.root {
+            result.take().expect("operand evaluated")
+        } else {
+            result.as_ref().expect("operand evaluated").clone()
+        }
+    }
+
+    fn run<'s>(&'s self, scope: &rayon::Scope<'s>, index: usize) {
+        let node = &self.graph.nodes[index];
+        let operands = node.operands.iter().map(|operand| self.take_operand(*operand)).collect();
+        let output = cpu_eval::evaluate(node.plan, self.inputs, operands);
+        if let MatrixOp::Output { name, .. } = &*node.plan.source {
+            self.outputs.lock().unwrap().insert(name.clone(), output.clone());
+        }
+        *self.results[index].lock().unwrap() = Some(output);
+
+        for user in &node.users {
+            if self.pending[*user].fetch_sub(1, Ordering::AcqRel) == 1 {
+                let user = *user;
+                scope.spawn(move |scope| self.run(scope, user));
+            }
+        }
+    }
+}
+
+pub(crate) fn execute<I: Scalar>(plan: &MatrixPlan<I>, inputs: &HashMap<&str, &Matrix<I>>) -> (Matrix<I>, HashMap<String, Matrix<I>>) {
+    let graph = PlanGraph::new(plan);
+    let scheduler = Scheduler {
+        pending: graph.nodes.iter().map(|node| AtomicUsize::new(node.operands.len())).collect(),
+        remaining_uses: graph.nodes.iter().map(|node| AtomicUsize::new(node.users.len())).collect(),
+        results: graph.nodes.iter().map(|_| Mutex::new(None)).collect(),
+        outputs: Mutex::new(HashMap::new()),
+        inputs,
+        graph,
+    };
+
+    parallel::pool().scope(|scope| {
+        for (index, node) in scheduler.graph.nodes.iter().enumerate() {
+            if node.operands.is_empty() {
+                let scheduler = &scheduler;
+                scope.spawn(move |scope| scheduler.run(scope, index));
+            }
+        }
+    });
+
+    let root = scheduler.results[scheduler.graph.root].lock().unwrap().take().expect("root evaluated");
+    (root, scheduler.outputs.into_inner().unwrap())
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use crate::{MatrixPlan, NeuralNetworkBuilder, activation::{Sigmoid, Tanh}, plan::testing::{assert_bitwise_eq, bind, sample}};
+
+    #[test]
+    fn concurrent_execution_matches_cpu_evaluation() {
+        let network = NeuralNetworkBuilder::<f64>::new()
+            .input(4)
+            .add_dense_layer_weighted(sample(5, 4, 1), Tanh)
+            .add_dense_layer_weighted(sample(5, 5, 2), Sigmoid)
+            .add_dense_layer_weighted(sample(3, 5, 3), Tanh);
+        let inputs = MatrixPlan::input(4, 6, "inputs");
+        // reads the same operand twice, and is read by two other nodes
+        let doubled = inputs.clone() + inputs;
+        let shared = doubled.clone().tanh();
+        let plan = MatrixPlan::merge_outputs([
+            network.plan_backprop(6),
+            (shared.clone() + shared.clone().hadamard_mul(doubled)).output("extra"),
+            shared.output("shared"),
+        ]);
+
+        let mut inputs = bind(&[("inputs", &sample(4, 6, 4)), ("targets", &sample(3, 6, 5))]);
+        network.fill_plan_weights(&mut inputs);
+        let (expected, expected_outputs) = plan.execute_cpu(&inputs);
+        assert_eq!(expected_outputs.len(), 6);
+        // concurrent evaluation order varies between runs
+        for _ in 0..20 {
+            let (actual, actual_outputs) = plan.execute_cpu_concurrent(&inputs);
+            assert_bitwise_eq(&actual, &expected);
+            assert_eq!(actual_outputs.len(), expected_outputs.len());
+            for (name, expected) in &expected_outputs {
+                assert_bitwise_eq(&actual_outputs[name], expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::{Dim, Matrix, MatrixError, MatrixPlan, Scalar, plan::{dim, graph::PlanGraph, op::MatrixOp}};
+
+/// The named inputs a plan reads and the named outputs it records, each listed once with its shape
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanSignature {
+    inputs: Vec<(String, (Dim, Dim))>,
+    outputs: Vec<(String, (Dim, Dim))>,
+}
+
+impl PlanSignature {
+    pub(crate) fn new<I: Scalar>(plan: &MatrixPlan<I>) -> Self {
+        let mut inputs: Vec<(String, (Dim, Dim))> = vec![];
+        let mut outputs: Vec<(String, (Dim, Dim))> = vec![];
+        for node in PlanGraph::new(plan).nodes {
+            let (list, name) = match &*node.plan.source {
+                MatrixOp::Input { name } => (&mut inputs, name),
+                MatrixOp::Output { name, .. } => (&mut outputs, name),
+                _ => continue,
+            };
+            if !list.iter().any(|(listed, _)| listed == name) {
+                list.push((name.clone(), node.plan.dims()));
+            }
+        }
+        Self { inputs, outputs }
+    }
+
+    /// Named inputs with their expected shapes, operands before their users
+    pub fn inputs(&self) -> impl Iterator<Item=(&str, (Dim, Dim))> + '_ {
+        self.inputs.iter().map(|(name, shape)| (&**name, shape.clone()))
+    }
+
+    /// Named outputs with their shapes, operands before their users
+    pub fn outputs(&self) -> impl Iterator<Item=(&str, (Dim, Dim))> + '_ {
+        self.outputs.iter().map(|(name, shape)| (&**name, shape.clone()))
+    }
+
+    pub fn input(&self, name: &str) -> Option<(Dim, Dim)> {
+        self.inputs().find(|(input, _)| *input == name).map(|(_, shape)| shape)
+    }
+
+    pub fn output(&self, name: &str) -> Option<(Dim, Dim)> {
+        self.outputs().find(|(output, _)| *output == name).map(|(_, shape)| shape)
+    }
+
+    /// Checks `inputs` against the signature, reporting every missing, mis-shaped and unexpected binding (the latter in name order).
+    /// Symbolic dimensions take their size from the first input using them.
+    pub fn validate<I: Scalar>(&self, inputs: &HashMap<impl AsRef<str>, impl AsRef<Matrix<I>>>) -> Result<(), Vec<MatrixError>> {
+        let inputs = inputs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect::<HashMap<_, _>>();
+        let bindings = dim::bind(self.inputs(), &inputs);
+        let mut errors = vec![];
+        for (name, (rows, cols)) in self.inputs() {
+            let Some(input) = inputs.get(name) else {
+                errors.push(MatrixError::MissingInput { name: name.to_string() });
+                continue;
+            };
+            // a bound input binds its own symbols
+            let expected = (rows.resolve(&bindings).expect("bound symbol"), cols.resolve(&bindings).expect("bound symbol"));
+            let actual = (input.rows(), input.cols());
+            if actual != expected {
+                errors.push(MatrixError::InputShape { name: name.to_string(), expected, actual });
+            }
+        }
+        let mut unexpected = inputs.keys().filter(|name| self.input(name).is_none()).collect::<Vec<_>>();
+        unexpected.sort_unstable();
+        errors.extend(unexpected.into_iter().map(|name| MatrixError::UnexpectedInput { name: name.to_string() }));
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Dim, Matrix, MatrixError, MatrixPlan, plan::testing::bind};
+
+    /// `w * x + b` over a symbolic batch, whose inputs are listed `w`, `x`, `b`
+    fn plan() -> MatrixPlan<f64> {
+        let x = MatrixPlan::input(3, "batch", "x");
+        let w = MatrixPlan::input(2, 3, "w");
+        let b = MatrixPlan::input(2, 1, "b");
+        w * x + b.broadcast_cols("batch")
+    }
+
+    #[test]
+    fn validation_reports_every_bad_binding_in_order() {
+        let (w, x, z) = (Matrix::new(3, 3), Matrix::new(3, 5), Matrix::new(1, 1));
+        let inputs = bind(&[("z", &z), ("w", &w), ("x", &x), ("a", &z)]);
+        assert_eq!(plan().validate(&inputs).unwrap_err(), vec![
+            MatrixError::InputShape { name: "w".to_string(), expected: (2, 3), actual: (3, 3) },
+            MatrixError::MissingInput { name: "b".to_string() },
+            MatrixError::UnexpectedInput { name: "a".to_string() },
+            MatrixError::UnexpectedInput { name: "z".to_string() },
+        ]);
+        let (w, b) = (Matrix::new(2, 3), Matrix::new(2, 1));
+        assert_eq!(plan().validate(&bind(&[("w", &w), ("x", &x), ("b", &b)])), Ok(()));
+    }
+
+    #[test]
+    fn symbols_are_bound_by_the_first_input_using_them() {
+        let x = MatrixPlan::<f64>::input(3, "batch", "x");
+        let y = MatrixPlan::input(2, "batch", "y");
+        let plan = MatrixPlan::merge_outputs([x.output("x"), y.output("y")]);
+        let (x, y) = (Matrix::new(3, 5), Matrix::new(2, 4));
+        assert_eq!(plan.validate(&bind(&[("x", &x), ("y", &y)])).unwrap_err(), vec![
+            MatrixError::InputShape { name: "y".to_string(), expected: (2, 5), actual: (2, 4) },
+        ]);
+        // without `x`, `y` binds the batch
+        assert_eq!(plan.validate(&bind(&[("y", &y)])).unwrap_err(), vec![MatrixError::MissingInput { name: "x".to_string() }]);
+    }
+
+    #[test]
+    fn repeated_inputs_are_listed_once() {
+        let (first, second) = (MatrixPlan::<f64>::input(3, 4, "x"), MatrixPlan::input(3, 4, "x"));
+        let plan = (first.clone() + second.exp()).hadamard_mul(first).output("y");
+        let signature = plan.signature();
+        assert_eq!(signature.inputs().collect::<Vec<_>>(), [("x", (Dim::Fixed(3), Dim::Fixed(4)))]);
+        assert_eq!(signature.outputs().collect::<Vec<_>>(), [("y", (Dim::Fixed(3), Dim::Fixed(4)))]);
+        assert_eq!(signature.input("w"), None);
+    }
+}
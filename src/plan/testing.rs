@@ -0,0 +1,115 @@
+//! Helpers shared by the unit tests of the crate
+
+use std::collections::HashMap;
+
+use crate::{Dim, Matrix, MatrixPlan, CompiledPlan, CustomOp};
+
+/// Deterministic pseudo-random `rows x cols` matrix with components in `[-1, 1)`
+pub(crate) fn sample(rows: usize, cols: usize, seed: u64) -> Matrix<f64> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut out = Matrix::new(rows, cols);
+    for component in out.as_mut() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *component = (state >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+    }
+    out
+}
+
+pub(crate) fn components(matrix: &Matrix<f64>) -> &[f64] {
+    matrix.as_ref()
+}
+
+pub(crate) fn bind(inputs: &[(&str, &Matrix<f64>)]) -> HashMap<String, Matrix<f64>> {
+    inputs.iter().map(|(name, matrix)| (name.to_string(), (*matrix).clone())).collect()
+}
+
+/// Same shape and the same bits in every component, so NaNs compare equal and `-0.0` differs from `0.0`
+pub(crate) fn assert_bitwise_eq(actual: &Matrix<f64>, expected: &Matrix<f64>) {
+    assert_eq!((actual.rows(), actual.cols()), (expected.rows(), expected.cols()), "shapes differ");
+    for (index, (actual, expected)) in components(actual).iter().zip(components(expected)).enumerate() {
+        assert_eq!(actual.to_bits(), expected.to_bits(), "component {}: {} != {}", index, actual, expected);
+    }
+}
+
+/// Result and named outputs of `plan` compiled with `compiled`, bit for bit the ones of [`MatrixPlan::execute_cpu`]
+pub(crate) fn assert_same_execution(plan: &MatrixPlan<f64>, compiled: &CompiledPlan<f64>, inputs: &HashMap<String, Matrix<f64>>) {
+    let (expected, expected_outputs) = plan.execute_cpu(inputs);
+    let (actual, actual_outputs) = compiled.execute(inputs);
+    assert_bitwise_eq(&actual, &expected);
+    assert_eq!(actual_outputs.len(), expected_outputs.len());
+    for (name, expected) in &expected_outputs {
+        assert_bitwise_eq(&actual_outputs[name], expected);
+    }
+}
+
+pub(crate) fn matrix(rows: usize, cols: usize, data: &[f64]) -> Matrix<f64> {
+    assert_eq!(rows * cols, data.len());
+    let mut out = Matrix::new(rows, cols);
+    out.as_mut().copy_from_slice(data);
+    out
+}
+
+/// Same shape and components within `tolerance`, relative to the magnitude of the expected ones
+pub(crate) fn assert_close(actual: &Matrix<f64>, expected: &Matrix<f64>, tolerance: f64) {
+    assert_eq!((actual.rows(), actual.cols()), (expected.rows(), expected.cols()), "shapes differ");
+    for (index, (actual, expected)) in components(actual).iter().zip(components(expected)).enumerate() {
+        assert!((actual - expected).abs() <= tolerance * (1.0 + expected.abs()), "component {}: {} != {}", index, actual, expected);
+    }
+}
+
+/// Custom op cubing each component, differentiable in both modes
+pub(crate) struct Cube;
+
+impl CustomOp<f64> for Cube {
+    fn name(&self) -> &str {
+        "cube"
+    }
+
+    fn output_shape(&self, operands: &[(Dim, Dim)]) -> Option<(Dim, Dim)> {
+        match operands {
+            [operand] => Some(operand.clone()),
+            _ => None,
+        }
+    }
+
+    fn forward(&self, operands: &[&Matrix<f64>]) -> Matrix<f64> {
+        operands[0].clone().pow(3.0)
+    }
+
+    fn backward_plan(&self, operands: &[MatrixPlan<f64>], _: &MatrixPlan<f64>, adjoint: &MatrixPlan<f64>) -> Option<Vec<Option<MatrixPlan<f64>>>> {
+        Some(vec![Some(adjoint.clone().hadamard_mul(operands[0].clone().pow(2.0).scale(3.0)))])
+    }
+
+    fn tangent_plan(&self, operands: &[MatrixPlan<f64>], _: &MatrixPlan<f64>, tangents: &[Option<MatrixPlan<f64>>]) -> Option<MatrixPlan<f64>> {
+        Some(tangents[0].clone()?.hadamard_mul(operands[0].clone().pow(2.0).scale(3.0)))
+    }
+}
+
+/// Central finite difference of the sum of all components of `plan` with respect to each component of the input `wrt`
+pub(crate) fn finite_difference(plan: &MatrixPlan<f64>, wrt: &str, inputs: &HashMap<String, Matrix<f64>>) -> Matrix<f64> {
+    const STEP: f64 = 1e-6;
+    let sum = |inputs: &HashMap<String, Matrix<f64>>| components(&plan.execute_cpu(inputs).0).iter().sum::<f64>();
+    let mut shifted = inputs.clone();
+    let mut out = inputs[wrt].clone();
+    for row in 0..out.rows() {
+        for col in 0..out.cols() {
+            let component = inputs[wrt][(row, col)];
+            let mut sum_at = |value: f64| {
+                shifted.get_mut(wrt).unwrap()[(row, col)] = value;
+                sum(&shifted)
+            };
+            out[(row, col)] = (sum_at(component + STEP) - sum_at(component - STEP)) / (2.0 * STEP);
+            shifted.get_mut(wrt).unwrap()[(row, col)] = component;
+        }
+    }
+    out
+}
+
+/// Checks the gradient plans of `plan` with respect to each input in `wrt` against finite differences at `inputs`
+pub(crate) fn assert_gradients(plan: &MatrixPlan<f64>, wrt: &[&str], inputs: &HashMap<String, Matrix<f64>>) {
+    for (name, gradient) in wrt.iter().zip(plan.gradients(wrt)) {
+        assert_close(&gradient.execute_cpu(inputs).0, &finite_difference(plan, name, inputs), 1e-6);
+    }
+}
@@ -0,0 +1,541 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Write, ops::Range, sync::Arc};
+
+use crate::{Dim, Matrix, MatrixError, MatrixPlan, Scalar, plan::{CustomOp, fusion, graph::PlanGraph, op::MatrixOp}};
+
+// One statement per line, `%id = op arguments : rows x cols`, defining each value before its uses:
+//
+//     %weights_0 = input "weights_0" : 3x2
+//     %inputs = input "inputs" : 2xbatch
+//     %0 = mul %weights_0, %inputs : 3xbatch
+//     %1 = scale %0, 0.5 : 3xbatch
+//     %2 = output %1, "hidden" : 3xbatch
+//
+// Arguments are operands (`%id`, or `%id.T` for an operand a product reads transposed) followed by the payload of the op:
+// scalars, names and custom op names as quoted strings, slice ranges (`0..2`, or `..` for the whole axis) and constants as rows of components (`[1, 2; 3, 4]`).
+// Inputs are referred to by their name where it is an identifier, other values are numbered.
+// Symbolic dimensions are written bare if they are identifiers without an `x`, quoted otherwise.
+
+pub(crate) fn print<I: Scalar>(plan: &MatrixPlan<I>) -> String {
+    // kernels are internal to a plan, their ops are printed instead
+    let plan = fusion::unfuse(plan);
+    let graph = PlanGraph::new(&plan);
+    let mut ids: Vec<String> = Vec::with_capacity(graph.nodes.len());
+    let mut named = HashSet::new();
+    let mut numbered = 0;
+    let mut text = String::new();
+    for node in &graph.nodes {
+        let source = &*node.plan.source;
+        let id = match source {
+            MatrixOp::Input { name } if is_identifier(name) && named.insert(name) => name.clone(),
+            _ => {
+                numbered += 1;
+                (numbered - 1).to_string()
+            },
+        };
+        let mut arguments = node.operands.iter().map(|operand| format!("%{}", ids[*operand])).collect::<Vec<_>>();
+        if let MatrixOp::Mul { transposed, .. } = source {
+            for (argument, transposed) in arguments.iter_mut().zip([transposed.0, transposed.1]) {
+                if transposed {
+                    argument.push_str(".T");
+                }
+            }
+        }
+        match source {
+            MatrixOp::Input { name } | MatrixOp::Output { name, .. } => arguments.push(quote(name)),
+            MatrixOp::Constant { matrix } => arguments.push(literal(matrix)),
+            MatrixOp::Fill { value: scalar } |
+            MatrixOp::Scale { scalar, .. } |
+            MatrixOp::Max { scalar, .. } |
+            MatrixOp::Min { scalar, .. } |
+            MatrixOp::Pow { exponent: scalar, .. } => arguments.push(format!("{:?}", scalar)),
+            MatrixOp::Clamp { min, max, .. } => arguments.extend([format!("{:?}", min), format!("{:?}", max)]),
+            MatrixOp::Slice { rows, cols, .. } => arguments.extend([range(rows), range(cols)]),
+            MatrixOp::Custom { op, .. } => arguments.push(quote(op.name())),
+            _ => (),
+        }
+        write!(text, "%{} = {}", id, source.name()).unwrap();
+        if !arguments.is_empty() {
+            write!(text, " {}", arguments.join(", ")).unwrap();
+        }
+        writeln!(text, " : {}x{}", dim(&node.plan.rows), dim(&node.plan.cols)).unwrap();
+        ids.push(id);
+    }
+    text
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+fn dim(dim: &Dim) -> String {
+    match dim {
+        Dim::Symbol(name) if !is_identifier(name) || name.contains('x') => quote(name),
+        dim => dim.to_string(),
+    }
+}
+
+/// A slice range, `..` for the whole axis
+pub(crate) fn range(range: &Option<Range<usize>>) -> String {
+    match range {
+        Some(range) => format!("{}..{}", range.start, range.end),
+        None => "..".to_string(),
+    }
+}
+
+fn literal<I: Scalar>(matrix: &Matrix<I>) -> String {
+    if matrix.rows() * matrix.cols() == 0 {
+        return "[]".to_string();
+    }
+    let rows = (0..matrix.rows()).map(|row| matrix[row].iter().map(|x| format!("{:?}", x)).collect::<Vec<_>>().join(", "));
+    format!("[{}]", rows.collect::<Vec<_>>().join("; "))
+}
+
+pub(crate) fn parse<I: Scalar>(text: &str, custom: &[Arc<dyn CustomOp<I>>]) -> Result<MatrixPlan<I>, MatrixError> {
+    let mut values: HashMap<&str, MatrixPlan<I>> = HashMap::new();
+    let mut result = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message| MatrixError::Parse { line: index + 1, message };
+        let (id, plan) = statement(line, &values, custom).map_err(error)?;
+        if values.insert(id, plan.clone()).is_some() {
+            return Err(error(format!("%{} is defined twice", id)));
+        }
+        result = Some(plan);
+    }
+    result.ok_or_else(|| MatrixError::Parse { line: text.lines().count().max(1), message: "no statements".to_string() })
+}
+
+/// Reads a statement, giving the id it defines and its plan
+fn statement<'a, I: Scalar>(line: &'a str, values: &HashMap<&str, MatrixPlan<I>>, custom: &[Arc<dyn CustomOp<I>>]) -> Result<(&'a str, MatrixPlan<I>), String> {
+    let mut cursor = Cursor(line);
+    cursor.expect('%')?;
+    let id = cursor.identifier()?;
+    cursor.expect('=')?;
+    let op = cursor.identifier()?;
+    let mut arguments = Arguments { operands: VecDeque::new(), payload: VecDeque::new() };
+    if cursor.peek() != Some(':') {
+        loop {
+            match cursor.peek() {
+                Some('%') => {
+                    cursor.expect('%')?;
+                    let operand = cursor.identifier()?;
+                    let operand = values.get(operand).ok_or_else(|| format!("%{} is not defined", operand))?;
+                    let transposed = cursor.eat('.');
+                    if transposed {
+                        cursor.expect('T')?;
+                    }
+                    arguments.operands.push_back((operand.clone(), transposed));
+                },
+                Some('"') => arguments.payload.push_back(Payload::Name(cursor.string()?)),
+                Some('[') => arguments.payload.push_back(Payload::Matrix(cursor.literal()?)),
+                _ => arguments.payload.push_back(cursor.atom()?),
+            }
+            if !cursor.eat(',') {
+                break;
+            }
+        }
+    }
+    cursor.expect(':')?;
+    let rows = cursor.dim()?;
+    cursor.expect('x')?;
+    let cols = cursor.dim()?;
+    if !cursor.0.trim().is_empty() {
+        return Err(format!("unexpected '{}'", cursor.0.trim()));
+    }
+    let plan = build(op, &mut arguments, &rows, &cols, custom)?;
+    if !arguments.operands.is_empty() || !arguments.payload.is_empty() {
+        return Err(format!("too many arguments for {}", op));
+    }
+    if (&plan.rows, &plan.cols) != (&rows, &cols) {
+        return Err(format!("{} is declared {}x{} but gives {}x{}", op, rows, cols, plan.rows, plan.cols));
+    }
+    Ok((id, plan))
+}
+
+fn build<I: Scalar>(op: &str, arguments: &mut Arguments<I>, rows: &Dim, cols: &Dim, custom: &[Arc<dyn CustomOp<I>>]) -> Result<MatrixPlan<I>, String> {
+    let shaped = |result: Result<MatrixPlan<I>, MatrixError>| result.map_err(|error| error.to_string());
+    Ok(match op {
+        "input" => MatrixPlan::input(rows, cols, arguments.name()?),
+        "output" => arguments.operand()?.output(arguments.name()?),
+        "constant" => {
+            let components = arguments.matrix()?;
+            let (Some(rows), Some(cols)) = (rows.fixed(), cols.fixed()) else {
+                return Err("constants have fixed dimensions".to_string());
+            };
+            let mut matrix = Matrix::new(rows, cols);
+            if rows * cols > 0 {
+                if components.len() != rows || components.iter().any(|row| row.len() != cols) {
+                    return Err(format!("constant does not have {}x{} components", rows, cols));
+                }
+                for (row, components) in components.iter().enumerate() {
+                    matrix[row].copy_from_slice(components);
+                }
+            } else if !components.is_empty() {
+                return Err("empty constant with components".to_string());
+            }
+            MatrixPlan::constant(matrix)
+        },
+        "fill" => MatrixPlan {
+            rows: rows.clone(),
+            cols: cols.clone(),
+            source: Arc::new(MatrixOp::Fill { value: arguments.scalar()? }),
+        },
+        "scale" => arguments.operand()?.scale(arguments.scalar()?),
+        "max" => arguments.operand()?.max(arguments.scalar()?),
+        "min" => arguments.operand()?.min(arguments.scalar()?),
+        "pow" => arguments.operand()?.pow(arguments.scalar()?),
+        "clamp" => arguments.operand()?.clamp(arguments.scalar()?, arguments.scalar()?),
+        "neg" => -arguments.operand()?,
+        "transpose" => arguments.operand()?.transpose(),
+        "sign" => arguments.operand()?.sign(),
+        "sigmoid" => arguments.operand()?.sigmoid(),
+        "exp" => arguments.operand()?.exp(),
+        "ln" => arguments.operand()?.ln(),
+        "sqrt" => arguments.operand()?.sqrt(),
+        "abs" => arguments.operand()?.abs(),
+        "tanh" => arguments.operand()?.tanh(),
+        "reciprocal" => arguments.operand()?.reciprocal(),
+        "sum_rows" => arguments.operand()?.sum_rows(),
+        "sum_cols" => arguments.operand()?.sum_cols(),
+        "mean" => arguments.operand()?.mean(),
+        "max_reduce" => shaped(arguments.operand()?.try_max_reduce())?,
+        "argmax" => shaped(arguments.operand()?.try_argmax())?,
+        "one_hot" => shaped(arguments.operand()?.try_one_hot(rows))?,
+        "broadcast_rows" => shaped(arguments.operand()?.try_broadcast_rows(rows))?,
+        "broadcast_cols" => shaped(arguments.operand()?.try_broadcast_cols(cols))?,
+        "softmax" => arguments.operand()?.softmax(),
+        "log_softmax" => arguments.operand()?.log_softmax(),
+        "reshape" => shaped(arguments.operand()?.try_reshape(rows, cols))?,
+        "slice" => {
+            let operand = arguments.operand()?;
+            match (arguments.range()?, arguments.range()?) {
+                (Some(rows), Some(cols)) => shaped(operand.try_slice(rows, cols))?,
+                (Some(rows), None) => shaped(operand.try_slice_rows(rows))?,
+                (None, Some(cols)) => shaped(operand.try_slice_cols(cols))?,
+                (None, None) => return Err("slice of the whole matrix".to_string()),
+            }
+        },
+        "concat_rows" | "concat_cols" => {
+            let parts = arguments.rest()?;
+            if parts.is_empty() {
+                return Err("nothing to concatenate".to_string());
+            }
+            if op == "concat_rows" {
+                shaped(MatrixPlan::try_concat_rows(parts))?
+            } else {
+                shaped(MatrixPlan::try_concat_cols(parts))?
+            }
+        },
+        "mul" => {
+            let (left, left_transposed) = arguments.product_operand()?;
+            let (right, right_transposed) = arguments.product_operand()?;
+            shaped(left.try_mul_transposed(&right, (left_transposed, right_transposed)))?
+        },
+        "hadamard_mul" => shaped(arguments.operand()?.try_hadamard_mul(arguments.operand()?))?,
+        "add" => shaped(arguments.operand()?.try_add(arguments.operand()?))?,
+        "sub" => shaped(arguments.operand()?.try_sub(arguments.operand()?))?,
+        "hadamard_div" => shaped(arguments.operand()?.try_hadamard_div(arguments.operand()?))?,
+        "greater" => shaped(arguments.operand()?.try_greater(arguments.operand()?))?,
+        "less" => shaped(arguments.operand()?.try_less(arguments.operand()?))?,
+        "equal" => shaped(arguments.operand()?.try_equal(arguments.operand()?))?,
+        "select" => shaped(arguments.operand()?.try_select(arguments.operand()?, arguments.operand()?))?,
+        "recompute" => arguments.operand()?.recompute_after(arguments.operand()?),
+        "merge_outputs" => MatrixPlan::merge_outputs(arguments.rest()?),
+        "custom" => {
+            let name = arguments.name()?;
+            let op = custom.iter().find(|op| op.name() == name).ok_or_else(|| format!("unknown custom op '{}'", name))?;
+            shaped(MatrixPlan::try_custom(op.clone(), arguments.rest()?))?
+        },
+        op => return Err(format!("unknown op '{}'", op)),
+    })
+}
+
+enum Payload<I: Scalar> {
+    Name(String),
+    Scalar(I),
+    Range(Option<Range<usize>>),
+    Matrix(Vec<Vec<I>>),
+}
+
+/// The arguments of a statement, taken in order by the op being built
+struct Arguments<I: Scalar> {
+    /// operands and whether they are marked transposed
+    operands: VecDeque<(MatrixPlan<I>, bool)>,
+    payload: VecDeque<Payload<I>>,
+}
+
+impl<I: Scalar> Arguments<I> {
+    fn operand(&mut self) -> Result<MatrixPlan<I>, String> {
+        match self.product_operand()? {
+            (_, true) => Err("only mul operands can be transposed".to_string()),
+            (operand, false) => Ok(operand),
+        }
+    }
+
+    fn product_operand(&mut self) -> Result<(MatrixPlan<I>, bool), String> {
+        self.operands.pop_front().ok_or_else(|| "missing operand".to_string())
+    }
+
+    /// All the remaining operands
+    fn rest(&mut self) -> Result<Vec<MatrixPlan<I>>, String> {
+        let mut operands = vec![];
+        while !self.operands.is_empty() {
+            operands.push(self.operand()?);
+        }
+        Ok(operands)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.payload.pop_front() {
+            Some(Payload::Name(name)) => Ok(name),
+            _ => Err("expected a quoted name".to_string()),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<I, String> {
+        match self.payload.pop_front() {
+            Some(Payload::Scalar(scalar)) => Ok(scalar),
+            _ => Err("expected a number".to_string()),
+        }
+    }
+
+    fn range(&mut self) -> Result<Option<Range<usize>>, String> {
+        match self.payload.pop_front() {
+            Some(Payload::Range(range)) => Ok(range),
+            _ => Err("expected a range".to_string()),
+        }
+    }
+
+    fn matrix(&mut self) -> Result<Vec<Vec<I>>, String> {
+        match self.payload.pop_front() {
+            Some(Payload::Matrix(matrix)) => Ok(matrix),
+            _ => Err("expected components".to_string()),
+        }
+    }
+}
+
+/// The unread rest of a line
+struct Cursor<'a>(&'a str);
+
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.0 = self.0.trim_start();
+        self.0.chars().next()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.0 = &self.0[expected.len_utf8()..];
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if !self.eat(expected) {
+            return Err(format!("expected '{}' at '{}'", expected, self.0));
+        }
+        Ok(())
+    }
+
+    /// Longest run of characters matching `accept`, which may be empty
+    fn take(&mut self, accept: impl Fn(char) -> bool) -> &'a str {
+        self.peek();
+        let end = self.0.find(|c: char| !accept(c)).unwrap_or(self.0.len());
+        let (taken, rest) = self.0.split_at(end);
+        self.0 = rest;
+        taken
+    }
+
+    fn identifier(&mut self) -> Result<&'a str, String> {
+        match self.take(|c| c.is_ascii_alphanumeric() || c == '_') {
+            "" => Err(format!("expected an identifier at '{}'", self.0)),
+            identifier => Ok(identifier),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        let mut chars = self.0.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = &self.0[index + 1..];
+                    return Ok(string);
+                },
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, escaped)) => string.push(escaped),
+                    None => break,
+                },
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    /// A scalar or a range
+    fn atom<I: Scalar>(&mut self) -> Result<Payload<I>, String> {
+        let atom = self.take(|c| c.is_ascii_alphanumeric() || "._+-".contains(c));
+        let Some((start, end)) = atom.split_once("..") else {
+            return Ok(Payload::Scalar(scalar(atom)?));
+        };
+        if (start, end) == ("", "") {
+            return Ok(Payload::Range(None));
+        }
+        let bound = |bound: &str| bound.parse::<usize>().map_err(|_| format!("bad range '{}'", atom));
+        Ok(Payload::Range(Some(bound(start)?..bound(end)?)))
+    }
+
+    /// Rows of components separated by `;`
+    fn literal<I: Scalar>(&mut self) -> Result<Vec<Vec<I>>, String> {
+        self.expect('[')?;
+        let mut rows = vec![];
+        if self.eat(']') {
+            return Ok(rows);
+        }
+        loop {
+            let mut row = vec![];
+            loop {
+                row.push(scalar(self.take(|c| c.is_ascii_alphanumeric() || "._+-".contains(c)))?);
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            rows.push(row);
+            if !self.eat(';') {
+                self.expect(']')?;
+                return Ok(rows);
+            }
+        }
+    }
+
+    fn dim(&mut self) -> Result<Dim, String> {
+        match self.peek() {
+            Some('"') => Ok(Dim::symbol(self.string()?)),
+            Some(c) if c.is_ascii_digit() => {
+                let size = self.take(|c| c.is_ascii_digit());
+                size.parse().map(Dim::Fixed).map_err(|_| format!("bad dimension '{}'", size))
+            },
+            _ => match self.take(|c| (c.is_ascii_alphanumeric() || c == '_') && c != 'x') {
+                "" => Err(format!("expected a dimension at '{}'", self.0)),
+                symbol => Ok(Dim::symbol(symbol)),
+            },
+        }
+    }
+}
+
+fn scalar<I: Scalar>(atom: &str) -> Result<I, String> {
+    atom.parse().map_err(|_| format!("bad number '{}'", atom))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{Checkpointing, CustomOp, Matrix, MatrixError, MatrixPlan, NeuralNetworkBuilder, activation::{Relu, Sigmoid, Softmax}, plan::{graph::PlanGraph, testing::{Cube, assert_bitwise_eq, matrix, sample}}};
+
+    fn cube() -> Vec<Arc<dyn CustomOp<f64>>> {
+        vec![Arc::new(Cube)]
+    }
+
+    /// A checkpointed backprop plan over a symbolic batch with products reading transposed operands,
+    /// merged with a custom op, special constants and awkward names
+    fn plan() -> MatrixPlan<f64> {
+        let backprop = NeuralNetworkBuilder::<f64>::new()
+            .input(3)
+            .add_dense_layer_weighted(sample(4, 3, 1), Sigmoid)
+            .add_dense_layer_weighted(sample(4, 4, 2), Relu)
+            .add_dense_layer_weighted(sample(2, 4, 3), Softmax)
+            .checkpointing(Checkpointing::EveryLayers(2))
+            .plan_backprop("batch")
+            .optimize();
+        let odd = MatrixPlan::input(2, "n x m", "odd \"name\"\n");
+        let constant = MatrixPlan::constant(matrix(2, 2, &[f64::NAN, f64::NEG_INFINITY, -0.0, 1e-300]));
+        let custom = MatrixPlan::custom(Arc::new(Cube), [odd.clone()]).hadamard_mul(odd.clone().max(-0.0).clamp(-1.5, 2.0)).output("cubed");
+        let first_max = odd.clone().argmax().one_hot(3).output("first max");
+        let shared = odd.transpose() * constant;
+        MatrixPlan::merge_outputs([backprop, custom, first_max, (shared.clone() - shared.exp().slice_cols(0..1).broadcast_cols(2)).output("shared")])
+    }
+
+    fn inputs(plan: &MatrixPlan<f64>) -> HashMap<String, Matrix<f64>> {
+        plan.inputs().into_iter().enumerate().map(|(seed, (name, (rows, cols)))| {
+            (name.to_string(), sample(rows.fixed().unwrap_or(5), cols.fixed().unwrap_or(5), seed as u64))
+        }).collect()
+    }
+
+    #[test]
+    fn printing_a_parsed_plan_gives_the_same_text() {
+        let plan = plan();
+        let text = plan.to_text();
+        assert!(text.contains(".T"));
+        assert!(text.contains("= recompute"));
+        assert!(text.contains("= custom"));
+        assert!(text.contains("= one_hot"));
+        assert!(text.contains("xbatch"));
+        assert!(text.contains(r#"2x"n x m""#));
+        assert!(text.contains(r#"input "odd \"name\"\n""#));
+        let parsed = MatrixPlan::from_text_with(&text, &cube()).unwrap();
+        assert_eq!(parsed.to_text(), text);
+        assert_eq!(parsed.signature(), plan.signature());
+
+        let inputs = inputs(&plan);
+        let (expected, expected_outputs) = plan.execute_cpu(&inputs);
+        let (actual, actual_outputs) = parsed.execute_cpu(&inputs);
+        assert_bitwise_eq(&actual, &expected);
+        assert_eq!(actual_outputs.len(), expected_outputs.len());
+        for (name, expected) in &expected_outputs {
+            assert_bitwise_eq(&actual_outputs[name], expected);
+        }
+    }
+
+    #[test]
+    fn parsing_keeps_shared_nodes_shared() {
+        let text = plan().to_text();
+        let parsed = MatrixPlan::from_text_with(&text, &cube()).unwrap();
+        // one statement per node, so nodes read several times are printed once
+        assert_eq!(PlanGraph::new(&parsed).nodes.len(), text.lines().count());
+    }
+
+    fn error(text: &str) -> MatrixError {
+        MatrixPlan::<f64>::from_text_with(text, &cube()).unwrap_err()
+    }
+
+    fn assert_error_at(text: &str, line: usize, message: &str) {
+        match error(text) {
+            MatrixError::Parse { line: actual, message: actual_message } => {
+                assert_eq!(actual, line, "{}", actual_message);
+                assert!(actual_message.contains(message), "'{}' does not mention '{}'", actual_message, message);
+            },
+            other => panic!("not a parse error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_errors_report_their_line() {
+        let prefix = "# a comment\n%x = input \"x\" : 2x3\n\n";
+        assert_error_at(&format!("{}%0 = exp %y : 2x3", prefix), 4, "%y is not defined");
+        assert_error_at(&format!("{}%0 = exp %x : 2x3\n%0 = neg %x : 2x3", prefix), 5, "%0 is defined twice");
+        assert_error_at(&format!("{}%0 = frobnicate %x : 2x3", prefix), 4, "unknown op 'frobnicate'");
+        assert_error_at(&format!("{}%0 = mul %x, %x : 2x2", prefix), 4, "shape mismatch in mul");
+        assert_error_at(&format!("{}%0 = mul %x.T, %x : 2x2", prefix), 4, "gives 3x3");
+        assert_error_at(&format!("{}%0 = exp %x.T : 3x2", prefix), 4, "only mul operands can be transposed");
+        assert_error_at(&format!("{}%0 = scale %x, two : 2x3", prefix), 4, "bad number 'two'");
+        assert_error_at(&format!("{}%0 = custom %x, \"square\" : 2x3", prefix), 4, "unknown custom op 'square'");
+        assert_error_at(&format!("{}%0 = custom \"cube\" : 2x3", prefix), 4, "custom op 'cube' does not accept operands of shapes []");
+        assert_error_at(&format!("{}%0 = custom %x, %x, \"cube\" : 2x3", prefix), 4, "operands of shapes [2x3, 2x3]");
+        assert_error_at(&format!("{}%0 = exp %x : 2x3 trailing", prefix), 4, "unexpected 'trailing'");
+        assert_error_at(&format!("{}%0 = concat_rows : 2x3", prefix), 4, "nothing to concatenate");
+        assert_error_at("%x = input \"x\" : 0x3\n%0 = argmax %x : 1x3", 2, "cannot argmax an empty matrix");
+        assert_error_at("%x = input \"x\" : 2x", 1, "expected a dimension");
+        assert_error_at("\n# only a comment\n", 2, "no statements");
+    }
+}
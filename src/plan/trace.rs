@@ -0,0 +1,222 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{ExecuteResult, Matrix, MatrixError, MatrixPlan, Scalar, plan::{cpu_eval, graph::PlanGraph, op::MatrixOp}};
+
+/// Summary of the components of a matrix, for diagnostics.
+/// Compared bit for bit, so stats whose minimum, maximum or mean are NaN still equal themselves.
+#[derive(Clone, Debug)]
+pub struct ComponentStats {
+    pub rows: usize,
+    pub cols: usize,
+    /// smallest, largest and mean of the finite components, NaN if there are none
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub nan: usize,
+    /// components that are positive or negative infinity
+    pub infinite: usize,
+}
+
+impl ComponentStats {
+    pub fn new<I: Scalar>(matrix: &Matrix<I>) -> Self {
+        let data: &[I] = matrix.as_ref();
+        let (mut min, mut max, mut sum, mut finite) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0);
+        let (mut nan, mut infinite) = (0, 0);
+        for x in data.iter().map(|x| x.to_f64()) {
+            if x.is_nan() {
+                nan += 1;
+            } else if x.is_infinite() {
+                infinite += 1;
+            } else {
+                min = min.min(x);
+                max = max.max(x);
+                sum += x;
+                finite += 1;
+            }
+        }
+        if finite == 0 {
+            (min, max) = (f64::NAN, f64::NAN);
+        }
+        Self {
+            rows: matrix.rows(),
+            cols: matrix.cols(),
+            min,
+            max,
+            mean: sum / finite as f64,
+            nan,
+            infinite,
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.nan == 0 && self.infinite == 0
+    }
+}
+
+impl PartialEq for ComponentStats {
+    fn eq(&self, other: &Self) -> bool {
+        let floats = |stats: &Self| [stats.min, stats.max, stats.mean].map(f64::to_bits);
+        (self.rows, self.cols, self.nan, self.infinite) == (other.rows, other.cols, other.nan, other.infinite) && floats(self) == floats(other)
+    }
+}
+
+impl Eq for ComponentStats {}
+
+impl fmt::Display for ComponentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} min {} max {} mean {}", self.rows, self.cols, self.min, self.max, self.mean)?;
+        if !self.is_finite() {
+            write!(f, " with {} NaN and {} infinite", self.nan, self.infinite)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first node of a checked execution producing a NaN or infinite component, see [`MatrixPlan::try_execute_cpu_checked`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonFiniteTrace {
+    /// kind of the op, named as in [`MatrixPlan::to_text`]
+    pub op: &'static str,
+    /// name of the node if it is an input or output
+    pub name: Option<String>,
+    pub result: ComponentStats,
+    /// each operand, all finite, in argument order
+    pub operands: Vec<ComponentStats>,
+    /// named inputs the node reads, directly or through other nodes, in evaluation order
+    pub inputs: Vec<String>,
+    /// named outputs the node reads, directly or through other nodes, in evaluation order
+    pub outputs: Vec<String>,
+    /// named outputs reading the node, which are not evaluated
+    pub affected: Vec<String>,
+}
+
+impl fmt::Display for NonFiniteTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |names: &[String]| names.iter().map(|name| format!("'{}'", name)).collect::<Vec<_>>().join(", ");
+        write!(f, "{}", self.op)?;
+        if let Some(name) = &self.name {
+            write!(f, " '{}'", name)?;
+        }
+        write!(f, " produced {}", self.result)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, ", operand {} is {}", index, operand)?;
+        }
+        if !self.inputs.is_empty() {
+            write!(f, "; reads inputs {}", names(&self.inputs))?;
+        }
+        if !self.outputs.is_empty() {
+            write!(f, " through outputs {}", names(&self.outputs))?;
+        }
+        if !self.affected.is_empty() {
+            write!(f, "; feeds outputs {}", names(&self.affected))?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates `plan` node by node, stopping at the first result with a NaN or infinite component
+pub(crate) fn execute<I: Scalar>(plan: &MatrixPlan<I>, inputs: &HashMap<&str, &Matrix<I>>) -> ExecuteResult<I> {
+    let graph = PlanGraph::new(plan);
+    let mut results: Vec<Matrix<I>> = Vec::with_capacity(graph.nodes.len());
+    let mut outputs = HashMap::new();
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let operands = node.operands.iter().map(|operand| results[*operand].clone()).collect();
+        let result = cpu_eval::evaluate(node.plan, inputs, operands);
+        let stats = ComponentStats::new(&result);
+        if !stats.is_finite() {
+            return Err(MatrixError::NonFinite(Box::new(trace(&graph, index, stats, &results))));
+        }
+        if let MatrixOp::Output { name, .. } = &*node.plan.source {
+            outputs.insert(name.clone(), result.clone());
+        }
+        results.push(result);
+    }
+    Ok((results.swap_remove(graph.root), outputs))
+}
+
+fn trace<I: Scalar>(graph: &PlanGraph<I>, index: usize, result: ComponentStats, results: &[Matrix<I>]) -> NonFiniteTrace {
+    let node = &graph.nodes[index];
+    let name = |index: usize| match &*graph.nodes[index].plan.source {
+        MatrixOp::Input { name } | MatrixOp::Output { name, .. } => Some(name.clone()),
+        _ => None,
+    };
+    // nodes are in topological order, so reachability settles in one pass each way
+    let mut upstream = vec![false; index + 1];
+    upstream[index] = true;
+    for current in (0..=index).rev() {
+        if upstream[current] {
+            for operand in &graph.nodes[current].operands {
+                upstream[*operand] = true;
+            }
+        }
+    }
+    let mut downstream = vec![false; graph.nodes.len()];
+    downstream[index] = true;
+    for current in index..graph.nodes.len() {
+        if downstream[current] {
+            for user in &graph.nodes[current].users {
+                downstream[*user] = true;
+            }
+        }
+    }
+    let named = |reached: &[bool], inputs: bool| {
+        let mut names: Vec<String> = vec![];
+        for (current, _) in reached.iter().enumerate().filter(|(current, reached)| **reached && *current != index) {
+            let name = match &*graph.nodes[current].plan.source {
+                MatrixOp::Input { name } if inputs => name,
+                MatrixOp::Output { name, .. } if !inputs => name,
+                _ => continue,
+            };
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    };
+    NonFiniteTrace {
+        op: node.plan.source.name(),
+        name: name(index),
+        result,
+        operands: node.operands.iter().map(|operand| ComponentStats::new(&results[*operand])).collect(),
+        inputs: named(&upstream, true),
+        outputs: named(&upstream, false),
+        affected: named(&downstream, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MatrixError, MatrixPlan, plan::testing::{bind, matrix}};
+
+    use super::ComponentStats;
+
+    #[test]
+    fn stats_without_finite_components_equal_themselves() {
+        let stats = ComponentStats::new(&matrix(1, 2, &[f64::NAN, f64::INFINITY]));
+        assert!(stats.min.is_nan() && stats.mean.is_nan());
+        assert_eq!((stats.nan, stats.infinite), (1, 1));
+        assert_eq!(stats, stats.clone());
+        assert_ne!(stats, ComponentStats::new(&matrix(1, 2, &[f64::NAN, f64::NAN])));
+        assert_ne!(ComponentStats::new(&matrix(1, 1, &[0.0])), ComponentStats::new(&matrix(1, 1, &[-0.0])));
+    }
+
+    #[test]
+    fn checked_execution_stops_at_the_first_non_finite_node() {
+        let x = MatrixPlan::<f64>::input(1, 2, "x");
+        let hidden = x.clone().scale(2.0).output("hidden");
+        let plan = MatrixPlan::merge_outputs([hidden.clone().ln().exp().output("result"), x.output("copy")]);
+        let error = plan.try_execute_cpu_checked(&bind(&[("x", &matrix(1, 2, &[-1.0, 1.0]))])).unwrap_err();
+        let MatrixError::NonFinite(trace) = &error else {
+            panic!("not a trace: {:?}", error);
+        };
+        assert_eq!(trace.op, "ln");
+        assert_eq!(trace.result.nan, 1);
+        assert_eq!(trace.operands, [ComponentStats::new(&matrix(1, 2, &[-2.0, 2.0]))]);
+        assert_eq!(trace.inputs, ["x"]);
+        assert_eq!(trace.outputs, ["hidden"]);
+        assert_eq!(trace.affected, ["result"]);
+        // errors compare as values, traces included
+        assert_eq!(error.clone(), error);
+        assert!(plan.try_execute_cpu_checked(&bind(&[("x", &matrix(1, 2, &[1.0, 2.0]))])).is_ok());
+    }
+}
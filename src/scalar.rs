@@ -1,15 +1,55 @@
-use std::{ops::{Mul, Div, Add, Sub, Neg}, iter::Sum, fmt::{Debug, Display}};
+use std::{ops::{Mul, Div, Add, Sub, Neg}, iter::Sum, fmt::{Debug, Display}, str::FromStr};
 
 use half::f16;
 
-pub trait Scalar: Clone + Copy + Default + Mul<Self, Output=Self> + Div<Self, Output=Self> + Add<Self, Output=Self> + Sub<Self, Output=Self> + Sum + Neg<Output=Self> + Display + Debug + PartialOrd + 'static {
+use crate::gemm::Gemm;
+
+pub trait Scalar: Clone + Copy + Default + Mul<Self, Output=Self> + Div<Self, Output=Self> + Add<Self, Output=Self> + Sub<Self, Output=Self> + Sum + Neg<Output=Self> + Display + FromStr + Debug + PartialOrd + Send + Sync + Gemm + 'static {
     const ONE: Self;
 
     fn from_f64(from: f64) -> Self;
 
+    fn to_f64(self) -> f64;
+
     fn is_nan(self) -> bool;
 
     fn power(self, exponent: Self) -> Self;
+
+    fn exp(self) -> Self;
+
+    /// natural logarithm
+    fn ln(self) -> Self;
+
+    fn sqrt(self) -> Self;
+
+    fn abs(self) -> Self;
+
+    fn tanh(self) -> Self;
+
+    fn recip(self) -> Self {
+        Self::ONE / self
+    }
+
+    /// `exp` is only taken of non-positive values, so it cannot overflow
+    fn sigmoid(self) -> Self {
+        if self >= Self::default() {
+            Self::ONE / (Self::ONE + (-self).exp())
+        } else {
+            let exp = self.exp();
+            exp / (Self::ONE + exp)
+        }
+    }
+
+    /// -1, 0, or 1
+    fn sign(self) -> Self {
+        if self > Self::default() {
+            Self::ONE
+        } else if self < Self::default() {
+            -Self::ONE
+        } else {
+            Self::default()
+        }
+    }
 }
 
 impl Scalar for f16 {
@@ -19,6 +59,10 @@ impl Scalar for f16 {
         f16::from_f64(from)
     }
 
+    fn to_f64(self) -> f64 {
+        f16::to_f64(self)
+    }
+
     fn is_nan(self) -> bool {
         self.is_nan()
     }
@@ -26,6 +70,26 @@ impl Scalar for f16 {
     fn power(self, exponent: Self) -> Self {
         f16::from_f32(self.to_f32().powf(exponent.to_f32()))
     }
+
+    fn exp(self) -> Self {
+        f16::from_f32(self.to_f32().exp())
+    }
+
+    fn ln(self) -> Self {
+        f16::from_f32(self.to_f32().ln())
+    }
+
+    fn sqrt(self) -> Self {
+        f16::from_f32(self.to_f32().sqrt())
+    }
+
+    fn abs(self) -> Self {
+        f16::from_bits(self.to_bits() & 0x7fff)
+    }
+
+    fn tanh(self) -> Self {
+        f16::from_f32(self.to_f32().tanh())
+    }
 }
 
 impl Scalar for f32 {
@@ -35,6 +99,10 @@ impl Scalar for f32 {
         from as f32
     }
 
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
     fn is_nan(self) -> bool {
         self.is_nan()
     }
@@ -42,6 +110,26 @@ impl Scalar for f32 {
     fn power(self, exponent: Self) -> Self {
         self.powf(exponent)
     }
+
+    fn exp(self) -> Self {
+        self.exp()
+    }
+
+    fn ln(self) -> Self {
+        self.ln()
+    }
+
+    fn sqrt(self) -> Self {
+        self.sqrt()
+    }
+
+    fn abs(self) -> Self {
+        self.abs()
+    }
+
+    fn tanh(self) -> Self {
+        self.tanh()
+    }
 }
 
 impl Scalar for f64 {
@@ -51,6 +139,10 @@ impl Scalar for f64 {
         from
     }
 
+    fn to_f64(self) -> f64 {
+        self
+    }
+
     fn is_nan(self) -> bool {
         self.is_nan()
     }
@@ -58,4 +150,24 @@ impl Scalar for f64 {
     fn power(self, exponent: Self) -> Self {
         self.powf(exponent)
     }
+
+    fn exp(self) -> Self {
+        self.exp()
+    }
+
+    fn ln(self) -> Self {
+        self.ln()
+    }
+
+    fn sqrt(self) -> Self {
+        self.sqrt()
+    }
+
+    fn abs(self) -> Self {
+        self.abs()
+    }
+
+    fn tanh(self) -> Self {
+        self.tanh()
+    }
 }